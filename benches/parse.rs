@@ -0,0 +1,107 @@
+//! Compares converting oplog entries into owned `Operation`s with borrowing them as
+//! `RawOperation`s, starting from the raw bytes read from the server in both cases.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use oplog::bson::{doc, Document, RawDocument, Timestamp};
+use oplog::{Operation, RawOperation};
+
+fn entries() -> Vec<(&'static str, Vec<u8>)> {
+    let ts = Timestamp {
+        time: 1479561394,
+        increment: 1,
+    };
+    let items: Vec<Document> = (0..20)
+        .map(|i| doc! { "sku": format!("SKU-{:05}", i), "quantity": i, "price": 9.99 })
+        .collect();
+
+    let insert = doc! {
+        "ts": ts,
+        "t": 1_i64,
+        "v": 2,
+        "op": "i",
+        "ns": "shop.orders",
+        "o": {
+            "_id": 1,
+            "customer": { "name": "Alice", "email": "alice@example.com" },
+            "items": items.clone(),
+            "status": "pending",
+        },
+    };
+    let update = doc! {
+        "ts": ts,
+        "t": 1_i64,
+        "v": 2,
+        "op": "u",
+        "ns": "shop.orders",
+        "o2": { "_id": 1 },
+        "o": { "$v": 2, "diff": { "u": { "status": "paid" } } },
+    };
+    let apply_ops = doc! {
+        "ts": ts,
+        "t": 1_i64,
+        "v": 2,
+        "op": "c",
+        "ns": "admin.$cmd",
+        "o": {
+            "applyOps": (0..10)
+                .map(|i| doc! { "op": "i", "ns": "shop.orders", "o": { "_id": i, "items": items.clone() } })
+                .collect::<Vec<_>>(),
+        },
+    };
+
+    vec![
+        ("insert", insert),
+        ("update", update),
+        ("applyOps", apply_ops),
+    ]
+    .into_iter()
+    .map(|(name, entry)| {
+        let mut bytes = Vec::new();
+        entry.to_writer(&mut bytes).unwrap();
+
+        (name, bytes)
+    })
+    .collect()
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+
+    for (name, bytes) in entries() {
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("owned", name), &bytes, |b, bytes| {
+            b.iter(|| {
+                let document = Document::from_reader(&bytes[..]).unwrap();
+
+                black_box(Operation::new(&document).unwrap())
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("raw", name), &bytes, |b, bytes| {
+            b.iter(|| {
+                let document = RawDocument::from_bytes(bytes).unwrap();
+                let operation = RawOperation::new(document).unwrap();
+
+                black_box((operation.namespace(), operation.document_id()))
+            })
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("raw_to_owned", name),
+            &bytes,
+            |b, bytes| {
+                b.iter(|| {
+                    let document = RawDocument::from_bytes(bytes).unwrap();
+
+                    black_box(RawOperation::new(document).unwrap().to_operation().unwrap())
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+use futures::StreamExt;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use mongodb::Client;
+use oplog::Oplog;
+use std::process;
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("{:?}", e);
+        process::exit(1);
+    }
+}
+
+async fn run() -> oplog::Result<()> {
+    let uri = std::env::var("MONGO_URL").unwrap_or_else(|_| "mongodb://localhost".to_string());
+    let client = Client::with_uri_str(&uri).await?;
+
+    // Serves metrics in the Prometheus text format on http://127.0.0.1:9000/metrics
+    PrometheusBuilder::new()
+        .with_http_listener(([127, 0, 0, 1], 9000))
+        .install()
+        .expect("failed to install Prometheus exporter");
+
+    let mut oplog = Oplog::new(&client).await?;
+
+    while let Some(res) = oplog.next().await {
+        res?;
+    }
+
+    process::exit(1);
+}
@@ -0,0 +1,27 @@
+use mongodb::Client;
+use oplog::{Oplog, Server};
+use std::process;
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("{:?}", e);
+        process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let uri = std::env::var("MONGO_URL").unwrap_or_else(|_| "mongodb://localhost".to_string());
+    let client = Client::with_uri_str(&uri).await?;
+
+    // Streams operations on http://127.0.0.1:8080/sse and http://127.0.0.1:8080/ndjson, e.g.
+    // curl -N 'http://127.0.0.1:8080/ndjson?ns=test.users&op=insert'
+    let listener = TcpListener::bind("127.0.0.1:8080").await?;
+
+    Server::new(&client, Oplog::builder())
+        .serve(listener)
+        .await?;
+
+    Ok(())
+}
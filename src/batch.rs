@@ -0,0 +1,393 @@
+//! The batch module groups operations into batches for bulk writes downstream.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::oper::document_size;
+use crate::{Error, OpTime, Operation, Result};
+use futures::{FutureExt, Stream, StreamExt};
+use futures_timer::Delay;
+
+/// A timer completing once a latency bound has passed.
+pub(crate) type Timer = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Returns a timer completing after the given duration of wall clock time.
+pub(crate) fn delay(duration: Duration) -> Timer {
+    Box::pin(Delay::new(duration))
+}
+
+/// A batch of operations read in order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    /// The operations, oldest first.
+    pub operations: Vec<Operation>,
+    /// The total size of the operations as BSON oplog entries, in bytes.
+    pub bytes: u64,
+}
+
+impl Batch {
+    /// Returns the position of the last operation in the batch, which is safe to checkpoint once
+    /// the whole batch has been written.
+    pub fn last_optime(&self) -> Option<OpTime> {
+        self.operations.last().map(Operation::optime)
+    }
+}
+
+/// A stream adapter grouping operations into batches bounded by count, size and latency.
+///
+/// A batch is yielded as soon as it holds the maximum number of operations, would exceed the
+/// maximum size with the next operation, or its first operation has waited for the maximum
+/// latency. An operation larger than the maximum size is yielded in a batch of its own.
+///
+/// Transactions are never split across batches. A small transaction is a single
+/// `Operation::ApplyOps`, but since MongoDB 4.2 large transactions are written as several
+/// `applyOps` entries (all but the last marked `partial`) and prepared transactions as an
+/// `applyOps` entry marked `prepare` followed by a `commitTransaction` or `abortTransaction`
+/// command. While such a transaction is incomplete the batch is held open, even past its bounds,
+/// and only yielded once the entry completing it has been added. Entries are chained together by
+/// their `prevOpTime`, so any projection must keep that field and any filter must keep the
+/// entries completing transactions, or batches are held open indefinitely.
+///
+/// Errors from the underlying stream are yielded after the operations read before them, except
+/// while a transaction is incomplete: they are then yielded straight away and the operations kept
+/// so the transaction can still be completed in one batch.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use futures::StreamExt;
+/// use mongodb::Client;
+/// use oplog::{Batches, Oplog};
+///
+/// # async fn run() -> Result<(), oplog::Error> {
+/// let client = Client::with_uri_str("mongodb://localhost").await?;
+/// let oplog = Oplog::new(&client).await?;
+///
+/// let mut batches = Batches::new(oplog)
+///     .max_operations(1000)
+///     .max_bytes(16 * 1024 * 1024)
+///     .max_latency(Duration::from_millis(500));
+///
+/// while let Some(res) = batches.next().await {
+///     let batch = res?;
+///     println!("{} operations up to {:?}", batch.operations.len(), batch.last_optime());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Batches<S> {
+    stream: S,
+    max_operations: usize,
+    max_bytes: u64,
+    max_latency: Duration,
+    operations: Vec<Operation>,
+    bytes: u64,
+    /// The number of transactions in the batch still waiting for further entries.
+    transactions: usize,
+    timer: fn(Duration) -> Timer,
+    delay: Option<Timer>,
+    error: Option<Error>,
+    done: bool,
+}
+
+impl<S> Batches<S>
+where
+    S: Stream<Item = Result<Operation>> + Unpin,
+{
+    /// Creates batches of up to 100 operations, 16MiB and one second of latency.
+    pub fn new(stream: S) -> Batches<S> {
+        Batches {
+            stream,
+            max_operations: 100,
+            max_bytes: 16 * 1024 * 1024,
+            max_latency: Duration::from_secs(1),
+            operations: Vec::new(),
+            bytes: 0,
+            transactions: 0,
+            timer: delay,
+            delay: None,
+            error: None,
+            done: false,
+        }
+    }
+
+    /// Sets the maximum number of operations in a batch.
+    pub fn max_operations(mut self, max_operations: usize) -> Self {
+        self.max_operations = max_operations.max(1);
+        self
+    }
+
+    /// Sets the maximum total size of a batch in bytes.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Sets how long the first operation in a batch may wait for the batch to fill up.
+    pub fn max_latency(mut self, max_latency: Duration) -> Self {
+        self.max_latency = max_latency;
+        self
+    }
+
+    /// Returns the underlying stream, discarding any operations not yet yielded.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn push(&mut self, operation: Operation, bytes: u64) {
+        if self.operations.is_empty() {
+            self.delay = Some((self.timer)(self.max_latency));
+        }
+
+        match operation {
+            Operation::ApplyOps {
+                partial,
+                prepare,
+                ref previous,
+                ..
+            } => match (partial || prepare, previous.is_some()) {
+                (true, false) => self.transactions += 1,
+                (false, true) => self.transactions = self.transactions.saturating_sub(1),
+                _ => {}
+            },
+            Operation::Command { ref command, .. }
+                if command.contains_key("commitTransaction")
+                    || command.contains_key("abortTransaction") =>
+            {
+                self.transactions = self.transactions.saturating_sub(1)
+            }
+            _ => {}
+        }
+
+        self.operations.push(operation);
+        self.bytes += bytes;
+    }
+
+    fn flush(&mut self) -> Batch {
+        self.delay = None;
+
+        Batch {
+            operations: std::mem::take(&mut self.operations),
+            bytes: std::mem::take(&mut self.bytes),
+        }
+    }
+
+    /// Returns true if the first operation in the batch has waited for the maximum latency.
+    ///
+    /// This is checked before adding each operation read as well as whenever the underlying
+    /// stream is pending, so a steady trickle of operations cannot hold a batch back.
+    fn expired(&mut self, cx: &mut Context) -> bool {
+        match self.delay {
+            Some(ref mut delay) => delay.poll_unpin(cx).is_ready(),
+            None => false,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.operations.len() >= self.max_operations || self.bytes >= self.max_bytes
+    }
+
+    /// Returns true if the batch holds an incomplete transaction, so cannot be yielded yet.
+    fn in_transaction(&self) -> bool {
+        self.transactions > 0
+    }
+}
+
+impl<S> Stream for Batches<S>
+where
+    S: Stream<Item = Result<Operation>> + Unpin,
+{
+    type Item = Result<Batch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Some(err) = self.error.take() {
+            return Poll::Ready(Some(Err(err)));
+        }
+
+        loop {
+            if self.done {
+                if self.operations.is_empty() {
+                    return Poll::Ready(None);
+                }
+
+                return Poll::Ready(Some(Ok(self.flush())));
+            }
+
+            match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(operation))) => {
+                    let bytes = document_size(&operation.to_document());
+
+                    let overflows = self.bytes + bytes > self.max_bytes;
+
+                    if !self.operations.is_empty()
+                        && !self.in_transaction()
+                        && (overflows || self.expired(cx))
+                    {
+                        let batch = self.flush();
+                        self.push(operation, bytes);
+
+                        return Poll::Ready(Some(Ok(batch)));
+                    }
+
+                    self.push(operation, bytes);
+
+                    if !self.in_transaction() && self.is_full() {
+                        return Poll::Ready(Some(Ok(self.flush())));
+                    }
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    if self.operations.is_empty() || self.in_transaction() {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+
+                    self.error = Some(err);
+
+                    return Poll::Ready(Some(Ok(self.flush())));
+                }
+                Poll::Ready(None) => self.done = true,
+                Poll::Pending => {
+                    if !self.in_transaction() && self.expired(cx) {
+                        return Poll::Ready(Some(Ok(self.flush())));
+                    }
+
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+    use futures::stream;
+    use mongodb::bson;
+
+    fn insert(time: i64, value: &str) -> Operation {
+        Operation::Insert {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: "foo.bar".into(),
+            document: doc! { "_id": time, "value": value },
+        }
+    }
+
+    fn apply_ops(time: i64, partial: bool, prepare: bool, previous: Option<i64>) -> Operation {
+        Operation::ApplyOps {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: "admin.$cmd".into(),
+            operations: vec![insert(time, "")],
+            partial,
+            prepare,
+            previous: previous.map(|time| Utc.timestamp_opt(time, 0).unwrap().into()),
+        }
+    }
+
+    fn commit(time: i64) -> Operation {
+        Operation::Command {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: "admin.$cmd".into(),
+            command: doc! { "commitTransaction": 1 },
+        }
+    }
+
+    /// Returns a timer that has already expired, so every batch is past its maximum latency.
+    fn expired(_: Duration) -> Timer {
+        Box::pin(futures::future::ready(()))
+    }
+
+    fn counts(batches: Vec<Result<Batch>>) -> Vec<usize> {
+        batches
+            .into_iter()
+            .map(|batch| batch.unwrap().operations.len())
+            .collect()
+    }
+
+    #[test]
+    fn batches_are_bounded_by_count() {
+        let operations = (1..=5).map(|time| Ok(insert(time, "")));
+        let batches = Batches::new(stream::iter(operations)).max_operations(2);
+
+        assert_eq!(counts(block_on(batches.collect())), vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn batches_are_bounded_by_size() {
+        let bytes = document_size(&insert(1, "").to_document());
+        let operations = vec![
+            Ok(insert(1, "")),
+            Ok(insert(2, "")),
+            Ok(insert(3, &"x".repeat(100))),
+        ];
+        let batches = Batches::new(stream::iter(operations)).max_bytes(bytes * 2 + 1);
+        let batches: Vec<_> = block_on(batches.collect());
+
+        assert_eq!(batches[0].as_ref().unwrap().bytes, bytes * 2);
+        assert_eq!(counts(batches), vec![2, 1]);
+    }
+
+    #[test]
+    fn batches_are_bounded_by_latency() {
+        let operations = stream::iter(vec![Ok(insert(1, ""))]).chain(stream::pending());
+        let mut batches = Batches::new(operations).max_latency(Duration::from_millis(10));
+        let batch = block_on(batches.next()).unwrap().unwrap();
+
+        assert_eq!(batch.last_optime(), Some(insert(1, "").optime()));
+    }
+
+    #[test]
+    fn batches_are_bounded_by_latency_while_operations_trickle_in() {
+        // The underlying stream never returns `Poll::Pending`.
+        let operations = (1..=3).map(|time| Ok(insert(time, "")));
+        let mut batches = Batches::new(stream::iter(operations));
+        batches.timer = expired;
+
+        assert_eq!(counts(block_on(batches.collect())), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn batches_do_not_split_large_transactions() {
+        let bytes = document_size(&insert(1, "").to_document());
+        let operations = vec![
+            Ok(insert(1, "")),
+            Ok(apply_ops(2, true, false, None)),
+            Ok(apply_ops(3, false, false, Some(2))),
+            Ok(insert(4, "")),
+        ];
+        let batches = Batches::new(stream::iter(operations)).max_bytes(bytes * 2 + 1);
+        let batches: Vec<_> = block_on(batches.collect());
+
+        assert!(batches[1].as_ref().unwrap().bytes > bytes * 2 + 1);
+        assert_eq!(counts(batches), vec![1, 2, 1]);
+    }
+
+    #[test]
+    fn batches_are_held_open_until_prepared_transactions_commit() {
+        let operations = vec![
+            Ok(insert(1, "")),
+            Ok(apply_ops(2, false, true, None)),
+            Ok(insert(3, "")),
+            Ok(commit(4)),
+            Ok(insert(5, "")),
+        ];
+        let mut batches = Batches::new(stream::iter(operations));
+        batches.timer = expired;
+
+        assert_eq!(counts(block_on(batches.collect())), vec![1, 3, 1]);
+    }
+
+    #[test]
+    fn errors_follow_earlier_operations() {
+        let err = Error::Lagged { capacity: 1 };
+        let operations = vec![Ok(insert(1, "")), Err(err), Ok(insert(2, ""))];
+        let batches: Vec<_> = block_on(Batches::new(stream::iter(operations)).collect());
+
+        assert_eq!(batches.len(), 3);
+        assert!(batches[1].is_err());
+    }
+}
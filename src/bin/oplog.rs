@@ -0,0 +1,384 @@
+//! A command-line tool for tailing and inspecting a MongoDB replica set oplog.
+//!
+//! Build with `cargo build --features cli` and run `oplog --help` for usage.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
+use oplog::bson::{doc, Bson, Document, Timestamp};
+use oplog::mongodb::Client;
+use oplog::{Checkpoint, FileCheckpoint, OpTime, Operation, Oplog, OplogBuilder};
+
+/// Tail and inspect a MongoDB replica set oplog.
+#[derive(Parser)]
+#[command(name = "oplog", version)]
+struct Cli {
+    /// The MongoDB connection string of the replica set.
+    #[arg(long, env = "MONGO_URL", default_value = "mongodb://localhost")]
+    uri: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Follow the oplog, printing operations as they happen.
+    Tail {
+        #[command(flatten)]
+        filter: Filter,
+
+        /// The format to print operations in.
+        #[arg(long, value_enum, default_value_t = Format::Human)]
+        format: Format,
+
+        /// A file recording the last operation printed. If it exists, tailing resumes after that
+        /// operation and --since is ignored.
+        #[arg(long)]
+        checkpoint: Option<PathBuf>,
+    },
+    /// Print the operations in a range of time, then exit.
+    Range {
+        #[command(flatten)]
+        filter: Filter,
+
+        #[command(flatten)]
+        end: End,
+
+        /// The format to print operations in.
+        #[arg(long, value_enum, default_value_t = Format::Human)]
+        format: Format,
+    },
+    /// Count the operations in a range of time by namespace.
+    Stats {
+        #[command(flatten)]
+        filter: Filter,
+
+        #[command(flatten)]
+        end: End,
+    },
+    /// Write the operations in a range of time as a stream of BSON oplog entries.
+    Dump {
+        #[command(flatten)]
+        filter: Filter,
+
+        #[command(flatten)]
+        end: End,
+
+        /// The file to write to instead of standard output.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Options restricting the operations read.
+#[derive(Args)]
+struct Filter {
+    /// Only read operations on this namespace (e.g. `db.collection`). May be repeated.
+    ///
+    /// Transactions (`applyOps` entries) touching the namespace are read in full, including any
+    /// operations on other namespaces within them.
+    #[arg(long = "ns")]
+    namespaces: Vec<String>,
+
+    /// Only read operations of this type. May be repeated.
+    #[arg(long = "op", value_enum)]
+    ops: Vec<OpType>,
+
+    /// Start from this time, given as RFC 3339 (e.g. `2024-01-31T12:00:00Z`) or as a BSON
+    /// timestamp of seconds and an optional increment (e.g. `1706702400:3`).
+    #[arg(long, value_parser = parse_since)]
+    since: Option<Timestamp>,
+}
+
+/// Options for where a range of operations ends.
+#[derive(Args)]
+struct End {
+    /// Stop after this time, in the same formats as --since. RFC 3339 times include every
+    /// operation in their second. Defaults to now.
+    #[arg(long, value_parser = parse_until)]
+    until: Option<Timestamp>,
+
+    /// Stop if no operations are read for this many seconds, as the oplog does not end when its
+    /// newest entry has been read.
+    #[arg(long, default_value_t = 5)]
+    idle_timeout: u64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OpType {
+    Insert,
+    Update,
+    Delete,
+    Command,
+    Noop,
+}
+
+impl OpType {
+    /// Returns the `op` code of the operation type in the oplog.
+    fn code(self) -> &'static str {
+        match self {
+            OpType::Insert => "i",
+            OpType::Update => "u",
+            OpType::Delete => "d",
+            OpType::Command => "c",
+            OpType::Noop => "n",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    /// One human-readable line per operation.
+    Human,
+    /// One line of relaxed Extended JSON per operation.
+    Json,
+    /// A stream of BSON documents.
+    Bson,
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("oplog: {}", e);
+        process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let client = Client::with_uri_str(&cli.uri).await?;
+
+    match cli.command {
+        Command::Tail {
+            filter,
+            format,
+            checkpoint,
+        } => {
+            let mut builder = filter.builder();
+            let mut checkpoint = checkpoint.map(FileCheckpoint::new);
+
+            if let Some(optime) = checkpoint
+                .as_mut()
+                .map(Checkpoint::load)
+                .transpose()?
+                .flatten()
+            {
+                builder = builder.start_after(optime.timestamp);
+            }
+
+            let mut oplog = builder.build(&client).await?;
+            let mut out = io::stdout().lock();
+
+            while let Some(res) = oplog.next().await {
+                let operation = res?;
+                write_operation(&mut out, &operation, format)?;
+                out.flush()?;
+
+                if let (Some(checkpoint), Some(timestamp)) =
+                    (&mut checkpoint, oplog.last_timestamp())
+                {
+                    checkpoint.commit(OpTime {
+                        timestamp,
+                        term: None,
+                        hash: None,
+                    })?;
+                }
+            }
+
+            Err(empty_oplog())
+        }
+        Command::Range {
+            filter,
+            end,
+            format,
+        } => {
+            let mut oplog = filter.builder().build(&client).await?;
+            let mut out = BufWriter::new(io::stdout().lock());
+            let end = end.range()?;
+
+            while let Some(operation) = end.next(&mut oplog).await? {
+                write_operation(&mut out, &operation, format)?;
+            }
+
+            Ok(out.flush()?)
+        }
+        Command::Stats { filter, end } => {
+            let mut oplog = filter.builder().build(&client).await?;
+            let mut counts = std::collections::BTreeMap::new();
+            let end = end.range()?;
+
+            while let Some(operation) = end.next(&mut oplog).await? {
+                let namespace = operation.namespace().unwrap_or("").to_string();
+                *counts.entry(namespace).or_insert(0_u64) += 1;
+            }
+
+            println!("{:<40} {:>10}", "NAMESPACE", "COUNT");
+            for (namespace, count) in counts {
+                println!("{:<40} {:>10}", namespace, count);
+            }
+
+            Ok(())
+        }
+        Command::Dump {
+            filter,
+            end,
+            output,
+        } => {
+            let mut oplog = filter.builder().build(&client).await?;
+            let mut out: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(fs::File::create(path)?)),
+                None => Box::new(BufWriter::new(io::stdout().lock())),
+            };
+            let end = end.range()?;
+
+            while let Some(operation) = end.next(&mut oplog).await? {
+                write_operation(&mut out, &operation, Format::Bson)?;
+            }
+
+            Ok(out.flush()?)
+        }
+    }
+}
+
+impl Filter {
+    /// Returns an `OplogBuilder` restricted to the requested operations.
+    fn builder(&self) -> OplogBuilder {
+        let mut query = Document::new();
+
+        if !self.namespaces.is_empty() {
+            query.insert(
+                "$or",
+                vec![
+                    doc! { "ns": { "$in": &self.namespaces } },
+                    doc! { "o.applyOps.ns": { "$in": &self.namespaces } },
+                ],
+            );
+        }
+
+        if !self.ops.is_empty() {
+            let codes: Vec<_> = self.ops.iter().map(|op| op.code()).collect();
+            query.insert("op", doc! { "$in": codes });
+        }
+
+        let mut builder = Oplog::builder();
+
+        if !query.is_empty() {
+            builder = builder.filter(query);
+        }
+
+        match self.since {
+            Some(ts) => builder.start_at(ts),
+            None => builder,
+        }
+    }
+}
+
+impl End {
+    /// Fixes the end of the range, defaulting to the time it is called.
+    fn range(&self) -> Result<Range> {
+        let until = match self.until {
+            Some(until) => until,
+            None => Timestamp {
+                time: u32::try_from(Utc::now().timestamp())?,
+                increment: u32::MAX,
+            },
+        };
+
+        Ok(Range {
+            until,
+            idle: Duration::from_secs(self.idle_timeout),
+        })
+    }
+}
+
+/// The end of a range of operations, fixed when the range starts being read.
+struct Range {
+    until: Timestamp,
+    idle: Duration,
+}
+
+impl Range {
+    /// Returns the next operation in the range, or `None` once it has ended.
+    async fn next(&self, oplog: &mut Oplog) -> Result<Option<Operation>> {
+        match tokio::time::timeout(self.idle, oplog.next()).await {
+            Ok(Some(res)) => {
+                let operation = res?;
+
+                if oplog.last_timestamp() > Some(self.until) {
+                    Ok(None)
+                } else {
+                    Ok(Some(operation))
+                }
+            }
+            Ok(None) => Err(empty_oplog()),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Writes an operation in the given format.
+fn write_operation<W: Write>(out: &mut W, operation: &Operation, format: Format) -> Result<()> {
+    match format {
+        Format::Human => writeln!(out, "{}", operation)?,
+        Format::Json => {
+            let json = Bson::Document(operation.to_document()).into_relaxed_extjson();
+            writeln!(out, "{}", json)?;
+        }
+        Format::Bson => operation.to_document().to_writer(out)?,
+    }
+
+    Ok(())
+}
+
+/// Parses a --since timestamp given as RFC 3339 or as `<seconds>[:<increment>]`.
+///
+/// RFC 3339 times start from the first operation in their second.
+fn parse_since(s: &str) -> std::result::Result<Timestamp, String> {
+    parse_timestamp(s, 0)
+}
+
+/// Parses an --until timestamp given as RFC 3339 or as `<seconds>[:<increment>]`.
+///
+/// RFC 3339 times include every operation in their second.
+fn parse_until(s: &str) -> std::result::Result<Timestamp, String> {
+    parse_timestamp(s, u32::MAX)
+}
+
+/// Parses a timestamp given as RFC 3339, with the given increment, or as
+/// `<seconds>[:<increment>]`.
+fn parse_timestamp(s: &str, increment: u32) -> std::result::Result<Timestamp, String> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(s) {
+        let time = u32::try_from(datetime.timestamp())
+            .map_err(|_| format!("{:?} is outside the range of BSON timestamps", s))?;
+
+        return Ok(Timestamp { time, increment });
+    }
+
+    let (time, increment) = match s.split_once(':') {
+        Some((time, increment)) => (time, increment),
+        None => (s, "0"),
+    };
+
+    match (time.parse(), increment.parse()) {
+        (Ok(time), Ok(increment)) => Ok(Timestamp { time, increment }),
+        _ => Err(format!(
+            "expected RFC 3339 or <seconds>[:<increment>], got {:?}",
+            s
+        )),
+    }
+}
+
+fn empty_oplog() -> Box<dyn std::error::Error> {
+    "oplog cursor ended. This probably means the oplog.rs collection is empty. \
+     See https://jira.mongodb.org/browse/SERVER-13955"
+        .into()
+}
@@ -0,0 +1,252 @@
+//! The cloudevents module maps operations onto CloudEvents 1.0 for delivery over HTTP in either
+//! structured or binary content mode.
+
+use crate::{Namespace, Operation};
+use bson::Bson;
+use chrono::{SecondsFormat, TimeZone, Utc};
+use mongodb::bson;
+use serde_json::{json, Value};
+
+/// The media type of an event in structured content mode.
+pub const STRUCTURED_CONTENT_TYPE: &str = "application/cloudevents+json";
+
+/// A CloudEvent for a single operation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloudEvent {
+    /// The event id, unique per operation and stable across redeliveries of it: the oplog
+    /// timestamp as `<seconds>.<increment>`, with the index of the operation appended for those
+    /// within an `applyOps` entry (e.g. `1479561394.3-2`).
+    pub id: String,
+    /// The `source` attribute, rendered from the source template.
+    pub source: String,
+    /// The `type` attribute, rendered from the type template.
+    pub event_type: String,
+    /// The `time` attribute: the time of the operation as RFC 3339, to the second.
+    pub time: String,
+    /// The `subject` attribute: the `_id` of the document as relaxed Extended JSON, if any.
+    pub subject: Option<String>,
+    /// The operation as a relaxed Extended JSON oplog entry (see `Operation::to_document`).
+    pub data: Value,
+}
+
+impl CloudEvent {
+    /// Returns the event in structured content mode, to be sent as the body of a request with the
+    /// content type `STRUCTURED_CONTENT_TYPE`.
+    pub fn to_structured(&self) -> Value {
+        let mut event = json!({
+            "specversion": "1.0",
+            "id": self.id,
+            "source": self.source,
+            "type": self.event_type,
+            "time": self.time,
+            "datacontenttype": "application/json",
+            "data": self.data,
+        });
+
+        if let Some(ref subject) = self.subject {
+            event["subject"] = Value::String(subject.clone());
+        }
+
+        event
+    }
+
+    /// Returns the HTTP headers and body of the event in binary content mode.
+    pub fn to_binary(&self) -> (Vec<(&'static str, String)>, Vec<u8>) {
+        let mut headers = vec![
+            ("ce-specversion", "1.0".to_string()),
+            ("ce-id", self.id.clone()),
+            ("ce-source", self.source.clone()),
+            ("ce-type", self.event_type.clone()),
+            ("ce-time", self.time.clone()),
+        ];
+
+        if let Some(ref subject) = self.subject {
+            headers.push(("ce-subject", subject.clone()));
+        }
+
+        headers.push(("content-type", "application/json".to_string()));
+
+        (headers, self.data.to_string().into_bytes())
+    }
+}
+
+/// Maps operations onto CloudEvents.
+///
+/// The `source` and `type` attributes are rendered from templates in which `{db}`,
+/// `{collection}`, `{ns}` and `{op}` are replaced by the database, collection and full namespace
+/// of the operation and its kind (see `Operation::kind`). An `Operation::ApplyOps` becomes one
+/// event per operation it applies and no-ops produce no events.
+///
+/// # Example
+///
+/// ```
+/// use oplog::bson::{doc, Timestamp};
+/// use oplog::{CloudEvents, Operation};
+///
+/// let operation = Operation::new(&doc! {
+///     "ts": Timestamp { time: 1479561394, increment: 3 },
+///     "op": "i",
+///     "ns": "foo.bar",
+///     "o": { "_id": 1, "foo": "bar" },
+/// }).unwrap();
+///
+/// let events = CloudEvents::new()
+///     .source("/mongodb/{db}/{collection}")
+///     .event_type("com.example.{op}")
+///     .convert(&operation);
+///
+/// assert_eq!(events[0].id, "1479561394.3");
+/// assert_eq!(events[0].source, "/mongodb/foo/bar");
+/// assert_eq!(events[0].event_type, "com.example.insert");
+/// ```
+#[derive(Clone, Debug)]
+pub struct CloudEvents {
+    source: String,
+    event_type: String,
+}
+
+impl Default for CloudEvents {
+    fn default() -> CloudEvents {
+        CloudEvents {
+            source: "/mongodb/{ns}".to_string(),
+            event_type: "mongodb.oplog.{op}".to_string(),
+        }
+    }
+}
+
+impl CloudEvents {
+    /// Creates a mapping with the source template `/mongodb/{ns}` and the type template
+    /// `mongodb.oplog.{op}`.
+    pub fn new() -> CloudEvents {
+        CloudEvents::default()
+    }
+
+    /// Sets the template of the `source` attribute.
+    pub fn source(mut self, template: &str) -> Self {
+        self.source = template.to_string();
+        self
+    }
+
+    /// Sets the template of the `type` attribute.
+    pub fn event_type(mut self, template: &str) -> Self {
+        self.event_type = template.to_string();
+        self
+    }
+
+    /// Returns the events for an operation.
+    pub fn convert(&self, operation: &Operation) -> Vec<CloudEvent> {
+        let timestamp = operation.optime().timestamp;
+        let id = format!("{}.{}", timestamp.time, timestamp.increment);
+        // Oplog timestamps only have a resolution of a second, the increment being a counter
+        // within it rather than a fraction of it.
+        let time = Utc
+            .timestamp_opt(i64::from(timestamp.time), 0)
+            .unwrap()
+            .to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        match *operation {
+            Operation::Noop { .. } => Vec::new(),
+            Operation::ApplyOps { ref operations, .. } => operations
+                .iter()
+                .enumerate()
+                .filter(|(_, operation)| !matches!(operation, Operation::Noop { .. }))
+                .map(|(i, operation)| self.event(format!("{}-{}", id, i), &time, operation))
+                .collect(),
+            _ => vec![self.event(id, &time, operation)],
+        }
+    }
+
+    fn event(&self, id: String, time: &str, operation: &Operation) -> CloudEvent {
+        CloudEvent {
+            id,
+            source: self.render(&self.source, operation),
+            event_type: self.render(&self.event_type, operation),
+            time: time.to_string(),
+            subject: operation
+                .document_id()
+                .map(|id| id.clone().into_relaxed_extjson().to_string()),
+            data: Bson::Document(operation.to_document()).into_relaxed_extjson(),
+        }
+    }
+
+    /// Replaces the placeholders of a template with the attributes of an operation.
+    fn render(&self, template: &str, operation: &Operation) -> String {
+        let ns = operation.namespace().unwrap_or("");
+        let namespace = Namespace::new(ns);
+
+        template
+            .replace("{db}", namespace.database)
+            .replace("{collection}", namespace.collection)
+            .replace("{ns}", ns)
+            .replace("{op}", operation.kind())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::{doc, Timestamp};
+
+    fn insert(id: i32) -> Operation {
+        Operation::Insert {
+            timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+            namespace: "foo.bar".into(),
+            document: doc! { "_id": id },
+        }
+    }
+
+    #[test]
+    fn convert_numbers_operations_within_apply_ops() {
+        let operation = Operation::ApplyOps {
+            timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+            namespace: "admin.$cmd".into(),
+            operations: vec![insert(1), insert(2)],
+            partial: false,
+            prepare: false,
+            previous: None,
+        };
+        let events = CloudEvents::new().convert(&operation);
+        let ids: Vec<_> = events.iter().map(|event| event.id.as_str()).collect();
+
+        assert_eq!(ids, vec!["1479561394.0-0", "1479561394.0-1"]);
+        assert_eq!(events[1].subject, Some("2".to_string()));
+        assert_eq!(events[1].source, "/mongodb/foo.bar");
+    }
+
+    #[test]
+    fn to_structured_includes_context_attributes() {
+        let event = &CloudEvents::new().convert(&insert(1))[0];
+        let structured = event.to_structured();
+
+        assert_eq!(structured["specversion"], "1.0");
+        assert_eq!(structured["type"], "mongodb.oplog.insert");
+        assert_eq!(structured["time"], "2016-11-19T13:16:34Z");
+        assert_eq!(structured["subject"], "1");
+        assert_eq!(structured["data"]["o"], json!({ "_id": 1 }));
+    }
+
+    #[test]
+    fn convert_ignores_the_increment_in_the_time() {
+        let operation = Operation::new(&doc! {
+            "ts": Timestamp { time: 1479561394, increment: 3 },
+            "op": "i",
+            "ns": "foo.bar",
+            "o": { "_id": 1 },
+        })
+        .unwrap();
+        let event = &CloudEvents::new().convert(&operation)[0];
+
+        assert_eq!(event.id, "1479561394.3");
+        assert_eq!(event.time, "2016-11-19T13:16:34Z");
+    }
+
+    #[test]
+    fn to_binary_moves_attributes_into_headers() {
+        let event = &CloudEvents::new().convert(&insert(1))[0];
+        let (headers, body) = event.to_binary();
+
+        assert!(headers.contains(&("ce-id", "1479561394.0".to_string())));
+        assert!(headers.contains(&("content-type", "application/json".to_string())));
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), event.data);
+    }
+}
@@ -0,0 +1,472 @@
+//! The coalesce module collapses redundant operations on the same document, for replaying a
+//! backlog without applying every intermediate state.
+
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::batch::{delay, Timer};
+use crate::update::Change;
+use crate::{Error, OpTime, Operation, Result};
+use futures::{FutureExt, Stream, StreamExt};
+
+/// A stream adapter collapsing operations on the same document within a window.
+///
+/// Operations are buffered until the window holds the maximum number of operations, the first
+/// has waited for the maximum latency, or a barrier is read. Within the window, operations on the
+/// same namespace and `_id` are collapsed:
+///
+/// * an insert followed by updates becomes a single insert of the updated document
+/// * successive updates become a single update, merging `$set` and `$unset` (including the
+///   `$v: 2` diffs of MongoDB 5.0) or applying them to a replacement document
+/// * an insert followed by a delete disappears altogether
+/// * an update followed by a delete becomes the delete
+///
+/// Collapsed operations take the timestamp and position in the stream of the last operation they
+/// replace, so timestamps stay in order. Updates that cannot be merged (e.g. with array updates or
+/// paths that overlap) are left as they are.
+///
+/// As collapsing moves operations later in the stream, the timestamp of an operation yielded
+/// mid-window is not safe to checkpoint: given an insert of A at `t1`, an update of B at `t2` and
+/// an update of A at `t3`, the update of B is yielded first and resuming after `t2` would lose the
+/// insert of A. Checkpoint `Coalesce::checkpoint` instead, which only moves once every operation
+/// of a window has been yielded.
+///
+/// Commands, transactions (`Operation::ApplyOps`) and unknown operations are barriers: the
+/// window is emitted before them and nothing is collapsed across them.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use futures::StreamExt;
+/// use mongodb::Client;
+/// use oplog::{Coalesce, Oplog};
+///
+/// # async fn run() -> Result<(), oplog::Error> {
+/// let client = Client::with_uri_str("mongodb://localhost").await?;
+/// let oplog = Oplog::new(&client).await?;
+///
+/// let mut operations = Coalesce::new(oplog).window(10_000);
+///
+/// while let Some(res) = operations.next().await {
+///     println!("{:?}", res?);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Coalesce<S> {
+    stream: S,
+    window: usize,
+    max_latency: Duration,
+    slots: Vec<Option<Operation>>,
+    latest: HashMap<String, usize>,
+    read: usize,
+    timer: fn(Duration) -> Timer,
+    delay: Option<Timer>,
+    ready: VecDeque<Operation>,
+    last: Option<OpTime>,
+    flushed: Option<OpTime>,
+    checkpoint: Option<OpTime>,
+    error: Option<Error>,
+    done: bool,
+}
+
+impl<S> Coalesce<S>
+where
+    S: Stream<Item = Result<Operation>> + Unpin,
+{
+    /// Creates a window of up to 1000 operations and one second of latency.
+    pub fn new(stream: S) -> Coalesce<S> {
+        Coalesce {
+            stream,
+            window: 1000,
+            max_latency: Duration::from_secs(1),
+            slots: Vec::new(),
+            latest: HashMap::new(),
+            read: 0,
+            timer: delay,
+            delay: None,
+            ready: VecDeque::new(),
+            last: None,
+            flushed: None,
+            checkpoint: None,
+            error: None,
+            done: false,
+        }
+    }
+
+    /// Sets the maximum number of operations read into a window.
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Sets how long the first operation in a window may wait for the window to fill up.
+    pub fn max_latency(mut self, max_latency: Duration) -> Self {
+        self.max_latency = max_latency;
+        self
+    }
+
+    /// Returns the position of the last operation read into a window that has been yielded in
+    /// full, if any.
+    ///
+    /// This is the only position safe to checkpoint: everything up to and including it has been
+    /// yielded, collapsed or dropped, and nothing after it has been yielded.
+    pub fn checkpoint(&self) -> Option<OpTime> {
+        self.checkpoint
+    }
+
+    /// Adds an operation to the window, returning true if it is a barrier.
+    fn push(&mut self, operation: Operation) -> bool {
+        if self.read == 0 {
+            self.delay = Some((self.timer)(self.max_latency));
+        }
+        self.read += 1;
+        self.last = Some(operation.optime());
+
+        let key = match operation {
+            Operation::Command { .. } | Operation::ApplyOps { .. } | Operation::Unknown { .. } => {
+                self.slots.push(Some(operation));
+                return true;
+            }
+            _ => match operation.document_key() {
+                Some(key) => key,
+                None => {
+                    self.slots.push(Some(operation));
+                    return false;
+                }
+            },
+        };
+
+        let operation = match self.latest.get(&key) {
+            Some(&index) => {
+                let earlier = self.slots[index]
+                    .as_ref()
+                    .expect("latest operation in window");
+
+                match collapse(earlier, &operation) {
+                    Some(None) => {
+                        self.slots[index] = None;
+                        self.latest.remove(&key);
+                        return false;
+                    }
+                    Some(Some(operation)) => {
+                        self.slots[index] = None;
+                        operation
+                    }
+                    None => operation,
+                }
+            }
+            None => operation,
+        };
+
+        self.latest.insert(key, self.slots.len());
+        self.slots.push(Some(operation));
+
+        false
+    }
+
+    /// Returns true if the first operation in the window has waited for the maximum latency.
+    ///
+    /// This is checked before adding each operation read as well as whenever the underlying
+    /// stream is pending, so a steady trickle of operations cannot hold a window open.
+    fn expired(&mut self, cx: &mut Context) -> bool {
+        match self.delay {
+            Some(ref mut delay) => delay.poll_unpin(cx).is_ready(),
+            None => false,
+        }
+    }
+
+    /// Moves the operations in the window to those ready to be yielded.
+    fn flush(&mut self) {
+        self.ready.extend(self.slots.drain(..).flatten());
+        self.latest.clear();
+        self.read = 0;
+        self.delay = None;
+
+        if let Some(last) = self.last.take() {
+            if self.ready.is_empty() {
+                self.checkpoint = Some(last);
+            } else {
+                self.flushed = Some(last);
+            }
+        }
+    }
+}
+
+impl<S> Stream for Coalesce<S>
+where
+    S: Stream<Item = Result<Operation>> + Unpin,
+{
+    type Item = Result<Operation>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(operation) = self.ready.pop_front() {
+                if self.ready.is_empty() {
+                    self.checkpoint = self.flushed.take().or(self.checkpoint);
+                }
+
+                return Poll::Ready(Some(Ok(operation)));
+            }
+
+            if let Some(err) = self.error.take() {
+                return Poll::Ready(Some(Err(err)));
+            }
+
+            if self.done {
+                return Poll::Ready(None);
+            }
+
+            match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(operation))) => {
+                    if self.expired(cx) {
+                        self.flush();
+                    }
+
+                    if self.push(operation) || self.read >= self.window {
+                        self.flush();
+                    }
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    self.flush();
+                    self.error = Some(err);
+                }
+                Poll::Ready(None) => {
+                    self.flush();
+                    self.done = true;
+                }
+                Poll::Pending => {
+                    if !self.expired(cx) {
+                        return Poll::Pending;
+                    }
+
+                    self.flush();
+                }
+            }
+        }
+    }
+}
+
+/// Collapses two operations on the same document into the one operation replacing them, if any,
+/// returning `None` if both are needed.
+fn collapse(earlier: &Operation, later: &Operation) -> Option<Option<Operation>> {
+    let collapsed = match (earlier, later) {
+        (Operation::Insert { .. }, Operation::Delete { .. }) => return Some(None),
+        (Operation::Update { .. }, Operation::Delete { .. }) => Some(later.clone()),
+        (
+            Operation::Insert {
+                namespace,
+                document,
+                ..
+            },
+            Operation::Update {
+                timestamp, update, ..
+            },
+        ) => Change::new(update)
+            .and_then(|change| change.apply(document))
+            .map(|document| Operation::Insert {
+                timestamp: *timestamp,
+                namespace: namespace.clone(),
+                document,
+            }),
+        (
+            Operation::Update { update: first, .. },
+            Operation::Update {
+                timestamp,
+                namespace,
+                query,
+                update: second,
+            },
+        ) => Change::new(first)
+            .zip(Change::new(second))
+            .and_then(|(first, second)| first.merge(second))
+            .map(|change| Operation::Update {
+                timestamp: *timestamp,
+                namespace: namespace.clone(),
+                query: query.clone(),
+                update: change.into_update(),
+            }),
+        _ => None,
+    };
+
+    collapsed.map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::{doc, Document};
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+    use futures::stream;
+    use mongodb::bson;
+
+    fn insert(time: i64, document: Document) -> Operation {
+        Operation::Insert {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: "foo.bar".into(),
+            document,
+        }
+    }
+
+    fn update(time: i64, id: i32, update: Document) -> Operation {
+        Operation::Update {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: "foo.bar".into(),
+            query: doc! { "_id": id },
+            update,
+        }
+    }
+
+    fn delete(time: i64, id: i32) -> Operation {
+        Operation::Delete {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: "foo.bar".into(),
+            query: doc! { "_id": id },
+        }
+    }
+
+    fn command(time: i64) -> Operation {
+        Operation::Command {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: "foo.$cmd".into(),
+            command: doc! { "drop": "bar" },
+        }
+    }
+
+    fn coalesce(operations: Vec<Operation>) -> Vec<Operation> {
+        let stream = Coalesce::new(stream::iter(operations.into_iter().map(Ok)));
+
+        block_on(stream.map(Result::unwrap).collect())
+    }
+
+    #[test]
+    fn coalesce_folds_updates_into_inserts() {
+        let operations = coalesce(vec![
+            insert(1, doc! { "_id": 1, "a": 1, "b": { "c": 1 } }),
+            insert(2, doc! { "_id": 2 }),
+            update(3, 1, doc! { "$set": { "a": 2, "b.d": 3 } }),
+            update(4, 1, doc! { "$v": 2, "diff": { "d": { "a": false } } }),
+        ]);
+
+        assert_eq!(
+            operations,
+            vec![
+                insert(2, doc! { "_id": 2 }),
+                insert(4, doc! { "_id": 1, "b": { "c": 1, "d": 3 } }),
+            ]
+        );
+    }
+
+    #[test]
+    fn coalesce_merges_successive_updates() {
+        let operations = coalesce(vec![
+            update(1, 1, doc! { "$set": { "a": 1, "b": 1 } }),
+            update(2, 1, doc! { "$unset": { "a": true }, "$set": { "c": 1 } }),
+        ]);
+
+        assert_eq!(
+            operations,
+            vec![update(
+                2,
+                1,
+                doc! { "$set": { "b": 1, "c": 1 }, "$unset": { "a": 1 } }
+            )]
+        );
+    }
+
+    #[test]
+    fn coalesce_keeps_overlapping_updates() {
+        let operations = vec![
+            update(1, 1, doc! { "$set": { "a": { "b": 1 } } }),
+            update(2, 1, doc! { "$set": { "a.b": 2 } }),
+        ];
+
+        assert_eq!(coalesce(operations.clone()), operations);
+    }
+
+    #[test]
+    fn coalesce_drops_inserts_that_are_deleted() {
+        let operations = coalesce(vec![
+            insert(1, doc! { "_id": 1 }),
+            update(2, 1, doc! { "$set": { "a": 1 } }),
+            delete(3, 1),
+            update(4, 2, doc! { "$set": { "a": 1 } }),
+            delete(5, 2),
+        ]);
+
+        assert_eq!(operations, vec![delete(5, 2)]);
+    }
+
+    #[test]
+    fn coalesce_only_checkpoints_whole_windows() {
+        let operations = vec![
+            insert(1, doc! { "_id": 1 }),
+            update(2, 2, doc! { "$set": { "a": 1 } }),
+            update(3, 1, doc! { "$set": { "a": 1 } }),
+        ];
+        let mut stream = Coalesce::new(stream::iter(operations.into_iter().map(Ok)));
+
+        block_on(async {
+            assert_eq!(
+                stream.next().await.unwrap().unwrap(),
+                update(2, 2, doc! { "$set": { "a": 1 } })
+            );
+            assert_eq!(stream.checkpoint(), None);
+
+            assert_eq!(
+                stream.next().await.unwrap().unwrap(),
+                insert(3, doc! { "_id": 1, "a": 1 })
+            );
+            assert_eq!(
+                stream.checkpoint().map(|optime| optime.timestamp.time),
+                Some(3)
+            );
+        });
+    }
+
+    #[test]
+    fn coalesce_checkpoints_windows_that_collapse_away() {
+        let operations = vec![insert(1, doc! { "_id": 1 }), delete(2, 1)];
+        let mut stream = Coalesce::new(stream::iter(operations.into_iter().map(Ok)));
+
+        block_on(async {
+            assert!(stream.next().await.is_none());
+        });
+
+        assert_eq!(
+            stream.checkpoint().map(|optime| optime.timestamp.time),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn coalesce_bounds_latency_while_operations_trickle_in() {
+        // The underlying stream never returns `Poll::Pending` and every window has expired by
+        // the time the next operation is read, so nothing is collapsed.
+        let operations = vec![
+            insert(1, doc! { "_id": 1 }),
+            update(2, 1, doc! { "$set": { "a": 1 } }),
+        ];
+        let mut stream = Coalesce::new(stream::iter(operations.clone().into_iter().map(Ok)));
+        stream.timer = |_| Box::pin(futures::future::ready(()));
+
+        assert_eq!(
+            block_on(stream.map(Result::unwrap).collect::<Vec<_>>()),
+            operations
+        );
+    }
+
+    #[test]
+    fn coalesce_does_not_collapse_across_commands() {
+        let operations = vec![
+            update(1, 1, doc! { "$set": { "a": 1 } }),
+            command(2),
+            update(3, 1, doc! { "$set": { "b": 1 } }),
+        ];
+
+        assert_eq!(coalesce(operations.clone()), operations);
+    }
+}
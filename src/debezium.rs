@@ -0,0 +1,300 @@
+//! The debezium module converts operations into the change event format of the Debezium MongoDB
+//! connector so that existing consumers of that format can be fed from this crate instead.
+
+use crate::update::Change;
+use crate::{Namespace, Operation};
+use bson::{Bson, Document};
+use chrono::Utc;
+use mongodb::bson;
+use serde_json::{json, Value};
+
+/// A Debezium change event, made up of the message key and value as JSON.
+///
+/// Both are in the format produced by the JSON converter with schemas disabled: the key holds the
+/// document `_id` and the value is the event envelope.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebeziumEvent {
+    /// The message key, e.g. `{"id": "{\"$oid\": \"...\"}"}`.
+    pub key: Value,
+    /// The event envelope with `op`, `before`, `after`, `updateDescription`, `source` and `ts_ms`.
+    pub value: Value,
+}
+
+/// The logical session of a write, as recorded in the `lsid` and `txnNumber` fields of oplog
+/// entries written by retryable writes and transactions.
+///
+/// These fields are not kept in an `Operation` so must be read from the raw oplog entry, e.g. with
+/// `Session::from_entry`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Session {
+    /// The logical session id (`lsid`).
+    pub lsid: Option<Document>,
+    /// The transaction number within the session (`txnNumber`).
+    pub txn_number: Option<i64>,
+}
+
+impl Session {
+    /// Reads the session of a raw oplog entry.
+    pub fn from_entry(entry: &Document) -> Session {
+        Session {
+            lsid: entry.get_document("lsid").ok().cloned(),
+            txn_number: entry.get_i64("txnNumber").ok(),
+        }
+    }
+}
+
+/// Converts operations into Debezium MongoDB connector change events.
+///
+/// Inserts become `c` events with `after`, deletes become `d` events, replacement updates become
+/// `u` events with `after`, and other updates become `u` events with an `updateDescription`
+/// listing updated and removed fields (for both `$set`/`$unset` and the `$v: 2` diff format of
+/// MongoDB 5.0). As with the connector, documents are encoded as strings of relaxed Extended JSON.
+/// The operations within an `Operation::ApplyOps` each become an event; other commands and no-ops
+/// produce none.
+///
+/// # Example
+///
+/// ```
+/// use oplog::bson::{doc, Timestamp};
+/// use oplog::{Debezium, Operation};
+///
+/// let operation = Operation::new(&doc! {
+///     "ts": Timestamp { time: 1479561394, increment: 2 },
+///     "op": "i",
+///     "ns": "inventory.customers",
+///     "o": { "_id": 1, "name": "Alice" },
+/// }).unwrap();
+///
+/// let events = Debezium::new("dbserver1", "rs0").convert(&operation, None);
+/// let value = &events[0].value;
+///
+/// assert_eq!(value["op"], "c");
+/// assert_eq!(value["source"]["collection"], "customers");
+/// assert_eq!(value["source"]["ord"], 2);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Debezium {
+    server_name: String,
+    replica_set: String,
+}
+
+impl Debezium {
+    /// Creates a converter for the given logical server name (the connector's `topic.prefix`)
+    /// and replica set name.
+    pub fn new(server_name: &str, replica_set: &str) -> Debezium {
+        Debezium {
+            server_name: server_name.to_string(),
+            replica_set: replica_set.to_string(),
+        }
+    }
+
+    /// Returns the change events for an operation, with the session of its oplog entry if known.
+    pub fn convert(&self, operation: &Operation, session: Option<&Session>) -> Vec<DebeziumEvent> {
+        let mut events = Vec::new();
+        self.push_events(&mut events, operation, operation, session);
+
+        events
+    }
+
+    /// Returns a read (`r`) event for a document, as emitted for each document in a snapshot.
+    pub fn read(&self, namespace: &str, document: &Document) -> DebeziumEvent {
+        let source = json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "connector": "mongodb",
+            "name": self.server_name,
+            "ts_ms": Utc::now().timestamp_millis(),
+            "snapshot": "true",
+            "db": Namespace::new(namespace).database,
+            "rs": self.replica_set,
+            "collection": Namespace::new(namespace).collection,
+            "ord": 0,
+            "lsid": Value::Null,
+            "txnNumber": Value::Null,
+        });
+
+        event(
+            document.get("_id"),
+            json!({
+                "before": Value::Null,
+                "after": extjson_string(document),
+                "updateDescription": Value::Null,
+                "source": source,
+                "op": "r",
+                "ts_ms": Utc::now().timestamp_millis(),
+                "transaction": Value::Null,
+            }),
+        )
+    }
+
+    fn push_events(
+        &self,
+        events: &mut Vec<DebeziumEvent>,
+        entry: &Operation,
+        operation: &Operation,
+        session: Option<&Session>,
+    ) {
+        let (op, after, update_description) = match *operation {
+            Operation::Insert { ref document, .. } => ("c", extjson_string(document), Value::Null),
+            Operation::Update { ref update, .. } => match update_description(update) {
+                Some(description) => ("u", Value::Null, description),
+                None => ("u", extjson_string(update), Value::Null),
+            },
+            Operation::Delete { .. } => ("d", Value::Null, Value::Null),
+            Operation::ApplyOps { ref operations, .. } => {
+                for operation in operations {
+                    self.push_events(events, entry, operation, session);
+                }
+
+                return;
+            }
+            _ => return,
+        };
+
+        let value = json!({
+            "before": Value::Null,
+            "after": after,
+            "updateDescription": update_description,
+            "source": self.source(entry, operation, session),
+            "op": op,
+            "ts_ms": Utc::now().timestamp_millis(),
+            "transaction": Value::Null,
+        });
+
+        events.push(event(operation.document_id(), value));
+    }
+
+    /// Returns the `source` block for an operation within an oplog entry.
+    fn source(&self, entry: &Operation, operation: &Operation, session: Option<&Session>) -> Value {
+        let namespace = Namespace::new(operation.namespace().unwrap_or(""));
+        let optime = entry.optime();
+        let session = session.cloned().unwrap_or_default();
+
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "connector": "mongodb",
+            "name": self.server_name,
+            "ts_ms": i64::from(optime.timestamp.time) * 1000,
+            "snapshot": "false",
+            "db": namespace.database,
+            "rs": self.replica_set,
+            "collection": namespace.collection,
+            "ord": optime.timestamp.increment,
+            "lsid": session.lsid.map(|lsid| extjson_string(&lsid)),
+            "txnNumber": session.txn_number,
+        })
+    }
+}
+
+/// Returns an event with the key for a document `_id`.
+fn event(id: Option<&Bson>, value: Value) -> DebeziumEvent {
+    let key = match id {
+        Some(id) => json!({ "id": id.clone().into_relaxed_extjson().to_string() }),
+        None => Value::Null,
+    };
+
+    DebeziumEvent { key, value }
+}
+
+/// Returns a document as a string of relaxed Extended JSON.
+fn extjson_string(document: &Document) -> Value {
+    Value::String(
+        Bson::Document(document.clone())
+            .into_relaxed_extjson()
+            .to_string(),
+    )
+}
+
+/// Returns the `updateDescription` of an update, or `None` if it replaces the whole document or
+/// cannot be interpreted.
+fn update_description(update: &Document) -> Option<Value> {
+    let (set, unset, truncated) = match Change::with_truncations(update)? {
+        (Change::Modify { set, unset }, truncated) => (set, unset, truncated),
+        (Change::Replace(_), _) => return None,
+    };
+    let truncated: Vec<Value> = truncated
+        .into_iter()
+        .map(|(field, size)| json!({ "field": field, "size": size }))
+        .collect();
+
+    Some(json!({
+        "updatedFields": extjson_string(&set.into_iter().collect()),
+        "removedFields": unset,
+        "truncatedArrays": if truncated.is_empty() { Value::Null } else { truncated.into() },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use chrono::TimeZone;
+
+    fn update(update: Document) -> Operation {
+        Operation::Update {
+            timestamp: Utc.timestamp_opt(1479561033, 4).unwrap(),
+            namespace: "inventory.customers".into(),
+            query: doc! { "_id": 1 },
+            update,
+        }
+    }
+
+    #[test]
+    fn debezium_converts_set_and_unset_updates() {
+        let operation = update(doc! { "$set": { "name": "Bob" }, "$unset": { "age": true } });
+        let session = Session {
+            lsid: None,
+            txn_number: Some(7),
+        };
+        let events = Debezium::new("dbserver1", "rs0").convert(&operation, Some(&session));
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].key, json!({ "id": "1" }));
+
+        let value = &events[0].value;
+        assert_eq!(value["op"], "u");
+        assert_eq!(value["after"], Value::Null);
+        assert_eq!(
+            value["updateDescription"],
+            json!({
+                "updatedFields": "{\"name\":\"Bob\"}",
+                "removedFields": ["age"],
+                "truncatedArrays": null,
+            })
+        );
+        assert_eq!(value["source"]["db"], "inventory");
+        assert_eq!(value["source"]["ts_ms"], 1479561033000_i64);
+        assert_eq!(value["source"]["ord"], 4);
+        assert_eq!(value["source"]["txnNumber"], 7);
+    }
+
+    #[test]
+    fn debezium_converts_replacements() {
+        let events = Debezium::new("dbserver1", "rs0").convert(&update(doc! { "_id": 1 }), None);
+
+        assert_eq!(events[0].value["after"], "{\"_id\":1}");
+        assert_eq!(events[0].value["updateDescription"], Value::Null);
+    }
+
+    #[test]
+    fn update_description_flattens_v2_diffs() {
+        let description = update_description(&doc! {
+            "$v": 2,
+            "diff": {
+                "u": { "name": "Bob" },
+                "d": { "age": false },
+                "saddress": { "i": { "city": "Leeds" } },
+                "stags": { "a": true, "u1": "new", "l": 2 },
+            }
+        })
+        .unwrap();
+
+        assert_eq!(
+            description,
+            json!({
+                "updatedFields": "{\"name\":\"Bob\",\"address.city\":\"Leeds\",\"tags.1\":\"new\"}",
+                "removedFields": ["age"],
+                "truncatedArrays": [{ "field": "tags", "size": 2 }],
+            })
+        );
+    }
+}
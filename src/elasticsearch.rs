@@ -0,0 +1,471 @@
+//! The elasticsearch module formats operations as actions for the Elasticsearch `_bulk` API, for
+//! indexing collections into Elasticsearch.
+
+use crate::update::Change;
+use crate::{Error, Namespace, OpTime, Operation, Result};
+use bson::{Bson, Document};
+use mongodb::bson;
+use serde_json::{json, Map, Value};
+
+/// Sets and removes fields by path in the document source of an update, replacing any object set
+/// rather than merging it into the existing one as a partial document would.
+const UPDATE_SCRIPT: &str = "for (f in params.set) { def o = ctx._source; def p = f.path; \
+    for (int i = 0; i < p.size() - 1; i++) { \
+    if (!(o.get(p.get(i)) instanceof Map)) { o.put(p.get(i), new HashMap()); } \
+    o = o.get(p.get(i)); } \
+    o.put(p.get(p.size() - 1), f.value); } \
+    for (p in params.unset) { def o = ctx._source; \
+    for (int i = 0; i < p.size() - 1 && o instanceof Map; i++) { o = o.get(p.get(i)); } \
+    if (o instanceof Map) { o.remove(p.get(p.size() - 1)); } }";
+
+/// Formats operations as newline-delimited JSON actions for the Elasticsearch `_bulk` API.
+///
+/// Inserts and replacement updates become `index` actions with the whole document and deletes
+/// become `delete` actions. Other updates become partial `update` actions: those only setting
+/// fields to values other than objects carry a `doc` of the fields set, while those removing
+/// fields or setting objects run a painless script setting and removing fields by path, so that
+/// an object set replaces the existing one as in MongoDB rather than being merged into it. Only
+/// the latter need scripting enabled on the cluster. The operations within an
+/// `Operation::ApplyOps` are formatted in order; commands and no-ops produce no actions.
+///
+/// Updates are upserts (`doc_as_upsert` or `scripted_upsert`): an update to a document missing
+/// from the index creates it with just the fields set, rather than failing, so index collections
+/// in full before following the oplog.
+///
+/// The index is named after the namespace of the operation by a template in which `{db}` and
+/// `{collection}` are replaced (by default `{db}-{collection}`), lowercased and with characters
+/// not allowed in index names replaced by `_`. Document `_id`s become strings: ObjectIds as hex,
+/// strings as they are, numbers in decimal and anything else as relaxed Extended JSON. Within
+/// documents, ObjectIds are also written as hex and dates as RFC 3339.
+///
+/// `index` and `delete` actions are versioned externally by their `OpTime`, with the seconds of
+/// its timestamp shifted left by 31 bits plus its increment (capped at 2^31 - 1), so that versions
+/// increase with the oplog and are never negative. The version type is `external_gte` so that operations within the same transaction,
+/// which share an `OpTime`, are all applied. Elasticsearch does not support external versions for
+/// `update` actions, so these are unversioned.
+///
+/// # Example
+///
+/// ```
+/// use oplog::bson::{doc, oid::ObjectId, Timestamp};
+/// use oplog::{ElasticsearchBulk, Operation};
+///
+/// let id = ObjectId::parse_str("5e8f8f8f8f8f8f8f8f8f8f8f").unwrap();
+/// let operation = Operation::new(&doc! {
+///     "ts": Timestamp { time: 1479561394, increment: 1 },
+///     "op": "i",
+///     "ns": "shop.Orders",
+///     "o": { "_id": id, "status": "new" },
+/// }).unwrap();
+///
+/// let bulk = ElasticsearchBulk::new().format(&operation).unwrap();
+/// let mut lines = bulk.lines();
+///
+/// assert_eq!(
+///     lines.next(),
+///     Some(concat!(
+///         r#"{"index":{"_index":"shop-orders","_id":"5e8f8f8f8f8f8f8f8f8f8f8f","#,
+///         r#""version":3177333899827085313,"version_type":"external_gte"}}"#,
+///     ))
+/// );
+/// assert_eq!(lines.next(), Some(r#"{"status":"new"}"#));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ElasticsearchBulk {
+    index: String,
+    versioning: bool,
+}
+
+impl Default for ElasticsearchBulk {
+    fn default() -> ElasticsearchBulk {
+        ElasticsearchBulk {
+            index: "{db}-{collection}".to_string(),
+            versioning: true,
+        }
+    }
+}
+
+impl ElasticsearchBulk {
+    /// Creates a formatter with the index template `{db}-{collection}` and external versioning.
+    pub fn new() -> ElasticsearchBulk {
+        ElasticsearchBulk::default()
+    }
+
+    /// Sets the template of index names.
+    pub fn index(mut self, template: &str) -> Self {
+        self.index = template.to_string();
+        self
+    }
+
+    /// Sets whether `index` and `delete` actions are versioned externally.
+    pub fn versioning(mut self, versioning: bool) -> Self {
+        self.versioning = versioning;
+        self
+    }
+
+    /// Returns the bulk actions for an operation as newline-delimited JSON, ending in a newline.
+    ///
+    /// Returns `Error::Unsupported` for updates that cannot be expressed as a partial update,
+    /// such as those setting array elements or truncating arrays, and for inserts, updates and
+    /// deletes without an `_id`.
+    pub fn format(&self, operation: &Operation) -> Result<String> {
+        let mut lines = Vec::new();
+        self.push_actions(&mut lines, operation, operation.optime())?;
+
+        Ok(lines.iter().map(|line| format!("{}\n", line)).collect())
+    }
+
+    /// Returns the name of the index for a namespace.
+    pub fn index_name(&self, namespace: Namespace) -> String {
+        let name: String = self
+            .index
+            .replace("{db}", namespace.database)
+            .replace("{collection}", namespace.collection)
+            .to_lowercase()
+            .chars()
+            .map(|c| match c {
+                '\\' | '/' | '*' | '?' | '"' | '<' | '>' | '|' | ',' | '#' | ':' | ' ' => '_',
+                c => c,
+            })
+            .collect();
+
+        name.trim_start_matches(['-', '_', '+']).to_string()
+    }
+
+    fn push_actions(
+        &self,
+        lines: &mut Vec<Value>,
+        operation: &Operation,
+        optime: OpTime,
+    ) -> Result<()> {
+        let namespace = match *operation {
+            Operation::ApplyOps { ref operations, .. } => {
+                for operation in operations {
+                    self.push_actions(lines, operation, optime)?;
+                }
+
+                return Ok(());
+            }
+            Operation::Insert { ref namespace, .. }
+            | Operation::Update { ref namespace, .. }
+            | Operation::Delete { ref namespace, .. } => Namespace::new(namespace),
+            _ => return Ok(()),
+        };
+        let id = match operation.document_id() {
+            Some(id) => document_id(id),
+            None => {
+                return Err(Error::Unsupported(format!(
+                    "{} of {} without an _id cannot be indexed in Elasticsearch",
+                    operation.kind(),
+                    namespace
+                )))
+            }
+        };
+        let metadata = json!({
+            "_index": self.index_name(namespace),
+            "_id": id,
+        });
+
+        match *operation {
+            Operation::Insert { ref document, .. } => {
+                lines.push(json!({ "index": self.versioned(metadata, optime) }));
+                lines.push(source(document));
+            }
+            Operation::Update { ref update, .. } => match Change::new(update) {
+                Some(Change::Replace(ref document)) => {
+                    lines.push(json!({ "index": self.versioned(metadata, optime) }));
+                    lines.push(source(document));
+                }
+                Some(Change::Modify { ref set, ref unset })
+                    if set.is_empty() && unset.is_empty() => {}
+                Some(Change::Modify { set, unset })
+                    if unset.is_empty()
+                        && !set
+                            .iter()
+                            .any(|(_, value)| matches!(value, Bson::Document(_))) =>
+                {
+                    let mut doc = Map::new();
+                    for (path, value) in &set {
+                        insert_path(&mut doc, &components(path, namespace)?, to_json(value));
+                    }
+
+                    lines.push(json!({ "update": metadata }));
+                    lines.push(json!({ "doc": doc, "doc_as_upsert": true }));
+                }
+                Some(Change::Modify { set, unset }) => {
+                    let set = set
+                        .iter()
+                        .map(|(path, value)| {
+                            Ok(json!({
+                                "path": components(path, namespace)?,
+                                "value": to_json(value),
+                            }))
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    let unset = unset
+                        .iter()
+                        .map(|path| components(path, namespace))
+                        .collect::<Result<Vec<_>>>()?;
+
+                    lines.push(json!({ "update": metadata }));
+                    lines.push(json!({
+                        "script": {
+                            "source": UPDATE_SCRIPT,
+                            "lang": "painless",
+                            "params": { "set": set, "unset": unset },
+                        },
+                        "scripted_upsert": true,
+                        "upsert": {},
+                    }));
+                }
+                None => return Err(unsupported(namespace)),
+            },
+            _ => lines.push(json!({ "delete": self.versioned(metadata, optime) })),
+        }
+
+        Ok(())
+    }
+
+    fn versioned(&self, mut metadata: Value, optime: OpTime) -> Value {
+        if self.versioning {
+            metadata["version"] = version(optime).into();
+            metadata["version_type"] = "external_gte".into();
+        }
+
+        metadata
+    }
+}
+
+/// Returns the external version of an operation.
+fn version(optime: OpTime) -> i64 {
+    let increment = optime.timestamp.increment.min(i32::MAX as u32);
+
+    (i64::from(optime.timestamp.time) << 31) | i64::from(increment)
+}
+
+/// Returns a document `_id` as an Elasticsearch `_id`.
+fn document_id(id: &Bson) -> String {
+    match *id {
+        Bson::ObjectId(ref id) => id.to_hex(),
+        Bson::String(ref s) => s.clone(),
+        Bson::Int32(n) => n.to_string(),
+        Bson::Int64(n) => n.to_string(),
+        _ => id.clone().into_relaxed_extjson().to_string(),
+    }
+}
+
+/// Returns a document without its `_id`, which Elasticsearch does not allow in the source.
+fn source(document: &Document) -> Value {
+    let mut source = to_json(&Bson::Document(document.clone()));
+    if let Value::Object(ref mut map) = source {
+        map.remove("_id");
+    }
+
+    source
+}
+
+/// Splits a dotted path into its components, which must not index into arrays.
+fn components<'a>(path: &'a str, namespace: Namespace) -> Result<Vec<&'a str>> {
+    let components: Vec<&str> = path.split('.').collect();
+
+    if components.iter().any(|c| c.parse::<usize>().is_ok()) {
+        return Err(unsupported(namespace));
+    }
+
+    Ok(components)
+}
+
+/// Inserts a value into a JSON object by path, creating objects along the way.
+fn insert_path(object: &mut Map<String, Value>, path: &[&str], value: Value) {
+    let (last, parents) = path
+        .split_last()
+        .expect("paths have at least one component");
+    let mut object = object;
+
+    for component in parents {
+        let child = object
+            .entry(component.to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !child.is_object() {
+            *child = Value::Object(Map::new());
+        }
+
+        object = child.as_object_mut().expect("child is an object");
+    }
+
+    object.insert(last.to_string(), value);
+}
+
+/// Converts BSON to JSON, with ObjectIds as hex and dates as RFC 3339.
+fn to_json(bson: &Bson) -> Value {
+    match *bson {
+        Bson::Document(ref document) => Value::Object(
+            document
+                .iter()
+                .map(|(key, value)| (key.clone(), to_json(value)))
+                .collect(),
+        ),
+        Bson::Array(ref array) => Value::Array(array.iter().map(to_json).collect()),
+        Bson::ObjectId(ref id) => Value::String(id.to_hex()),
+        Bson::DateTime(ref dt) => match dt.try_to_rfc3339_string() {
+            Ok(s) => Value::String(s),
+            Err(_) => bson.clone().into_relaxed_extjson(),
+        },
+        _ => bson.clone().into_relaxed_extjson(),
+    }
+}
+
+fn unsupported(namespace: Namespace) -> Error {
+    Error::Unsupported(format!(
+        "update of {} cannot be expressed as an Elasticsearch partial update",
+        namespace
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use chrono::{TimeZone, Utc};
+
+    fn update(update: Document) -> Operation {
+        Operation::Update {
+            timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+            namespace: "shop.orders".into(),
+            query: doc! { "_id": 1 },
+            update,
+        }
+    }
+
+    fn lines(bulk: &str) -> Vec<Value> {
+        bulk.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn format_sets_fields_with_a_partial_document() {
+        let bulk = ElasticsearchBulk::new()
+            .format(&update(
+                doc! { "$set": { "status": "paid", "total.amount": 5 } },
+            ))
+            .unwrap();
+
+        assert_eq!(
+            lines(&bulk),
+            vec![
+                json!({ "update": { "_index": "shop-orders", "_id": "1" } }),
+                json!({
+                    "doc": { "status": "paid", "total": { "amount": 5 } },
+                    "doc_as_upsert": true,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn format_sets_and_unsets_fields_with_a_scripted_upsert() {
+        let bulk = ElasticsearchBulk::new()
+            .format(&update(doc! {
+                "$set": { "status": "paid", "total.amount": 5 },
+                "$unset": { "notes": true },
+            }))
+            .unwrap();
+        let lines = lines(&bulk);
+
+        assert_eq!(
+            lines[0],
+            json!({ "update": { "_index": "shop-orders", "_id": "1" } })
+        );
+        assert_eq!(
+            lines[1]["script"]["params"],
+            json!({
+                "set": [
+                    { "path": ["status"], "value": "paid" },
+                    { "path": ["total", "amount"], "value": 5 },
+                ],
+                "unset": [["notes"]],
+            })
+        );
+        assert_eq!(lines[1]["scripted_upsert"], true);
+        assert_eq!(lines[1]["upsert"], json!({}));
+    }
+
+    #[test]
+    fn format_sets_objects_whole() {
+        let bulk = ElasticsearchBulk::new()
+            .format(&update(doc! { "$set": { "total": { "amount": 5 } } }))
+            .unwrap();
+
+        assert_eq!(
+            lines(&bulk)[1]["script"]["params"]["set"],
+            json!([{ "path": ["total"], "value": { "amount": 5 } }])
+        );
+    }
+
+    #[test]
+    fn format_deletes_with_external_versions() {
+        let operation = Operation::Delete {
+            timestamp: Utc.timestamp_opt(1, 2).unwrap(),
+            namespace: "shop.orders".into(),
+            query: doc! { "_id": "a" },
+        };
+        let bulk = ElasticsearchBulk::new().format(&operation).unwrap();
+
+        assert_eq!(
+            lines(&bulk),
+            vec![json!({ "delete": {
+                "_index": "shop-orders",
+                "_id": "a",
+                "version": (1_i64 << 31) | 2,
+                "version_type": "external_gte",
+            } })]
+        );
+    }
+
+    #[test]
+    fn format_keeps_versions_positive_after_2038() {
+        let operation = Operation::Delete {
+            timestamp: Utc.timestamp_opt(1 << 31, 3).unwrap(),
+            namespace: "shop.orders".into(),
+            query: doc! { "_id": "a" },
+        };
+        let bulk = ElasticsearchBulk::new().format(&operation).unwrap();
+
+        assert_eq!(lines(&bulk)[0]["delete"]["version"], (1_i64 << 62) | 3);
+    }
+
+    #[test]
+    fn format_rejects_operations_without_an_id() {
+        let operation = Operation::Delete {
+            timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+            namespace: "shop.orders".into(),
+            query: doc! { "status": "cancelled" },
+        };
+
+        assert!(matches!(
+            ElasticsearchBulk::new().format(&operation),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn format_rejects_array_element_updates() {
+        let operation = update(doc! { "$set": { "tags.0": "new" } });
+
+        assert!(matches!(
+            ElasticsearchBulk::new().format(&operation),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn index_name_is_valid() {
+        let bulk = ElasticsearchBulk::new().index("_{db}:{collection}");
+
+        assert_eq!(
+            bulk.index_name(Namespace::new("Shop.My Orders")),
+            "shop_my_orders"
+        );
+    }
+}
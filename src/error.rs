@@ -1,31 +1,102 @@
+use crate::OpTime;
+use bson::spec::ElementType;
+use bson::Document;
 use mongodb::bson;
+use mongodb::error::ErrorKind;
 use std::fmt;
 
 /// A type alias for convenience so we can fix the error to our own `Error` type.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Server error codes after which reopening the cursor can be expected to succeed, as per the
+/// MongoDB retryable reads specification (plus `CursorNotFound`, as a killed tailable cursor can
+/// simply be reopened).
+const RETRYABLE_CODES: [i32; 14] = [
+    6, 7, 43, 89, 91, 134, 189, 262, 9001, 10107, 11600, 11602, 13435, 13436,
+];
+
 /// Error enumerates the list of possible error conditions when tailing an oplog.
 #[derive(Debug)]
 pub enum Error {
     /// A database connectivity error raised by the MongoDB driver.
     Database(mongodb::error::Error),
-    /// An error when converting a BSON document to an `Operation` and it has a missing field or
-    /// unexpected type.
-    MissingField(bson::document::ValueAccessError),
-    /// An error when converting a BSON document to an `Operation` and it has an unsupported
-    /// operation type.
-    UnknownOperation(String),
-    /// An error when converting an applyOps command with invalid documents.
-    InvalidOperation,
+    /// An error when converting a BSON document to an `Operation`.
+    Parse(ParseError),
+    /// The requested start position has already been truncated from the capped oplog collection so
+    /// operations between it and the oldest remaining entry have been lost.
+    PositionLost {
+        /// The timestamp we were asked to start from.
+        requested: bson::Timestamp,
+        /// The timestamp of the oldest entry remaining in the oplog.
+        oldest: bson::Timestamp,
+    },
+    /// An error delivering records to a `Sink`.
+    Sink(Box<dyn std::error::Error + Send + Sync>),
+    /// An error loading or saving a `Checkpoint`.
+    Checkpoint(std::io::Error),
+    /// Previously read operations no longer exist in the oplog, having been rolled back following
+    /// a replica set election.
+    RollbackDetected {
+        /// The operations that disappeared, oldest first.
+        operations: Vec<OpTime>,
+    },
+    /// A `Subscriber` fell so far behind a `SharedOplog` that it was disconnected.
+    Lagged {
+        /// The number of operations the subscriber could buffer.
+        capacity: usize,
+    },
+    /// An operation that cannot be translated into the requested output format.
+    Unsupported(String),
+    /// An error converting operations to Arrow or writing them to Parquet, with the `parquet`
+    /// feature.
+    Export(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// Returns true if the error is transient and tailing can be expected to carry on after
+    /// calling `Oplog::resume` (e.g. a network error or an election in progress).
+    ///
+    /// All other errors are fatal: retrying will fail in the same way.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            Error::Database(ref err) => match *err.kind {
+                ErrorKind::Io(_)
+                | ErrorKind::ConnectionPoolCleared { .. }
+                | ErrorKind::ServerSelection { .. } => true,
+                ErrorKind::Command(ref cmd) => RETRYABLE_CODES.contains(&cmd.code),
+                _ => err.contains_label("ResumableChangeStreamError"),
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns true if an `Oplog` carries on reading after yielding the error: rolled back
+    /// operations and entries that cannot be parsed are reported without closing the cursor.
+    pub(crate) fn carries_on(&self) -> bool {
+        matches!(*self, Error::RollbackDetected { .. } | Error::Parse(_))
+    }
+
+    /// Attaches the raw entry to a parse error, leaving any other error as is.
+    pub(crate) fn with_raw(self, raw: Document) -> Error {
+        match self {
+            Error::Parse(err) => Error::Parse(err.with_raw(raw)),
+            err => err,
+        }
+    }
 }
 
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::Database(e) => Some(e),
-            Error::MissingField(e) => Some(e),
-            Error::UnknownOperation(_) => None,
-            Error::InvalidOperation => None,
+            Error::Parse(e) => Some(e),
+            Error::Sink(e) => Some(e.as_ref()),
+            Error::Checkpoint(e) => Some(e),
+            Error::PositionLost { .. } => None,
+            Error::RollbackDetected { .. } => None,
+            Error::Lagged { .. } => None,
+            Error::Unsupported(_) => None,
+            Error::Export(e) => Some(e.as_ref()),
         }
     }
 }
@@ -34,16 +105,33 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Database(ref err) => err.fmt(f),
-            Error::MissingField(ref err) => err.fmt(f),
-            Error::UnknownOperation(ref op) => write!(f, "Unknown operation type found: {}", op),
-            Error::InvalidOperation => write!(f, "Invalid operation"),
+            Error::Parse(ref err) => err.fmt(f),
+            Error::Sink(ref err) => write!(f, "Sink error: {}", err),
+            Error::Checkpoint(ref err) => write!(f, "Checkpoint error: {}", err),
+            Error::PositionLost { requested, oldest } => write!(
+                f,
+                "Oplog position {} lost, oldest entry is now {}",
+                requested, oldest
+            ),
+            Error::RollbackDetected { ref operations } => write!(
+                f,
+                "Rollback detected, {} operations no longer exist",
+                operations.len()
+            ),
+            Error::Lagged { capacity } => write!(
+                f,
+                "Subscriber disconnected after falling more than {} operations behind",
+                capacity
+            ),
+            Error::Unsupported(ref message) => write!(f, "Unsupported operation: {}", message),
+            Error::Export(ref err) => write!(f, "Export error: {}", err),
         }
     }
 }
 
-impl From<bson::document::ValueAccessError> for Error {
-    fn from(original: bson::document::ValueAccessError) -> Error {
-        Error::MissingField(original)
+impl From<ParseError> for Error {
+    fn from(original: ParseError) -> Error {
+        Error::Parse(original)
     }
 }
 
@@ -52,3 +140,111 @@ impl From<mongodb::error::Error> for Error {
         Error::Database(original)
     }
 }
+
+/// The reason an oplog entry could not be converted into an `Operation`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// A required field is missing.
+    MissingField {
+        /// The BSON type the field should have had.
+        expected: ElementType,
+    },
+    /// A field has an unexpected BSON type.
+    InvalidType {
+        /// The BSON type the field should have had.
+        expected: ElementType,
+        /// The BSON type the field actually had.
+        found: ElementType,
+    },
+    /// The `op` field holds an unsupported operation type.
+    UnknownOperation(String),
+    /// The raw BSON of the entry could not be read, e.g. as it was truncated.
+    Malformed(String),
+}
+
+/// An error converting an oplog entry into an `Operation`, with enough context to find the entry
+/// and field at fault.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// What went wrong.
+    pub kind: ParseErrorKind,
+    /// The path to the field at fault within the entry, e.g. `o.applyOps[3].ns`.
+    pub path: String,
+    /// The operation type (`op`) of the entry, if known.
+    pub op: Option<String>,
+    /// The timestamp (`ts`) of the entry, if known.
+    pub timestamp: Option<bson::Timestamp>,
+    /// The raw entry, if it was available when the error occurred.
+    ///
+    /// This is populated for errors yielded by an `Oplog` but not by `Operation::new` to avoid
+    /// cloning the document.
+    pub raw: Option<Box<Document>>,
+}
+
+impl ParseError {
+    /// Attaches the raw entry to the error.
+    pub(crate) fn with_raw(mut self, raw: Document) -> ParseError {
+        self.raw = Some(Box::new(raw));
+        self
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::MissingField { expected } => {
+                write!(f, "Missing field {} (expected {:?})", self.path, expected)?
+            }
+            ParseErrorKind::InvalidType { expected, found } => write!(
+                f,
+                "Invalid field {} (expected {:?}, found {:?})",
+                self.path, expected, found
+            )?,
+            ParseErrorKind::UnknownOperation(ref op) => {
+                write!(f, "Unknown operation type found at {}: {}", self.path, op)?
+            }
+            ParseErrorKind::Malformed(ref message) => {
+                write!(f, "Malformed BSON found at {}: {}", self.path, message)?
+            }
+        }
+
+        if let Some(ref op) = self.op {
+            write!(f, " in {:?} entry", op)?;
+        }
+
+        if let Some(ts) = self.timestamp {
+            write!(f, " at {}", ts)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_classifies_network_errors_as_retryable() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let err = Error::Database(io.into());
+
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn error_classifies_lost_positions_as_fatal() {
+        let ts = bson::Timestamp {
+            time: 1479561394,
+            increment: 0,
+        };
+        let err = Error::PositionLost {
+            requested: ts,
+            oldest: ts,
+        };
+
+        assert!(!err.is_retryable());
+    }
+}
@@ -0,0 +1,749 @@
+//! The export module converts operations into Apache Arrow record batches and writes them to
+//! Parquet files, for loading oplog history into a data lake.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::update::Change;
+use crate::{Error, OpTime, Operation, RawOperation, Result};
+use arrow::array::{
+    ArrayRef, BinaryBuilder, Int64Builder, ListBuilder, StringBuilder, TimestampMillisecondBuilder,
+    UInt32Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use bson::raw::{RawDocument, RawDocumentBuf};
+use bson::{Bson, Document};
+use futures::{Stream, StreamExt};
+use mongodb::bson;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+/// The name of the namespace partition for operations without one, such as no-ops.
+const NO_NAMESPACE: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// How documents are stored in the `document` column.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DocumentEncoding {
+    /// As relaxed Extended JSON in a `Utf8` column.
+    #[default]
+    Json,
+    /// As BSON in a `Binary` column.
+    Bson,
+}
+
+/// Converts operations into Arrow record batches with a fixed envelope schema.
+///
+/// Each operation becomes one row, except for an `Operation::ApplyOps` which becomes one row per
+/// operation it applies, all with the optime of the `applyOps` entry. The columns are:
+///
+/// * `ts_time` and `ts_increment` (`UInt32`): the oplog timestamp
+/// * `term` (`Int64`, nullable): the replica set term of the entry
+/// * `wall` (`Timestamp(Millisecond, UTC)`, nullable): the wall clock time of the entry
+/// * `op` (`Utf8`): the kind of operation (see `Operation::kind`)
+/// * `namespace` (`Utf8`, nullable)
+/// * `id` (`Utf8`, nullable): the document `_id` as relaxed Extended JSON
+/// * `document` (`Utf8` or `Binary` as per the `DocumentEncoding`): the `o` field of the oplog
+///   entry, i.e. the document inserted, the update, the delete query or the command
+/// * `updated_fields` and `removed_fields` (`List(Utf8)`, nullable): the dotted paths set and
+///   removed by updates other than replacements
+///
+/// The `term` and `wall` columns are only filled in when converting raw entries, as an
+/// `Operation` keeps neither.
+#[derive(Clone, Debug, Default)]
+pub struct ArrowConverter {
+    encoding: DocumentEncoding,
+}
+
+impl ArrowConverter {
+    /// Creates a converter storing documents as JSON.
+    pub fn new() -> ArrowConverter {
+        ArrowConverter::default()
+    }
+
+    /// Sets how documents are stored.
+    pub fn document_encoding(mut self, encoding: DocumentEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Returns the schema of the record batches.
+    pub fn schema(&self) -> SchemaRef {
+        let document = match self.encoding {
+            DocumentEncoding::Json => DataType::Utf8,
+            DocumentEncoding::Bson => DataType::Binary,
+        };
+        let list = DataType::List(Arc::new(Field::new("item", DataType::Utf8, true)));
+
+        Arc::new(Schema::new(vec![
+            Field::new("ts_time", DataType::UInt32, false),
+            Field::new("ts_increment", DataType::UInt32, false),
+            Field::new("term", DataType::Int64, true),
+            Field::new(
+                "wall",
+                DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                true,
+            ),
+            Field::new("op", DataType::Utf8, false),
+            Field::new("namespace", DataType::Utf8, true),
+            Field::new("id", DataType::Utf8, true),
+            Field::new("document", document, false),
+            Field::new("updated_fields", list.clone(), true),
+            Field::new("removed_fields", list, true),
+        ]))
+    }
+
+    /// Converts operations into a record batch.
+    pub fn convert(&self, operations: &[Operation]) -> Result<RecordBatch> {
+        let mut rows = Vec::new();
+        for operation in operations {
+            push_rows(&mut rows, operation.optime(), operation, None);
+        }
+
+        self.record_batch(&rows)
+    }
+
+    /// Converts raw oplog entries, e.g. as read by a `RawOplog`, into a record batch, including
+    /// their terms and wall clock times.
+    pub fn convert_raw(&self, entries: &[RawDocumentBuf]) -> Result<RecordBatch> {
+        let mut rows = Vec::new();
+        for entry in entries {
+            push_raw_rows(&mut rows, entry)?;
+        }
+
+        self.record_batch(&rows)
+    }
+
+    fn record_batch(&self, rows: &[Row]) -> Result<RecordBatch> {
+        let mut time = UInt32Builder::with_capacity(rows.len());
+        let mut increment = UInt32Builder::with_capacity(rows.len());
+        let mut term = Int64Builder::with_capacity(rows.len());
+        let mut wall = TimestampMillisecondBuilder::with_capacity(rows.len()).with_timezone("UTC");
+        let mut op = StringBuilder::new();
+        let mut namespace = StringBuilder::new();
+        let mut id = StringBuilder::new();
+        let mut json = StringBuilder::new();
+        let mut binary = BinaryBuilder::new();
+        let mut updated = ListBuilder::new(StringBuilder::new());
+        let mut removed = ListBuilder::new(StringBuilder::new());
+
+        for row in rows {
+            time.append_value(row.time);
+            increment.append_value(row.increment);
+            term.append_option(row.term);
+            wall.append_option(row.wall);
+            op.append_value(row.op);
+            namespace.append_option(row.namespace.as_deref());
+            id.append_option(row.id.as_deref());
+
+            match self.encoding {
+                DocumentEncoding::Json => json.append_value(
+                    Bson::Document(row.document.clone())
+                        .into_relaxed_extjson()
+                        .to_string(),
+                ),
+                DocumentEncoding::Bson => {
+                    let mut bytes = Vec::new();
+                    row.document.to_writer(&mut bytes).map_err(export_error)?;
+                    binary.append_value(bytes);
+                }
+            }
+
+            match row.fields {
+                Some((ref set, ref unset)) => {
+                    updated.append_value(set.iter().map(Some));
+                    removed.append_value(unset.iter().map(Some));
+                }
+                None => {
+                    updated.append_null();
+                    removed.append_null();
+                }
+            }
+        }
+
+        let document: ArrayRef = match self.encoding {
+            DocumentEncoding::Json => Arc::new(json.finish()),
+            DocumentEncoding::Bson => Arc::new(binary.finish()),
+        };
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(time.finish()),
+            Arc::new(increment.finish()),
+            Arc::new(term.finish()),
+            Arc::new(wall.finish()),
+            Arc::new(op.finish()),
+            Arc::new(namespace.finish()),
+            Arc::new(id.finish()),
+            document,
+            Arc::new(updated.finish()),
+            Arc::new(removed.finish()),
+        ];
+
+        RecordBatch::try_new(self.schema(), columns).map_err(export_error)
+    }
+}
+
+/// A row of the envelope schema.
+struct Row {
+    time: u32,
+    increment: u32,
+    term: Option<i64>,
+    wall: Option<i64>,
+    op: &'static str,
+    namespace: Option<String>,
+    id: Option<String>,
+    document: Document,
+    fields: Option<(Vec<String>, Vec<String>)>,
+}
+
+impl Row {
+    /// Returns the date and namespace partition of the row, dated by its oplog timestamp.
+    fn partition(&self) -> (String, String) {
+        let date = chrono::DateTime::from_timestamp(i64::from(self.time), 0)
+            .map(|time| time.format("%Y-%m-%d").to_string())
+            .unwrap_or_default();
+        let namespace = self.namespace.as_deref().unwrap_or(NO_NAMESPACE);
+
+        (date, namespace.to_string())
+    }
+}
+
+fn push_rows(rows: &mut Vec<Row>, optime: OpTime, operation: &Operation, wall: Option<i64>) {
+    if let Operation::ApplyOps { ref operations, .. } = *operation {
+        for operation in operations {
+            push_rows(rows, optime, operation, wall);
+        }
+
+        return;
+    }
+
+    let mut document = operation.to_document();
+    let document = match document.remove("o") {
+        Some(Bson::Document(o)) => o,
+        _ => document,
+    };
+    let fields = match *operation {
+        Operation::Update { ref update, .. } => match Change::new(update) {
+            Some(Change::Modify { set, unset }) => {
+                Some((set.into_iter().map(|(path, _)| path).collect(), unset))
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+
+    rows.push(Row {
+        time: optime.timestamp.time,
+        increment: optime.timestamp.increment,
+        term: optime.term,
+        wall,
+        op: operation.kind(),
+        namespace: operation.namespace().map(String::from),
+        id: operation
+            .document_id()
+            .map(|id| id.clone().into_relaxed_extjson().to_string()),
+        document,
+        fields,
+    });
+}
+
+fn push_raw_rows(rows: &mut Vec<Row>, entry: &RawDocument) -> Result<()> {
+    let operation = RawOperation::new(entry)?;
+    let wall = entry
+        .get_datetime("wall")
+        .ok()
+        .map(|wall| wall.timestamp_millis());
+    push_rows(rows, operation.optime(), &operation.to_operation()?, wall);
+
+    Ok(())
+}
+
+/// Writes operations to Parquet files partitioned by date and namespace.
+///
+/// Files are laid out as `<directory>/date=<YYYY-MM-DD>/namespace=<namespace>/<file>.parquet`,
+/// as understood by Hive-style partition discovery, with operations dated by their oplog
+/// timestamp and those without a namespace under `namespace=__HIVE_DEFAULT_PARTITION__`. Each
+/// file is named after the timestamp of its first operation, with a numeric suffix if that file
+/// already exists, so that exporting later or overlapping ranges adds files rather than replacing
+/// them. Files are compressed with Snappy.
+///
+/// As the oplog is in timestamp order, a date's files are closed as soon as an operation from a
+/// later date is written, so only the current date's files are open at once. Operations from an
+/// earlier date than that go to new files.
+///
+/// Use `export_raw` with a `RawOplog` to fill in the `term` and `wall` columns (see `ArrowConverter`).
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use futures::StreamExt;
+/// use mongodb::bson::Timestamp;
+/// use mongodb::Client;
+/// use oplog::{Oplog, ParquetExporter};
+///
+/// # async fn run() -> Result<(), oplog::Error> {
+/// let client = Client::with_uri_str("mongodb://localhost").await?;
+/// let until = Timestamp { time: 1706788800, increment: 0 };
+/// let oplog = Oplog::builder()
+///     .start_at(Timestamp { time: 1706702400, increment: 0 })
+///     .build(&client)
+///     .await?
+///     .take_while(|res| {
+///         let before = res.as_ref().map_or(true, |op| op.optime().timestamp < until);
+///         futures::future::ready(before)
+///     });
+///
+/// let files = ParquetExporter::new("oplog").export(oplog).await?;
+/// println!("Wrote {} files", files.len());
+/// # Ok(())
+/// # }
+/// ```
+pub struct ParquetExporter {
+    directory: PathBuf,
+    converter: ArrowConverter,
+    batch_size: usize,
+    partitions: HashMap<(String, String), Partition>,
+    /// The latest date written.
+    date: Option<String>,
+    /// The paths of the files closed so far.
+    written: Vec<PathBuf>,
+}
+
+struct Partition {
+    path: PathBuf,
+    writer: ArrowWriter<File>,
+    rows: Vec<Row>,
+}
+
+impl ParquetExporter {
+    /// Creates an exporter writing into the given directory in record batches of up to 8192
+    /// rows.
+    pub fn new<P: Into<PathBuf>>(directory: P) -> ParquetExporter {
+        ParquetExporter {
+            directory: directory.into(),
+            converter: ArrowConverter::new(),
+            batch_size: 8192,
+            partitions: HashMap::new(),
+            date: None,
+            written: Vec::new(),
+        }
+    }
+
+    /// Sets how documents are stored.
+    pub fn document_encoding(mut self, encoding: DocumentEncoding) -> Self {
+        self.converter = self.converter.document_encoding(encoding);
+        self
+    }
+
+    /// Sets the maximum number of rows buffered for each partition before they are written.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Writes every operation in the stream, returning the paths of the files written once it
+    /// ends.
+    pub async fn export<St>(mut self, stream: St) -> Result<Vec<PathBuf>>
+    where
+        St: Stream<Item = Result<Operation>>,
+    {
+        futures::pin_mut!(stream);
+
+        while let Some(res) = stream.next().await {
+            self.write(&res?)?;
+        }
+
+        self.finish()
+    }
+
+    /// Writes every raw entry in the stream, e.g. from a `RawOplog`, returning the paths of the
+    /// files written once it ends.
+    pub async fn export_raw<St>(mut self, stream: St) -> Result<Vec<PathBuf>>
+    where
+        St: Stream<Item = Result<RawDocumentBuf>>,
+    {
+        futures::pin_mut!(stream);
+
+        while let Some(res) = stream.next().await {
+            self.write_raw(&res?)?;
+        }
+
+        self.finish()
+    }
+
+    /// Writes an operation.
+    pub fn write(&mut self, operation: &Operation) -> Result<()> {
+        let mut rows = Vec::new();
+        push_rows(&mut rows, operation.optime(), operation, None);
+
+        self.write_rows(rows)
+    }
+
+    /// Writes a raw entry, including its wall clock time.
+    pub fn write_raw(&mut self, entry: &RawDocument) -> Result<()> {
+        let mut rows = Vec::new();
+        push_raw_rows(&mut rows, entry)?;
+
+        self.write_rows(rows)
+    }
+
+    fn write_rows(&mut self, rows: Vec<Row>) -> Result<()> {
+        for row in rows {
+            let key = row.partition();
+
+            if self.date.as_ref().is_none_or(|date| key.0 > *date) {
+                self.close_before(&key.0)?;
+                self.date = Some(key.0.clone());
+            }
+
+            if !self.partitions.contains_key(&key) {
+                let partition = self.open(&key, &row)?;
+                self.partitions.insert(key.clone(), partition);
+            }
+
+            let partition = self.partitions.get_mut(&key).expect("open partition");
+            partition.rows.push(row);
+
+            if partition.rows.len() >= self.batch_size {
+                let batch = self.converter.record_batch(&partition.rows)?;
+                partition.writer.write(&batch).map_err(export_error)?;
+                partition.rows.clear();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes any buffered rows and closes every file, returning the paths of all the files
+    /// written.
+    pub fn finish(mut self) -> Result<Vec<PathBuf>> {
+        for (_, partition) in std::mem::take(&mut self.partitions) {
+            self.close(partition)?;
+        }
+
+        self.written.sort();
+
+        Ok(self.written)
+    }
+
+    /// Closes the files of dates before the given one.
+    fn close_before(&mut self, date: &str) -> Result<()> {
+        let keys: Vec<_> = self
+            .partitions
+            .keys()
+            .filter(|(partition_date, _)| partition_date.as_str() < date)
+            .cloned()
+            .collect();
+
+        for key in keys {
+            let partition = self.partitions.remove(&key).expect("open partition");
+            self.close(partition)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes any buffered rows of a partition and closes its file.
+    fn close(&mut self, mut partition: Partition) -> Result<()> {
+        if !partition.rows.is_empty() {
+            let batch = self.converter.record_batch(&partition.rows)?;
+            partition.writer.write(&batch).map_err(export_error)?;
+        }
+
+        partition.writer.close().map_err(export_error)?;
+        self.written.push(partition.path);
+
+        Ok(())
+    }
+
+    fn open(&self, (date, namespace): &(String, String), first: &Row) -> Result<Partition> {
+        let directory = self
+            .directory
+            .join(format!("date={}", date))
+            .join(format!("namespace={}", namespace));
+        fs::create_dir_all(&directory).map_err(export_error)?;
+
+        let mut path = directory.join(format!("{}-{}.parquet", first.time, first.increment));
+        let mut suffix = 0;
+        let file = loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break file,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    suffix += 1;
+                    path = directory.join(format!(
+                        "{}-{}-{}.parquet",
+                        first.time, first.increment, suffix
+                    ));
+                }
+                Err(e) => return Err(export_error(e)),
+            }
+        };
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer = ArrowWriter::try_new(file, self.converter.schema(), Some(properties))
+            .map_err(export_error)?;
+
+        Ok(Partition {
+            path,
+            writer,
+            rows: Vec::new(),
+        })
+    }
+}
+
+fn export_error<E>(err: E) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    Error::Export(Box::new(err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{
+        Array, BinaryArray, Int64Array, ListArray, StringArray, TimestampMillisecondArray,
+        UInt32Array,
+    };
+    use bson::{doc, rawdoc, Timestamp};
+    use chrono::{TimeZone, Utc};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn insert(time: i64, namespace: &str, id: i32) -> Operation {
+        Operation::Insert {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: namespace.into(),
+            document: doc! { "_id": id, "name": "Alice" },
+        }
+    }
+
+    fn strings(batch: &RecordBatch, column: &str) -> Vec<Option<String>> {
+        let array = batch
+            .column_by_name(column)
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+
+        array.iter().map(|value| value.map(String::from)).collect()
+    }
+
+    fn list(batch: &RecordBatch, column: &str, row: usize) -> Option<Vec<String>> {
+        let array = batch
+            .column_by_name(column)
+            .unwrap()
+            .as_any()
+            .downcast_ref::<ListArray>()
+            .unwrap();
+        if array.is_null(row) {
+            return None;
+        }
+        let values = array.value(row);
+        let values = values.as_any().downcast_ref::<StringArray>().unwrap();
+
+        Some(values.iter().map(|value| value.unwrap().into()).collect())
+    }
+
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("oplog-export-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        directory
+    }
+
+    #[test]
+    fn convert_writes_envelope_columns() {
+        let update = Operation::Update {
+            timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+            namespace: "foo.bar".into(),
+            query: doc! { "_id": 1 },
+            update: doc! { "$set": { "name": "Bob" }, "$unset": { "age": "" } },
+        };
+        let batch = ArrowConverter::new()
+            .convert(&[insert(1479561394, "foo.bar", 1), update])
+            .unwrap();
+
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.schema(), ArrowConverter::new().schema());
+        assert_eq!(
+            strings(&batch, "op"),
+            vec![Some("insert".into()), Some("update".into())]
+        );
+        assert_eq!(
+            strings(&batch, "id"),
+            vec![Some("1".into()), Some("1".into())]
+        );
+        assert_eq!(
+            strings(&batch, "document"),
+            vec![
+                Some(r#"{"_id":1,"name":"Alice"}"#.into()),
+                Some(r#"{"$set":{"name":"Bob"},"$unset":{"age":""}}"#.into()),
+            ]
+        );
+        assert_eq!(list(&batch, "updated_fields", 0), None);
+        assert_eq!(list(&batch, "updated_fields", 1), Some(vec!["name".into()]));
+        assert_eq!(list(&batch, "removed_fields", 1), Some(vec!["age".into()]));
+    }
+
+    #[test]
+    fn convert_flattens_apply_ops() {
+        let apply_ops = Operation::ApplyOps {
+            timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+            namespace: "admin.$cmd".into(),
+            operations: vec![insert(1479561394, "foo.bar", 1), insert(1, "foo.baz", 2)],
+            partial: false,
+            prepare: false,
+            previous: None,
+        };
+        let batch = ArrowConverter::new().convert(&[apply_ops]).unwrap();
+        let times = batch
+            .column_by_name("ts_time")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt32Array>()
+            .unwrap();
+
+        assert_eq!(times.values().to_vec(), vec![1479561394, 1479561394]);
+        assert_eq!(
+            strings(&batch, "namespace"),
+            vec![Some("foo.bar".into()), Some("foo.baz".into())]
+        );
+    }
+
+    #[test]
+    fn convert_raw_reads_the_term_and_wall_clock_time_of_entries() {
+        let entry = rawdoc! {
+            "ts": Timestamp { time: 1479561394, increment: 7 },
+            "t": 1_i64,
+            "wall": bson::DateTime::from_millis(1479561394123),
+            "op": "i",
+            "ns": "foo.bar",
+            "o": { "_id": 1 },
+        };
+        let converter = ArrowConverter::new();
+        let terms = |batch: &RecordBatch| {
+            let array = batch
+                .column_by_name("term")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap()
+                .clone();
+
+            array.iter().collect::<Vec<_>>()
+        };
+        let walls = |batch: &RecordBatch| {
+            let array = batch
+                .column_by_name("wall")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<TimestampMillisecondArray>()
+                .unwrap()
+                .clone();
+
+            array.iter().collect::<Vec<_>>()
+        };
+        let raw = converter.convert_raw(&[entry]).unwrap();
+        let owned = converter
+            .convert(&[insert(1479561394, "foo.bar", 1)])
+            .unwrap();
+
+        assert_eq!(terms(&raw), vec![Some(1)]);
+        assert_eq!(walls(&raw), vec![Some(1479561394123)]);
+        assert_eq!(terms(&owned), vec![None]);
+        assert_eq!(walls(&owned), vec![None]);
+    }
+
+    #[test]
+    fn convert_encodes_documents_as_bson() {
+        let batch = ArrowConverter::new()
+            .document_encoding(DocumentEncoding::Bson)
+            .convert(&[insert(1, "foo.bar", 1)])
+            .unwrap();
+        let documents = batch
+            .column_by_name("document")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<BinaryArray>()
+            .unwrap();
+
+        assert_eq!(
+            Document::from_reader(documents.value(0)).unwrap(),
+            doc! { "_id": 1, "name": "Alice" }
+        );
+    }
+
+    #[test]
+    fn exporter_partitions_by_date_and_namespace() {
+        let dir = directory("partitions");
+        let mut exporter = ParquetExporter::new(&dir).batch_size(1);
+        for operation in &[
+            insert(1479561394, "foo.bar", 1),
+            insert(1479561395, "foo.bar", 2),
+            insert(1479561396, "foo.baz", 3),
+            insert(1479661394, "foo.bar", 4),
+        ] {
+            exporter.write(operation).unwrap();
+        }
+        let paths = exporter.finish().unwrap();
+
+        assert_eq!(
+            paths,
+            vec![
+                dir.join("date=2016-11-19/namespace=foo.bar/1479561394-0.parquet"),
+                dir.join("date=2016-11-19/namespace=foo.baz/1479561396-0.parquet"),
+                dir.join("date=2016-11-20/namespace=foo.bar/1479661394-0.parquet"),
+            ]
+        );
+
+        let file = File::open(&paths[0]).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let rows: usize = reader.map(|batch| batch.unwrap().num_rows()).sum();
+        assert_eq!(rows, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exporter_closes_past_dates() {
+        let dir = directory("dates");
+        let mut exporter = ParquetExporter::new(&dir);
+        exporter.write(&insert(1479561394, "foo.bar", 1)).unwrap();
+        exporter.write(&insert(1479661394, "foo.bar", 2)).unwrap();
+
+        let file = File::open(dir.join("date=2016-11-19/namespace=foo.bar/1479561394-0.parquet"));
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file.unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let rows: usize = reader.map(|batch| batch.unwrap().num_rows()).sum();
+        assert_eq!(rows, 1);
+
+        exporter.finish().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exporter_does_not_overwrite_earlier_exports() {
+        let dir = directory("overwrite");
+        let export = || {
+            let mut exporter = ParquetExporter::new(&dir);
+            exporter.write(&insert(1479561394, "foo.bar", 1)).unwrap();
+            exporter.finish().unwrap()
+        };
+
+        let partition = dir.join("date=2016-11-19/namespace=foo.bar");
+        assert_eq!(export(), vec![partition.join("1479561394-0.parquet")]);
+        assert_eq!(export(), vec![partition.join("1479561394-0-1.parquet")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+//! The instrument module records metrics and emits tracing events while tailing the oplog.
+//!
+//! Metrics are reported through the [`metrics`](https://docs.rs/metrics) facade when the `metrics`
+//! feature is enabled so that any compatible exporter (e.g. Prometheus or StatsD) can be installed
+//! by the application. Events are emitted through [`tracing`](https://docs.rs/tracing) when the
+//! `tracing` feature is enabled. Without either feature, every function here is a no-op.
+
+use crate::{Error, OpTime, Operation};
+use bson::Document;
+use mongodb::bson;
+
+/// Records an oplog entry read from the cursor, before it is parsed or skipped.
+pub(crate) fn entry_read(document: &Document) {
+    #[cfg(not(feature = "metrics"))]
+    let _ = document;
+
+    #[cfg(feature = "metrics")]
+    metrics::counter!("oplog_bytes_read_total").increment(crate::oper::document_size(document));
+}
+
+/// Records an operation read from the oplog.
+pub(crate) fn operation_read(operation: &Operation, optime: Option<OpTime>) {
+    #[cfg(not(feature = "tracing"))]
+    let _ = (operation, optime);
+
+    #[cfg(feature = "metrics")]
+    {
+        use chrono::Utc;
+        use metrics::{counter, gauge};
+
+        let namespace = operation.namespace().unwrap_or("").to_string();
+
+        counter!("oplog_operations_total", "op" => op_label(operation), "namespace" => namespace)
+            .increment(1);
+
+        let lag = Utc::now() - operation.timestamp();
+        gauge!("oplog_lag_seconds").set(lag.num_milliseconds() as f64 / 1000.0);
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        op = op_label(operation),
+        namespace = operation.namespace(),
+        optime = ?optime,
+        "read operation"
+    );
+}
+
+/// Records an oplog entry that could not be converted into an `Operation`.
+pub(crate) fn parse_error(document: &Document, error: &Error) {
+    #[cfg(not(feature = "tracing"))]
+    let _ = (document, error);
+
+    #[cfg(feature = "metrics")]
+    metrics::counter!("oplog_parse_errors_total").increment(1);
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        op = document.get_str("op").ok(),
+        namespace = document.get_str("ns").ok(),
+        ts = ?document.get_timestamp("ts").ok(),
+        %error,
+        "unparseable oplog entry"
+    );
+}
+
+/// Records an unparseable oplog entry being skipped rather than yielded.
+pub(crate) fn entry_skipped(document: &Document) {
+    #[cfg(not(feature = "tracing"))]
+    let _ = document;
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(ts = ?document.get_timestamp("ts").ok(), "skipped oplog entry");
+}
+
+/// Records the cursor having to wait on the server after yielding `documents` entries since it
+/// last waited, i.e. the end of a batch.
+pub(crate) fn batch_read(documents: usize) {
+    #[cfg(not(feature = "tracing"))]
+    let _ = documents;
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(batch_size = documents, "awaiting next batch");
+}
+
+/// Records the oplog cursor being reopened.
+pub(crate) fn reconnect(timestamp: Option<bson::Timestamp>) {
+    #[cfg(not(feature = "tracing"))]
+    let _ = timestamp;
+
+    #[cfg(feature = "metrics")]
+    metrics::counter!("oplog_reconnects_total").increment(1);
+
+    #[cfg(feature = "tracing")]
+    tracing::info!(after = ?timestamp, "reopened oplog cursor");
+}
+
+/// Records previously read operations disappearing from the oplog.
+pub(crate) fn rollback_detected(lost: &[OpTime]) {
+    #[cfg(not(feature = "tracing"))]
+    let _ = lost;
+
+    #[cfg(feature = "tracing")]
+    tracing::warn!(operations = lost.len(), first = ?lost.first(), "rollback detected");
+}
+
+/// Records the server failing to accept a connection.
+#[cfg(feature = "server")]
+pub(crate) fn accept_failed(error: &std::io::Error) {
+    #[cfg(not(feature = "tracing"))]
+    let _ = error;
+
+    #[cfg(feature = "tracing")]
+    tracing::warn!(%error, "failed to accept connection");
+}
+
+/// Returns the label used for the type of an operation.
+#[cfg(any(feature = "metrics", feature = "tracing"))]
+fn op_label(operation: &Operation) -> &'static str {
+    match *operation {
+        Operation::Noop { .. } => "noop",
+        Operation::Insert { .. } => "insert",
+        Operation::Update { .. } => "update",
+        Operation::Delete { .. } => "delete",
+        Operation::Command { .. } => "command",
+        Operation::ApplyOps { .. } => "applyOps",
+        Operation::Unknown { .. } => "unknown",
+    }
+}
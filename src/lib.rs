@@ -53,37 +53,156 @@
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! # Raw entries
+//!
+//! Converting every entry into an `Operation` copies its documents. Where throughput matters,
+//! `OplogBuilder::build_raw` builds a `RawOplog` yielding entries as raw BSON instead, which can
+//! be parsed into a `RawOperation` borrowing from them and only converted into an `Operation`
+//! when needed. The `parse` benchmark compares both approaches (`cargo bench --bench parse`).
+//!
+//! # Blocking iteration
+//!
+//! With the `sync` feature enabled, `OplogBuilder::build_sync` builds a `SyncOplog` over the
+//! MongoDB driver's sync `Client` instead, which implements `Iterator` so no async runtime is
+//! needed by the caller.
+//!
+//! # HTTP server
+//!
+//! With the `server` feature enabled, a `Server` streams operations from a single `Oplog` to
+//! any number of HTTP clients as Server-Sent Events or newline-delimited JSON.
+//!
+//! # Parquet export
+//!
+//! With the `parquet` feature enabled, an `ArrowConverter` turns operations into Apache Arrow
+//! record batches and a `ParquetExporter` writes them to Parquet files partitioned by date and
+//! namespace.
+//!
+//! # Metrics
+//!
+//! With the `metrics` feature enabled, the following are reported through the
+//! [`metrics`](https://docs.rs/metrics) facade to whichever exporter the application installs:
+//!
+//! * `oplog_operations_total`: a counter of operations read, labelled by `op` and `namespace`,
+//!   from which throughput can be derived (e.g. with Prometheus' `rate`)
+//! * `oplog_bytes_read_total`: a counter of raw BSON bytes read, including entries that are
+//!   skipped or cannot be parsed
+//! * `oplog_parse_errors_total`: a counter of entries that could not be converted to an
+//!   `Operation`
+//! * `oplog_reconnects_total`: a counter of calls to `Oplog::resume`
+//! * `oplog_lag_seconds`: a gauge of the time between the last operation and the wall clock
+//!
+//! See `examples/prometheus.rs` for serving these as a Prometheus text endpoint.
+//!
+//! # Tracing
+//!
+//! With the `tracing` feature enabled, events are emitted through
+//! [`tracing`](https://docs.rs/tracing) for building the `Oplog` (an `oplog_build` span), the
+//! end of each batch read from the cursor (`batch_size`), each operation read and parsed (`op`,
+//! `namespace`, `optime`) and, at debug level, any entry that could not be parsed.
 
-use bson::Document;
+use bson::{doc, Bson, Document};
+use futures::future::BoxFuture;
 use futures::ready;
-use futures::Stream;
-use mongodb::options::{CursorType, FindOptions};
+use futures::{FutureExt, Stream};
+use mongodb::options::{
+    CollectionOptions, CursorType, FindOneOptions, FindOptions, ReadConcern, ReadPreference,
+    SelectionCriteria,
+};
 use mongodb::Client;
-use mongodb::Cursor;
+use mongodb::{Collection, Cursor};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 pub use oper::Operation;
+pub use optime::OpTime;
 
 pub use mongodb;
 pub use mongodb::bson;
 
+mod batch;
+mod cloudevents;
+mod coalesce;
+mod debezium;
+mod elasticsearch;
 mod error;
+#[cfg(feature = "parquet")]
+mod export;
+mod instrument;
+mod namespace;
 mod oper;
+mod optime;
+mod pace;
+mod parse;
+mod partition;
+mod postgres;
+mod raw;
+mod reader;
+mod rollback;
+#[cfg(feature = "server")]
+mod server;
+mod shared;
+mod shutdown;
+mod sink;
+mod stats;
+#[cfg(feature = "sync")]
+mod sync;
+mod update;
+
+pub use batch::{Batch, Batches};
+pub use cloudevents::{CloudEvent, CloudEvents, STRUCTURED_CONTENT_TYPE};
+pub use coalesce::Coalesce;
+pub use debezium::{Debezium, DebeziumEvent, Session};
+pub use elasticsearch::ElasticsearchBulk;
+pub use error::{Error, ParseError, ParseErrorKind, Result};
+#[cfg(feature = "parquet")]
+pub use export::{ArrowConverter, DocumentEncoding, ParquetExporter};
+pub use namespace::Namespace;
+pub use pace::{PaceControl, Paced};
+pub use parse::{DeadLetter, ParseMode};
+pub use partition::{PartitionedExecutor, Watermark};
+pub use postgres::{PostgresTranslator, SqlValue, Statement, TableMapping};
+pub use raw::{RawOperation, RawOplog};
+#[cfg(feature = "server")]
+pub use server::Server;
+pub use shared::{LagPolicy, SharedOplog, SharedOplogHandle, SubscribeOptions, Subscriber};
+pub use shutdown::{Finished, ShutdownHandle};
+#[cfg(feature = "kafka")]
+pub use sink::KafkaSink;
+pub use sink::{Checkpoint, FileCheckpoint, Forwarder, MemoryCheckpoint, MemorySink, Record, Sink};
+pub use stats::{Analyzer, Counts, HotDocument, OplogStats, RateBucket};
 
-pub use error::{Error, Result};
+use reader::Reader;
+use shutdown::StoppableCursor;
+
+#[cfg(feature = "sync")]
+pub use sync::SyncOplog;
 
 /// Oplog represents a MongoDB replica set oplog.
 ///
-/// It implements the `Iterator` trait so it can be iterated over, yielding successive `Operation`s
-/// as they are read from the server. This will effectively iterate forever as it will await new
-/// operations.
+/// It implements the `Stream` trait so it can be iterated over asynchronously, yielding
+/// successive `Operation`s as they are read from the server. This will effectively iterate forever
+/// as it will await new operations. See `SyncOplog` (with the `sync` feature) for a blocking
+/// `Iterator` instead.
 ///
 /// Any errors raised while tailing the oplog (e.g. a connectivity issue) will cause the iteration
 /// to end.
 pub struct Oplog {
-    /// The internal MongoDB cursor for the current position in the oplog.
-    cursor: Cursor<bson::Document>,
+    /// The internal MongoDB cursor for the current position in the oplog, closed on shutdown.
+    cursor: StoppableCursor<Cursor<bson::Document>>,
+    /// The oplog collection, kept so the cursor can be reopened on resume.
+    coll: Collection<Document>,
+    /// The options the cursor was built with.
+    builder: OplogBuilder,
+    /// Converts entries into operations and tracks the position reached.
+    reader: Reader,
+    /// An entry read from a new term, held back until the rollback check completes.
+    held: Option<Document>,
+    /// The rollback check in progress, if any.
+    check: Option<BoxFuture<'static, Result<Vec<OpTime>>>>,
+    /// The number of entries read since the cursor last had to wait on the server.
+    batch_documents: usize,
 }
 
 impl Oplog {
@@ -96,6 +215,80 @@ impl Oplog {
     pub fn builder() -> OplogBuilder {
         OplogBuilder::new()
     }
+
+    /// Returns the timestamp of the last entry read from the oplog, if any.
+    ///
+    /// This is suitable for checkpointing: passing it to `OplogBuilder::start_after` will pick up
+    /// with the next entry.
+    pub fn last_timestamp(&self) -> Option<bson::Timestamp> {
+        self.reader.last_timestamp()
+    }
+
+    /// Reopens the underlying cursor directly after the last entry read.
+    ///
+    /// This is intended for recovering after the cursor has died (e.g. following a connectivity
+    /// error). If nothing has been read yet, the cursor is reopened from the builder's original
+    /// start position.
+    ///
+    /// Returns `Error::PositionLost` if the oplog has since been truncated past the position we
+    /// would resume from.
+    ///
+    /// If rollback detection is enabled, recently read entries are checked first and
+    /// `Error::RollbackDetected` is returned if any have disappeared. The cursor is still reopened
+    /// in that case, after the newest entry that survived, so the stream can carry on once the
+    /// rollback has been dealt with.
+    pub async fn resume(&mut self) -> Result<()> {
+        let lost = match self.reader.recent() {
+            Some(recent) => rollback::find_lost(self.coll.clone(), recent).await?,
+            None => Vec::new(),
+        };
+        let rolled_back = self.reader.rolled_back(lost, true);
+
+        self.cursor.reopen(
+            self.builder
+                .open(&self.coll, self.reader.resume_from())
+                .await?,
+        );
+        instrument::reconnect(self.reader.last_timestamp());
+        self.held = None;
+        self.check = None;
+
+        rolled_back
+    }
+
+    /// Returns a handle to shut the oplog down gracefully from another task.
+    ///
+    /// See `ShutdownHandle`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.cursor.handle()
+    }
+
+    /// Records the number of entries read from the batch just drained.
+    fn end_batch(&mut self) {
+        if self.batch_documents > 0 {
+            instrument::batch_read(self.batch_documents);
+            self.batch_documents = 0;
+        }
+    }
+
+    /// Closes the cursor and reports the final position to any `ShutdownHandle`s.
+    fn finish(&mut self) {
+        self.end_batch();
+        self.cursor.finish(self.reader.last_timestamp());
+    }
+
+    /// Starts a rollback check if the given entry was written in a new term.
+    fn start_check(&mut self, document: &Document) -> bool {
+        match self.reader.term_changed(document) {
+            Some(recent) => {
+                let check = rollback::find_lost(self.coll.clone(), recent);
+                self.check = Some(check.boxed());
+
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 impl Stream for Oplog {
@@ -104,18 +297,88 @@ impl Stream for Oplog {
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
 
-        if let Some(res) = ready!(Pin::new(&mut this.cursor).poll_next(cx)) {
-            match res {
-                Ok(v) => match Operation::new(&v) {
-                    Ok(o) => Some(Ok(o)).into(),
-                    Err(e) => Some(Err(e)).into(),
-                },
-                Err(e) => Some(Err(e.into())).into(),
+        if let Some(ref mut check) = this.check {
+            let res = ready!(check.as_mut().poll(cx));
+            this.check = None;
+
+            if let Err(e) = res.and_then(|lost| this.reader.rolled_back(lost, false)) {
+                return Some(Err(e)).into();
+            }
+        }
+
+        if let Some(held) = this.held.take() {
+            if let Some(res) = this.reader.read(held) {
+                return Some(res).into();
+            }
+        }
+
+        loop {
+            let next = match this.cursor.poll_next(cx) {
+                Poll::Ready(next) => next,
+                Poll::Pending => {
+                    this.end_batch();
+
+                    return Poll::Pending;
+                }
+            };
+
+            if let Some(res) = next {
+                this.batch_documents += 1;
+
+                match res {
+                    Ok(v) => {
+                        if this.start_check(&v) {
+                            this.held = Some(v);
+
+                            return Pin::new(this).poll_next(cx);
+                        }
+
+                        if let Some(res) = this.reader.read(v) {
+                            return Some(res).into();
+                        }
+                    }
+                    Err(e) => return Some(Err(e.into())).into(),
+                }
+            } else {
+                // Underlying cursor is over, either as shutdown was requested or, more unusually,
+                // as the oplog.rs collection is empty. See
+                // https://jira.mongodb.org/browse/SERVER-13955
+                this.finish();
+
+                return None.into();
             }
-        } else {
-            // Underlying cursor is over. This probably indicates that the oplog.rs collection
-            // is empty. See https://jira.mongodb.org/browse/SERVER-13955
-            None.into()
+        }
+    }
+}
+
+impl Drop for Oplog {
+    fn drop(&mut self) {
+        self.cursor.finish(self.reader.last_timestamp());
+    }
+}
+
+/// A position in the oplog to start reading from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Start {
+    /// Start with the entry at the given timestamp.
+    At(bson::Timestamp),
+    /// Start with the first entry following the given timestamp.
+    After(bson::Timestamp),
+}
+
+impl Start {
+    /// The timestamp that must still be present in the oplog for no entries to have been lost.
+    fn timestamp(self) -> bson::Timestamp {
+        match self {
+            Start::At(ts) | Start::After(ts) => ts,
+        }
+    }
+
+    /// Returns a filter restricting entries to those from this position onwards.
+    fn filter(self) -> Document {
+        match self {
+            Start::At(ts) => doc! { "ts": { "$gte": ts } },
+            Start::After(ts) => doc! { "ts": { "$gt": ts } },
         }
     }
 }
@@ -130,6 +393,16 @@ impl Stream for Oplog {
 pub struct OplogBuilder {
     filter: Option<Document>,
     batch_size: Option<u32>,
+    start: Option<Start>,
+    rollback_window: Option<usize>,
+    parse_mode: ParseMode,
+    projection: Option<Document>,
+    cursor_type: CursorType,
+    max_await_time: Option<Duration>,
+    selection_criteria: Option<SelectionCriteria>,
+    read_concern: Option<ReadConcern>,
+    database: String,
+    collection: String,
 }
 
 impl OplogBuilder {
@@ -137,6 +410,16 @@ impl OplogBuilder {
         OplogBuilder {
             filter: None,
             batch_size: None,
+            start: None,
+            rollback_window: None,
+            parse_mode: ParseMode::Strict,
+            projection: None,
+            cursor_type: CursorType::Tailable,
+            max_await_time: None,
+            selection_criteria: None,
+            read_concern: None,
+            database: "local".into(),
+            collection: "oplog.rs".into(),
         }
     }
 
@@ -175,18 +458,515 @@ impl OplogBuilder {
         self
     }
 
+    /// Start reading from the oplog entry with the given timestamp (inclusive).
+    ///
+    /// When the `Oplog` is built, the oldest entry still in the capped collection is checked and
+    /// `Error::PositionLost` is returned if it is newer than `timestamp`, as any operations in
+    /// between have been truncated and can no longer be read.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use mongodb::Client;
+    /// use oplog::bson::Timestamp;
+    /// use oplog::{Error, Oplog};
+    ///
+    /// # async fn run() -> Result<(), oplog::Error> {
+    /// let client = Client::with_uri_str("mongodb://localhost").await?;
+    ///
+    /// let res = Oplog::builder()
+    ///     .start_at(Timestamp { time: 1479561394, increment: 0 })
+    ///     .build(&client)
+    ///     .await;
+    ///
+    /// if let Err(Error::PositionLost { oldest, .. }) = res {
+    ///     eprintln!("Oplog now starts at {:?}, a full resync is needed", oldest);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn start_at(mut self, timestamp: bson::Timestamp) -> Self {
+        self.start = Some(Start::At(timestamp));
+        self
+    }
+
+    /// Start reading from the first oplog entry after the given timestamp (exclusive).
+    ///
+    /// This is typically used to resume from a checkpoint saved with `Oplog::last_timestamp`. The
+    /// same check for truncated entries as `start_at` applies.
+    pub fn start_after(mut self, timestamp: bson::Timestamp) -> Self {
+        self.start = Some(Start::After(timestamp));
+        self
+    }
+
+    /// Detect operations rolled back after a replica set election.
+    ///
+    /// The optimes of the last `window` entries read are remembered. Whenever an entry from a new
+    /// election term is read, or the `Oplog` is resumed, those entries are looked up in the oplog
+    /// again and an `Error::RollbackDetected` listing any that have disappeared is yielded before
+    /// carrying on. Operations read before the last `window` entries are not checked.
+    ///
+    /// Term changes can only be noticed on servers that record the term (`t`) of each entry, i.e.
+    /// MongoDB 3.2 and later using replication protocol version 1.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use futures::StreamExt;
+    /// use mongodb::Client;
+    /// use oplog::{Error, Oplog};
+    ///
+    /// # async fn run() -> Result<(), oplog::Error> {
+    /// let client = Client::with_uri_str("mongodb://localhost").await?;
+    ///
+    /// let mut oplog = Oplog::builder()
+    ///     .detect_rollbacks(1000)
+    ///     .build(&client)
+    ///     .await?;
+    ///
+    /// while let Some(res) = oplog.next().await {
+    ///     match res {
+    ///         Ok(oper) => println!("{}", oper),
+    ///         Err(Error::RollbackDetected { operations }) => {
+    ///             eprintln!("{} operations were rolled back", operations.len());
+    ///         }
+    ///         Err(e) => return Err(e),
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn detect_rollbacks(mut self, window: usize) -> Self {
+        self.rollback_window = Some(window);
+        self
+    }
+
+    /// Choose how entries that cannot be converted into an `Operation` are handled.
+    ///
+    /// By default (`ParseMode::Strict`) an error is yielded for each such entry, which typically
+    /// ends iteration. The other modes let tailing carry on regardless.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::sync::Arc;
+    /// use mongodb::Client;
+    /// use oplog::{Oplog, ParseMode};
+    ///
+    /// # async fn run() -> Result<(), oplog::Error> {
+    /// let client = Client::with_uri_str("mongodb://localhost").await?;
+    ///
+    /// let mut oplog = Oplog::builder()
+    ///     .parse_mode(ParseMode::DeadLetter(Arc::new(|document, error| {
+    ///         eprintln!("Skipping {}: {}", document, error);
+    ///     })))
+    ///     .build(&client)
+    ///     .await?;
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn parse_mode(mut self, mode: ParseMode) -> Self {
+        self.parse_mode = mode;
+        self
+    }
+
+    /// Only fetch the given fields of each oplog entry from the server.
+    ///
+    /// Entries are then parsed with `Operation::new_projected`, which tolerates the `o` and `o2`
+    /// fields being trimmed or projected away but still requires `ts`, `op` and `ns`. If rollback
+    /// detection is enabled, `t` and `h` are fetched whatever the projection as entries are
+    /// compared by them. Note that projecting `o` affects commands as well, e.g. a `drop` command
+    /// projected down to `o._id` becomes an empty command.
+    ///
+    /// See `OplogBuilder::key_fields_only` for the common case of only needing namespaces and
+    /// `_id`s.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use mongodb::Client;
+    /// use oplog::bson::doc;
+    /// use oplog::Oplog;
+    ///
+    /// # async fn run() -> Result<(), oplog::Error> {
+    /// let client = Client::with_uri_str("mongodb://localhost").await?;
+    ///
+    /// let mut oplog = Oplog::builder()
+    ///     .projection(doc! { "ts": 1, "op": 1, "ns": 1, "o._id": 1, "o2._id": 1 })
+    ///     .build(&client)
+    ///     .await?;
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn projection(mut self, projection: Document) -> Self {
+        self.projection = Some(projection);
+        self
+    }
+
+    /// Only fetch the position, type, namespace and `_id` of each oplog entry (including those
+    /// within `applyOps` commands) from the server.
+    ///
+    /// This suits consumers such as cache invalidators that only need to know which documents
+    /// changed, using far less bandwidth than reading whole entries. Operations are yielded with
+    /// every document trimmed to its `_id`, if it has one, so updates and commands other than
+    /// `applyOps` are typically left empty.
+    pub fn key_fields_only(self) -> Self {
+        self.projection(doc! {
+            "ts": 1,
+            "t": 1,
+            "h": 1,
+            "op": 1,
+            "ns": 1,
+            "o._id": 1,
+            "o2._id": 1,
+            "o.applyOps.ts": 1,
+            "o.applyOps.op": 1,
+            "o.applyOps.ns": 1,
+            "o.applyOps.o._id": 1,
+            "o.applyOps.o2._id": 1,
+        })
+    }
+
+    /// Use a tailable await cursor, so the server holds each request for more entries open for up
+    /// to `max_await_time` (or its own default of one second if `None`) when none are available.
+    ///
+    /// By default the cursor is merely tailable: the server returns an empty batch straight away
+    /// once the cursor reaches the end of the oplog and the driver immediately asks again, which
+    /// busy-polls the server while the oplog is quiet. Awaiting data avoids that at the cost of
+    /// the stream only noticing it has been dropped or needs resuming once the wait ends, so keep
+    /// `max_await_time` short if that matters.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use mongodb::Client;
+    /// use oplog::Oplog;
+    ///
+    /// # async fn run() -> Result<(), oplog::Error> {
+    /// let client = Client::with_uri_str("mongodb://localhost").await?;
+    ///
+    /// let mut oplog = Oplog::builder()
+    ///     .tailable_await(Some(Duration::from_millis(500)))
+    ///     .build(&client)
+    ///     .await?;
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn tailable_await(mut self, max_await_time: Option<Duration>) -> Self {
+        self.cursor_type = CursorType::TailableAwait;
+        self.max_await_time = max_await_time;
+        self
+    }
+
+    /// Set the read preference used to pick the replica set member to tail, overriding the
+    /// client's.
+    ///
+    /// Every member keeps its own oplog so tailing a secondary (optionally one matching given tag
+    /// sets, e.g. in a given data centre) takes load off the primary. However, a secondary's oplog
+    /// lags behind the primary's, and positions are only comparable across members by timestamp:
+    /// use `Oplog::last_timestamp` with `OplogBuilder::start_after` when switching members.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use mongodb::options::{ReadPreference, ReadPreferenceOptions, TagSet};
+    /// use mongodb::Client;
+    /// use oplog::Oplog;
+    ///
+    /// # async fn run() -> Result<(), oplog::Error> {
+    /// let client = Client::with_uri_str("mongodb://localhost").await?;
+    /// let tags: TagSet = vec![("dc".to_string(), "east".to_string())].into_iter().collect();
+    ///
+    /// let mut oplog = Oplog::builder()
+    ///     .read_preference(ReadPreference::Secondary {
+    ///         options: ReadPreferenceOptions::builder().tag_sets(vec![tags]).build(),
+    ///     })
+    ///     .build(&client)
+    ///     .await?;
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_preference(mut self, read_preference: ReadPreference) -> Self {
+        self.selection_criteria = Some(SelectionCriteria::ReadPreference(read_preference));
+        self
+    }
+
+    /// Set the read concern of the queries on the oplog, overriding the client's.
+    ///
+    /// The default (`local`) returns entries as soon as they are written to the member being
+    /// read, including entries that may later be rolled back. Which other levels the server
+    /// accepts for the oplog depends on its version and configuration.
+    pub fn read_concern(mut self, read_concern: ReadConcern) -> Self {
+        self.read_concern = Some(read_concern);
+        self
+    }
+
+    /// Read from the given database and collection rather than `local.oplog.rs`.
+    ///
+    /// This is intended for testing, e.g. against a capped collection filled with fixture
+    /// entries on a standalone server.
+    pub fn source(mut self, database: &str, collection: &str) -> Self {
+        self.database = database.into();
+        self.collection = collection.into();
+        self
+    }
+
     /// Executes the query and builds the `Oplog` over the client provided.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "oplog_build",
+            skip_all,
+            fields(filter = ?self.filter, batch_size = ?self.batch_size, start = ?self.start),
+            err
+        )
+    )]
     pub async fn build(self, client: &Client) -> Result<Oplog> {
-        let coll = client.database("local").collection("oplog.rs");
+        let coll = client
+            .database(&self.database)
+            .collection_with_options(&self.collection, self.collection_options());
+        let cursor = self.open(&coll, self.start).await?;
+
+        Ok(Oplog {
+            cursor: StoppableCursor::new(cursor),
+            coll,
+            reader: self.reader(),
+            builder: self,
+            held: None,
+            check: None,
+            batch_documents: 0,
+        })
+    }
+
+    /// Executes the query and builds a `RawOplog` over the client provided, yielding entries as
+    /// raw BSON to be parsed with `RawOperation::new` rather than as `Operation`s.
+    ///
+    /// This avoids copying every entry into owned documents, which can dominate CPU usage when
+    /// tailing a busy primary. The `parse_mode` and `detect_rollbacks` options are ignored as
+    /// entries are not parsed by the stream.
+    pub async fn build_raw(self, client: &Client) -> Result<RawOplog> {
+        let coll = client
+            .database(&self.database)
+            .collection_with_options(&self.collection, self.collection_options());
+        let cursor = self.open(&coll, self.start).await?;
+
+        Ok(RawOplog::new(cursor, coll, self))
+    }
+
+    /// Opens a tailable cursor on the oplog from the given position.
+    async fn open<T>(&self, coll: &Collection<T>, start: Option<Start>) -> Result<Cursor<T>>
+    where
+        T: Send + Sync,
+    {
+        if let Some(start) = start {
+            let first = coll
+                .clone_with_type::<Document>()
+                .find_one(None, oldest_options())
+                .await?;
+            check_oldest(first, start.timestamp())?;
+        }
+
+        Ok(coll.find(self.query(start), self.find_options()).await?)
+    }
+
+    /// Returns a reader configured with this builder's options.
+    fn reader(&self) -> Reader {
+        Reader::new(
+            self.parse_mode.clone(),
+            self.start,
+            self.rollback_window,
+            self.projection.is_some(),
+        )
+    }
+
+    /// Returns the filter for the oplog query from the given position.
+    fn query(&self, start: Option<Start>) -> Option<Document> {
+        merge_filters(self.filter.clone(), start.map(Start::filter))
+    }
+
+    /// Returns the options for the oplog collection, applying to every query on it.
+    fn collection_options(&self) -> CollectionOptions {
+        CollectionOptions::builder()
+            .selection_criteria(self.selection_criteria.clone())
+            .read_concern(self.read_concern.clone())
+            .build()
+    }
 
-        let opts = FindOptions::builder()
+    /// Returns the options for the oplog query.
+    fn find_options(&self) -> FindOptions {
+        FindOptions::builder()
             .no_cursor_timeout(true)
-            .cursor_type(CursorType::Tailable)
+            .cursor_type(self.cursor_type)
+            .max_await_time(self.max_await_time)
             .batch_size(self.batch_size)
-            .build();
+            .projection(self.query_projection())
+            .build()
+    }
+
+    /// Returns the projection for the oplog query, adding the fields compared by rollback
+    /// detection if it is enabled.
+    fn query_projection(&self) -> Option<Document> {
+        let mut projection = self.projection.clone()?;
+
+        if self.rollback_window.is_some() {
+            // Only `_id` may be excluded from an inclusion projection, so a projection including
+            // no other field (e.g. `{ "_id": 0 }`) is an exclusion one.
+            let including = projection
+                .iter()
+                .any(|(field, value)| field != "_id" && !is_excluded(value));
+
+            for field in ["t", "h"] {
+                if including {
+                    projection.insert(field, 1);
+                } else {
+                    projection.remove(field);
+                }
+            }
+        }
+
+        Some(projection)
+    }
+}
+
+/// Returns true if a projection value excludes its field.
+fn is_excluded(value: &Bson) -> bool {
+    match *value {
+        Bson::Boolean(b) => !b,
+        Bson::Int32(n) => n == 0,
+        Bson::Int64(n) => n == 0,
+        Bson::Double(n) => n == 0.0,
+        _ => false,
+    }
+}
+
+/// Returns the options to find the oldest entry in the oplog.
+fn oldest_options() -> FindOneOptions {
+    FindOneOptions::builder()
+        .sort(doc! { "$natural": 1 })
+        .build()
+}
+
+/// Returns `Error::PositionLost` if the oldest entry in the oplog is newer than `requested`.
+fn check_oldest(first: Option<Document>, requested: bson::Timestamp) -> Result<()> {
+    if let Some(first) = first {
+        let oldest = oper::Entry::new(&first, String::new()).get_timestamp("ts")?;
+
+        if oldest > requested {
+            return Err(Error::PositionLost { requested, oldest });
+        }
+    }
+
+    Ok(())
+}
+
+/// Combines a user-supplied filter with a position filter so both must match.
+fn merge_filters(filter: Option<Document>, position: Option<Document>) -> Option<Document> {
+    match (filter, position) {
+        (Some(filter), Some(position)) => Some(doc! { "$and": [filter, position] }),
+        (filter, position) => filter.or(position),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_filters_combines_filter_and_position() {
+        let ts = bson::Timestamp {
+            time: 1479561394,
+            increment: 1,
+        };
+        let filter = merge_filters(Some(doc! { "op": "i" }), Some(Start::After(ts).filter()));
+
+        assert_eq!(
+            filter,
+            Some(doc! { "$and": [{ "op": "i" }, { "ts": { "$gt": ts } }] })
+        );
+    }
+
+    #[test]
+    fn merge_filters_uses_either_filter_alone() {
+        let ts = bson::Timestamp {
+            time: 1479561394,
+            increment: 1,
+        };
+
+        assert_eq!(
+            merge_filters(None, Some(Start::At(ts).filter())),
+            Some(doc! { "ts": { "$gte": ts } })
+        );
+        assert_eq!(
+            merge_filters(Some(doc! { "op": "i" }), None),
+            Some(doc! { "op": "i" })
+        );
+        assert_eq!(merge_filters(None, None), None);
+    }
+
+    #[test]
+    fn find_options_keep_the_fields_compared_by_rollback_detection() {
+        let projection = |projection| {
+            OplogBuilder::new()
+                .projection(projection)
+                .detect_rollbacks(100)
+                .find_options()
+                .projection
+        };
+
+        assert_eq!(
+            projection(doc! { "ts": 1, "op": 1, "ns": 1 }),
+            Some(doc! { "ts": 1, "op": 1, "ns": 1, "t": 1, "h": 1 })
+        );
+        assert_eq!(projection(doc! { "o": 0, "t": 0 }), Some(doc! { "o": 0 }));
+        assert_eq!(projection(doc! { "_id": 0 }), Some(doc! { "_id": 0 }));
+        assert_eq!(
+            projection(doc! { "_id": 0, "ns": 1 }),
+            Some(doc! { "_id": 0, "ns": 1, "t": 1, "h": 1 })
+        );
+    }
+
+    #[test]
+    fn find_options_default_to_a_tailable_cursor() {
+        let options = OplogBuilder::new().find_options();
+
+        assert!(matches!(options.cursor_type, Some(CursorType::Tailable)));
+        assert_eq!(options.max_await_time, None);
+        assert_eq!(options.no_cursor_timeout, Some(true));
+    }
+
+    #[test]
+    fn find_options_await_data_when_configured() {
+        let options = OplogBuilder::new()
+            .tailable_await(Some(Duration::from_millis(500)))
+            .find_options();
+
+        assert!(matches!(
+            options.cursor_type,
+            Some(CursorType::TailableAwait)
+        ));
+        assert_eq!(options.max_await_time, Some(Duration::from_millis(500)));
+    }
 
-        let cursor = coll.find(self.filter, opts).await?;
+    #[test]
+    fn collection_options_apply_read_preference_and_concern() {
+        let options = OplogBuilder::new()
+            .read_preference(ReadPreference::SecondaryPreferred {
+                options: Default::default(),
+            })
+            .read_concern(ReadConcern::majority())
+            .collection_options();
 
-        Ok(Oplog { cursor })
+        assert!(matches!(
+            options.selection_criteria,
+            Some(SelectionCriteria::ReadPreference(
+                ReadPreference::SecondaryPreferred { .. }
+            ))
+        ));
+        assert_eq!(options.read_concern, Some(ReadConcern::majority()));
     }
 }
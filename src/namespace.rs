@@ -0,0 +1,59 @@
+//! The namespace module splits the full namespace of an operation into its database and
+//! collection.
+
+use std::fmt;
+
+/// The database and collection of an operation, e.g. `foo.bar` has database `foo` and collection
+/// `bar`.
+///
+/// Collection names may themselves contain dots so only the first dot separates the two. Commands
+/// have the collection `$cmd`.
+///
+/// # Example
+///
+/// ```
+/// use oplog::Namespace;
+///
+/// let namespace = Namespace::new("foo.bar.baz");
+///
+/// assert_eq!(namespace.database, "foo");
+/// assert_eq!(namespace.collection, "bar.baz");
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Namespace<'a> {
+    /// The name of the database.
+    pub database: &'a str,
+    /// The name of the collection, empty if the namespace has no collection.
+    pub collection: &'a str,
+}
+
+impl<'a> Namespace<'a> {
+    /// Splits a full namespace into its database and collection.
+    pub fn new(namespace: &'a str) -> Namespace<'a> {
+        match namespace.split_once('.') {
+            Some((database, collection)) => Namespace {
+                database,
+                collection,
+            },
+            None => Namespace {
+                database: namespace,
+                collection: "",
+            },
+        }
+    }
+
+    /// Returns true if this is the namespace of a command, i.e. the collection is `$cmd`.
+    pub fn is_command(&self) -> bool {
+        self.collection == "$cmd"
+    }
+}
+
+impl fmt::Display for Namespace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.collection.is_empty() {
+            write!(f, "{}", self.database)
+        } else {
+            write!(f, "{}.{}", self.database, self.collection)
+        }
+    }
+}
@@ -8,8 +8,10 @@
 
 use std::fmt;
 
-use crate::{Error, Result};
-use bson::{Bson, Document};
+use crate::error::{ParseError, ParseErrorKind};
+use crate::{Error, OpTime, Result};
+use bson::spec::ElementType;
+use bson::{doc, Bson, Document};
 use chrono::{DateTime, TimeZone, Utc};
 use mongodb::bson;
 
@@ -69,6 +71,25 @@ pub enum Operation {
         namespace: String,
         /// A vector of operations to apply.
         operations: Vec<Operation>,
+        /// Whether more entries of a large transaction follow (`partialTxn`).
+        ///
+        /// Since MongoDB 4.2, a transaction too large for one entry is written as several
+        /// `applyOps` entries, all but the last of which are partial.
+        partial: bool,
+        /// Whether the entry prepares a transaction (`prepare`), which is only applied once a
+        /// later `commitTransaction` command arrives or discarded by an `abortTransaction` one.
+        prepare: bool,
+        /// The position of the previous entry of the same transaction (`prevOpTime`), if this is
+        /// not the first.
+        previous: Option<OpTime>,
+    },
+    /// An oplog entry that could not be converted into any other operation, as returned by
+    /// `Operation::new_lenient`.
+    Unknown {
+        /// The time of the operation.
+        timestamp: DateTime<Utc>,
+        /// The raw BSON oplog entry.
+        raw: Document,
     },
 }
 
@@ -98,32 +119,288 @@ impl Operation {
     /// let operation = Operation::new(&document);
     /// # }
     /// ```
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "parse_operation",
+            level = "trace",
+            skip_all,
+            fields(
+                op = document.get_str("op").ok(),
+                namespace = document.get_str("ns").ok(),
+                ts = ?document.get_timestamp("ts").ok(),
+            ),
+            err(level = "debug")
+        )
+    )]
     pub fn new(document: &Document) -> Result<Operation> {
-        let op = document.get_str("op")?;
+        Operation::from_entry(&Entry::new(document, String::new()))
+    }
+
+    /// Try to create a new Operation from a BSON document read with a projection, as set by
+    /// `OplogBuilder::projection`.
+    ///
+    /// This is as strict as `Operation::new` about the `ts`, `op` and `ns` fields but treats a
+    /// missing `o` or `o2` as an empty document, so projecting those down to e.g. their `_id`
+    /// (or away entirely) still yields operations.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use oplog::bson::{doc, Timestamp};
+    /// use oplog::Operation;
+    ///
+    /// let document = doc! {
+    ///     "ts": Timestamp { time: 1479561394, increment: 0 },
+    ///     "op": "u",
+    ///     "ns": "foo.bar",
+    ///     "o2": { "_id": 1 },
+    /// };
+    /// let operation = Operation::new_projected(&document).unwrap();
+    ///
+    /// assert_eq!(operation.namespace(), Some("foo.bar"));
+    /// assert_eq!(operation.document_id(), Some(&1.into()));
+    /// ```
+    pub fn new_projected(document: &Document) -> Result<Operation> {
+        let mut entry = Entry::new(document, String::new());
+        entry.projected = true;
+
+        Operation::from_entry(&entry)
+    }
+
+    /// Returns an operation for a given entry.
+    fn from_entry(entry: &Entry) -> Result<Operation> {
+        let op = entry.get_str("op")?;
 
         match op {
-            "n" => Operation::from_noop(document),
-            "i" => Operation::from_insert(document),
-            "u" => Operation::from_update(document),
-            "d" => Operation::from_delete(document),
-            "c" => Operation::from_command(document),
-            op => Err(Error::UnknownOperation(op.into())),
+            "n" => Operation::from_noop(entry),
+            "i" => Operation::from_insert(entry),
+            "u" => Operation::from_update(entry),
+            "d" => Operation::from_delete(entry),
+            "c" => Operation::from_command(entry),
+            op => Err(entry.error("op", ParseErrorKind::UnknownOperation(op.into()))),
         }
     }
 
-    /// Returns an operation from any BSON value.
-    fn from_bson(bson: &Bson) -> Result<Operation> {
-        match *bson {
-            Bson::Document(ref document) => Operation::new(document),
-            _ => Err(Error::InvalidOperation),
+    /// Try to create a new Operation from a BSON document, falling back to `Operation::Unknown`
+    /// if it has a timestamp but otherwise cannot be converted.
+    ///
+    /// This still returns an error for documents without a `ts` timestamp as they cannot be oplog
+    /// entries at all.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use oplog::bson::{doc, Timestamp};
+    /// use oplog::Operation;
+    ///
+    /// let document = doc! {
+    ///     "ts": Timestamp { time: 1479561394, increment: 0 },
+    ///     "op": "x",
+    ///     "ns": "foo.bar",
+    /// };
+    /// let operation = Operation::new_lenient(&document).unwrap();
+    ///
+    /// assert!(matches!(operation, Operation::Unknown { .. }));
+    /// ```
+    pub fn new_lenient(document: &Document) -> Result<Operation> {
+        Operation::new(document).or_else(|e| Operation::unknown(document).ok_or(e))
+    }
+
+    /// Returns an unknown operation for a given document, if it has a timestamp.
+    pub(crate) fn unknown(document: &Document) -> Option<Operation> {
+        let ts = document.get_timestamp("ts").ok()?;
+
+        Some(Operation::Unknown {
+            timestamp: timestamp_to_datetime(ts),
+            raw: document.to_owned(),
+        })
+    }
+
+    /// Returns the position of the operation in the oplog.
+    ///
+    /// Only the timestamp is kept when converting an entry into an `Operation` so the term and
+    /// hash are not available (except for unknown operations, which keep the raw entry). The
+    /// timestamp alone is enough to resume from with `OplogBuilder::start_after`.
+    pub fn optime(&self) -> OpTime {
+        match *self {
+            Operation::Unknown { ref raw, .. } => {
+                OpTime::from_document(raw).unwrap_or_else(|_| self.timestamp().into())
+            }
+            _ => self.timestamp().into(),
         }
     }
 
-    /// Returns a no-op operation for a given document.
-    fn from_noop(document: &Document) -> Result<Operation> {
-        let ts = document.get_timestamp("ts")?;
+    /// Converts the operation back into a BSON document in the shape of an oplog entry.
+    ///
+    /// Only the fields kept in the `Operation` are written: `ts`, `op`, `ns`, `o` and `o2`, and
+    /// `prevOpTime` for `applyOps` entries. Unknown operations return their raw entry.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use oplog::bson::{doc, Timestamp};
+    /// use oplog::Operation;
+    ///
+    /// let document = doc! {
+    ///     "ts": Timestamp { time: 1479561394, increment: 0 },
+    ///     "op": "i",
+    ///     "ns": "foo.bar",
+    ///     "o": { "foo": "bar" },
+    /// };
+    /// let operation = Operation::new(&document).unwrap();
+    ///
+    /// assert_eq!(operation.to_document(), document);
+    /// ```
+    pub fn to_document(&self) -> Document {
+        let ts = datetime_to_timestamp(self.timestamp());
+
+        match *self {
+            Operation::Noop { ref message, .. } => {
+                let mut document = doc! { "ts": ts, "op": "n", "ns": "" };
+
+                if let Some(ref message) = *message {
+                    document.insert("o", doc! { "msg": message });
+                }
+
+                document
+            }
+            Operation::Insert {
+                ref namespace,
+                ref document,
+                ..
+            } => doc! { "ts": ts, "op": "i", "ns": namespace, "o": document },
+            Operation::Update {
+                ref namespace,
+                ref query,
+                ref update,
+                ..
+            } => doc! { "ts": ts, "op": "u", "ns": namespace, "o2": query, "o": update },
+            Operation::Delete {
+                ref namespace,
+                ref query,
+                ..
+            } => doc! { "ts": ts, "op": "d", "ns": namespace, "o": query },
+            Operation::Command {
+                ref namespace,
+                ref command,
+                ..
+            } => doc! { "ts": ts, "op": "c", "ns": namespace, "o": command },
+            Operation::ApplyOps {
+                ref namespace,
+                ref operations,
+                partial,
+                prepare,
+                ref previous,
+                ..
+            } => {
+                let operations: Vec<_> = operations.iter().map(Operation::to_document).collect();
+                let mut o = doc! { "applyOps": operations };
+
+                if partial {
+                    o.insert("partialTxn", true);
+                }
+
+                if prepare {
+                    o.insert("prepare", true);
+                }
+
+                let mut document = doc! { "ts": ts, "op": "c", "ns": namespace, "o": o };
+
+                if let Some(ref previous) = *previous {
+                    let mut optime = doc! { "ts": previous.timestamp };
+
+                    if let Some(term) = previous.term {
+                        optime.insert("t", term);
+                    }
+
+                    document.insert("prevOpTime", optime);
+                }
+
+                document
+            }
+            Operation::Unknown { ref raw, .. } => raw.clone(),
+        }
+    }
+
+    /// Returns the time of the operation.
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match *self {
+            Operation::Noop { timestamp, .. }
+            | Operation::Insert { timestamp, .. }
+            | Operation::Update { timestamp, .. }
+            | Operation::Delete { timestamp, .. }
+            | Operation::Command { timestamp, .. }
+            | Operation::ApplyOps { timestamp, .. }
+            | Operation::Unknown { timestamp, .. } => timestamp,
+        }
+    }
+
+    /// Returns the `_id` of the document inserted, updated or deleted, if known.
+    ///
+    /// This is taken from the inserted document or the selection criteria of an update or delete,
+    /// which MongoDB always records by `_id`.
+    pub fn document_id(&self) -> Option<&Bson> {
+        match *self {
+            Operation::Insert { ref document, .. } => document.get("_id"),
+            Operation::Update { ref query, .. } | Operation::Delete { ref query, .. } => {
+                query.get("_id")
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns a key identifying the document inserted, updated or deleted, if known.
+    ///
+    /// The key is the namespace and `_id` as relaxed Extended JSON, so ids that MongoDB considers
+    /// equal despite their types (e.g. `Int32(1)` and `Int64(1)`) share a key while `1` and `"1"`
+    /// do not.
+    pub(crate) fn document_key(&self) -> Option<String> {
+        match (self.namespace(), self.document_id()) {
+            (Some(namespace), Some(id)) => Some(format!(
+                "{}/{}",
+                namespace,
+                id.clone().into_relaxed_extjson()
+            )),
+            _ => None,
+        }
+    }
+
+    /// Returns a short name for the type of the operation, e.g. `insert` or `applyOps`.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Operation::Noop { .. } => "noop",
+            Operation::Insert { .. } => "insert",
+            Operation::Update { .. } => "update",
+            Operation::Delete { .. } => "delete",
+            Operation::Command { .. } => "command",
+            Operation::ApplyOps { .. } => "applyOps",
+            Operation::Unknown { .. } => "unknown",
+        }
+    }
+
+    /// Returns the full namespace of the operation, if it has one.
+    ///
+    /// No-ops and unknown operations without an `ns` field are the only operations without a
+    /// namespace.
+    pub fn namespace(&self) -> Option<&str> {
+        match *self {
+            Operation::Noop { .. } => None,
+            Operation::Unknown { ref raw, .. } => raw.get_str("ns").ok(),
+            Operation::Insert { ref namespace, .. }
+            | Operation::Update { ref namespace, .. }
+            | Operation::Delete { ref namespace, .. }
+            | Operation::Command { ref namespace, .. }
+            | Operation::ApplyOps { ref namespace, .. } => Some(namespace),
+        }
+    }
+
+    /// Returns a no-op operation for a given entry.
+    fn from_noop(entry: &Entry) -> Result<Operation> {
+        let ts = entry.get_timestamp("ts")?;
         // We don't always get a document in "o"
-        let message = document
+        let message = entry
+            .document
             .get("o")
             .and_then(|d| d.as_document())
             .and_then(|d| d.get("msg"))
@@ -136,78 +413,187 @@ impl Operation {
         })
     }
 
-    /// Return an insert operation for a given document.
-    fn from_insert(document: &Document) -> Result<Operation> {
-        let ts = document.get_timestamp("ts")?;
-        let ns = document.get_str("ns")?;
-        let o = document.get_document("o")?;
+    /// Return an insert operation for a given entry.
+    fn from_insert(entry: &Entry) -> Result<Operation> {
+        let ts = entry.get_timestamp("ts")?;
+        let ns = entry.get_str("ns")?;
+        let o = entry.get_payload("o")?;
 
         Ok(Operation::Insert {
             timestamp: timestamp_to_datetime(ts),
             namespace: ns.into(),
-            document: o.to_owned(),
+            document: o,
         })
     }
 
-    /// Return an update operation for a given document.
-    fn from_update(document: &Document) -> Result<Operation> {
-        let ts = document.get_timestamp("ts")?;
-        let ns = document.get_str("ns")?;
-        let o = document.get_document("o")?;
-        let o2 = document.get_document("o2")?;
+    /// Return an update operation for a given entry.
+    fn from_update(entry: &Entry) -> Result<Operation> {
+        let ts = entry.get_timestamp("ts")?;
+        let ns = entry.get_str("ns")?;
+        let o = entry.get_payload("o")?;
+        let o2 = entry.get_payload("o2")?;
 
         Ok(Operation::Update {
             timestamp: timestamp_to_datetime(ts),
             namespace: ns.into(),
-            query: o2.to_owned(),
-            update: o.to_owned(),
+            query: o2,
+            update: o,
         })
     }
 
-    /// Return a delete operation for a given document.
-    fn from_delete(document: &Document) -> Result<Operation> {
-        let ts = document.get_timestamp("ts")?;
-        let ns = document.get_str("ns")?;
-        let o = document.get_document("o")?;
+    /// Return a delete operation for a given entry.
+    fn from_delete(entry: &Entry) -> Result<Operation> {
+        let ts = entry.get_timestamp("ts")?;
+        let ns = entry.get_str("ns")?;
+        let o = entry.get_payload("o")?;
 
         Ok(Operation::Delete {
             timestamp: timestamp_to_datetime(ts),
             namespace: ns.into(),
-            query: o.to_owned(),
+            query: o,
         })
     }
 
-    /// Return a command operation for a given document.
+    /// Return a command operation for a given entry.
     ///
     /// Note that this can return either an `Operation::Command` or an `Operation::ApplyOps` when
     /// successful.
-    fn from_command(document: &Document) -> Result<Operation> {
-        let ts = document.get_timestamp("ts")?;
-        let ns = document.get_str("ns")?;
-        let o = document.get_document("o")?;
+    fn from_command(entry: &Entry) -> Result<Operation> {
+        let ts = entry.get_timestamp("ts")?;
+        let ns = entry.get_str("ns")?;
+        let o = entry.get_payload("o")?;
 
-        match o.get_array("applyOps") {
-            Ok(ops) => {
+        match o.get("applyOps") {
+            Some(Bson::Array(ops)) => {
                 let operations = ops
                     .iter()
-                    .map(|bson| Operation::from_bson(bson))
+                    .enumerate()
+                    .map(|(i, bson)| {
+                        let path = format!("{}.applyOps[{}]", entry.path("o"), i);
+
+                        match *bson {
+                            Bson::Document(ref document) => {
+                                let mut inner = Entry::new(document, path);
+                                inner.projected = entry.projected;
+
+                                Operation::from_entry(&inner)
+                            }
+                            _ => Err(entry.error_at(
+                                path,
+                                ParseErrorKind::InvalidType {
+                                    expected: ElementType::EmbeddedDocument,
+                                    found: bson.element_type(),
+                                },
+                            )),
+                        }
+                    })
                     .collect::<Result<Vec<Operation>>>()?;
 
                 Ok(Operation::ApplyOps {
                     timestamp: timestamp_to_datetime(ts),
                     namespace: ns.into(),
-                    operations: operations,
+                    operations,
+                    partial: o.get_bool("partialTxn").unwrap_or(false),
+                    prepare: o.get_bool("prepare").unwrap_or(false),
+                    previous: match entry.document.get("prevOpTime") {
+                        Some(Bson::Document(ref optime)) => previous_optime(optime),
+                        _ => None,
+                    },
                 })
             }
-            Err(_) => Ok(Operation::Command {
+            _ => Ok(Operation::Command {
                 timestamp: timestamp_to_datetime(ts),
                 namespace: ns.into(),
-                command: o.to_owned(),
+                command: o,
             }),
         }
     }
 }
 
+/// An oplog entry being converted, along with the context needed to report errors.
+pub(crate) struct Entry<'a> {
+    /// The BSON document of the entry.
+    document: &'a Document,
+    /// The path to this entry from the top-level entry (e.g. `o.applyOps[3]`), empty for the
+    /// top-level entry itself.
+    prefix: String,
+    /// Whether the entry was read with a projection, so its `o` and `o2` fields may be missing.
+    projected: bool,
+}
+
+impl<'a> Entry<'a> {
+    pub(crate) fn new(document: &'a Document, prefix: String) -> Entry<'a> {
+        Entry {
+            document,
+            prefix,
+            projected: false,
+        }
+    }
+
+    /// Returns the full path to a field of this entry.
+    fn path(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.into()
+        } else {
+            format!("{}.{}", self.prefix, key)
+        }
+    }
+
+    /// Returns an error for a field of this entry.
+    fn error(&self, key: &str, kind: ParseErrorKind) -> Error {
+        self.error_at(self.path(key), kind)
+    }
+
+    /// Returns an error for the given path within this entry.
+    fn error_at(&self, path: String, kind: ParseErrorKind) -> Error {
+        Error::Parse(ParseError {
+            kind,
+            path,
+            op: self.document.get_str("op").ok().map(|s| s.to_string()),
+            timestamp: self.document.get_timestamp("ts").ok(),
+            raw: None,
+        })
+    }
+
+    /// Returns a field of this entry converted with `f`, or an error if it is missing or `f`
+    /// returns `None` as it has the wrong type.
+    fn get<T, F>(&self, key: &str, expected: ElementType, f: F) -> Result<T>
+    where
+        F: FnOnce(&'a Bson) -> Option<T>,
+    {
+        match self.document.get(key) {
+            None => Err(self.error(key, ParseErrorKind::MissingField { expected })),
+            Some(bson) => f(bson).ok_or_else(|| {
+                let found = bson.element_type();
+
+                self.error(key, ParseErrorKind::InvalidType { expected, found })
+            }),
+        }
+    }
+
+    fn get_str(&self, key: &str) -> Result<&'a str> {
+        self.get(key, ElementType::String, Bson::as_str)
+    }
+
+    pub(crate) fn get_timestamp(&self, key: &str) -> Result<bson::Timestamp> {
+        self.get(key, ElementType::Timestamp, Bson::as_timestamp)
+    }
+
+    fn get_document(&self, key: &str) -> Result<&'a Document> {
+        self.get(key, ElementType::EmbeddedDocument, Bson::as_document)
+    }
+
+    /// Returns a copy of a document field such as `o`, or an empty document if it is missing
+    /// from an entry read with a projection.
+    fn get_payload(&self, key: &str) -> Result<Document> {
+        if self.projected && !self.document.contains_key(key) {
+            return Ok(Document::new());
+        }
+
+        self.get_document(key).cloned()
+    }
+}
+
 impl fmt::Display for Operation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -258,6 +644,7 @@ impl fmt::Display for Operation {
                 timestamp,
                 ref namespace,
                 ref operations,
+                ..
             } => {
                 write!(
                     f,
@@ -267,16 +654,60 @@ impl fmt::Display for Operation {
                     operations.len()
                 )
             }
+            Operation::Unknown { timestamp, ref raw } => {
+                write!(f, "Unknown operation at {}: {}", timestamp, raw)
+            }
         }
     }
 }
 
+/// Returns the encoded size of a BSON document in bytes.
+pub(crate) fn document_size(document: &Document) -> u64 {
+    struct Counter(u64);
+
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    let _ = document.to_writer(&mut counter);
+
+    counter.0
+}
+
+/// Reads the `prevOpTime` of a transaction entry, which is null (a zero timestamp) for the first
+/// entry of a transaction.
+pub(crate) fn previous_optime(document: &Document) -> Option<OpTime> {
+    OpTime::from_document(document).ok().filter(|optime| {
+        optime.timestamp
+            != bson::Timestamp {
+                time: 0,
+                increment: 0,
+            }
+    })
+}
+
+/// Convert a UTC `DateTime` created by `timestamp_to_datetime` back into a BSON timestamp.
+pub(crate) fn datetime_to_timestamp(datetime: DateTime<Utc>) -> bson::Timestamp {
+    bson::Timestamp {
+        time: datetime.timestamp() as u32,
+        increment: datetime.timestamp_subsec_nanos(),
+    }
+}
+
 /// Convert a BSON timestamp into a UTC `DateTime`.
-fn timestamp_to_datetime(timestamp: bson::Timestamp) -> DateTime<Utc> {
+pub(crate) fn timestamp_to_datetime(timestamp: bson::Timestamp) -> DateTime<Utc> {
     let seconds = timestamp.time;
     let nanoseconds = timestamp.increment;
 
-    Utc.timestamp(seconds as i64, nanoseconds)
+    Utc.timestamp_opt(seconds as i64, nanoseconds).unwrap()
 }
 
 #[cfg(test)]
@@ -303,7 +734,7 @@ mod tests {
         assert_eq!(
             operation,
             Operation::Noop {
-                timestamp: Utc.timestamp(1479419535, 0),
+                timestamp: Utc.timestamp_opt(1479419535, 0).unwrap(),
                 message: Some("initiating set".into()),
             }
         );
@@ -328,7 +759,7 @@ mod tests {
         assert_eq!(
             operation,
             Operation::Insert {
-                timestamp: Utc.timestamp(1479561394, 0),
+                timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
                 namespace: "foo.bar".into(),
                 document: doc! { "foo" : "bar" },
             }
@@ -359,7 +790,7 @@ mod tests {
         assert_eq!(
             operation,
             Operation::Update {
-                timestamp: Utc.timestamp(1479561033, 0),
+                timestamp: Utc.timestamp_opt(1479561033, 0).unwrap(),
                 namespace: "foo.bar".into(),
                 query: doc! { "_id" : 1 },
                 update: doc! { "$set" : { "foo" : "baz" } },
@@ -386,7 +817,7 @@ mod tests {
         assert_eq!(
             operation,
             Operation::Delete {
-                timestamp: Utc.timestamp(1479421186, 0),
+                timestamp: Utc.timestamp_opt(1479421186, 0).unwrap(),
                 namespace: "foo.bar".into(),
                 query: doc! { "_id" : 1 },
             }
@@ -412,7 +843,7 @@ mod tests {
         assert_eq!(
             operation,
             Operation::Command {
-                timestamp: Utc.timestamp(1479553955, 0),
+                timestamp: Utc.timestamp_opt(1479553955, 0).unwrap(),
                 namespace: "test.$cmd".into(),
                 command: doc! { "create" : "foo" },
             }
@@ -425,24 +856,132 @@ mod tests {
         let operation = Operation::new(&doc);
 
         match operation {
-            Err(Error::UnknownOperation(op)) => assert_eq!(op, "x"),
+            Err(Error::Parse(err)) => {
+                assert_eq!(err.kind, ParseErrorKind::UnknownOperation("x".into()));
+                assert_eq!(err.path, "op");
+            }
             _ => panic!("Expected unknown operation."),
         }
     }
 
     #[test]
     fn operation_returns_missing_fields() {
-        use bson::document::ValueAccessError;
-
         let doc = doc! { "foo" : "bar" };
         let operation = Operation::new(&doc);
 
         match operation {
-            Err(Error::MissingField(err)) => assert_eq!(err, ValueAccessError::NotPresent),
+            Err(Error::Parse(err)) => {
+                assert_eq!(
+                    err.kind,
+                    ParseErrorKind::MissingField {
+                        expected: ElementType::String
+                    }
+                );
+                assert_eq!(err.path, "op");
+                assert_eq!(err.op, None);
+            }
+            _ => panic!("Expected missing field."),
+        }
+    }
+
+    #[test]
+    fn operation_new_projected_tolerates_missing_documents() {
+        let ts = bson::Timestamp {
+            time: 1479561394,
+            increment: 0,
+        };
+        let doc = doc! {
+            "ts" : ts,
+            "op" : "c",
+            "ns" : "admin.$cmd",
+            "o" : {
+                "applyOps" : [
+                    { "ts" : ts, "op" : "u", "ns" : "foo.bar", "o2" : { "_id" : 1 } },
+                    { "ts" : ts, "op" : "d", "ns" : "foo.bar" },
+                ]
+            }
+        };
+        let operation = Operation::new_projected(&doc).unwrap();
+
+        assert_eq!(
+            operation,
+            Operation::ApplyOps {
+                timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                namespace: "admin.$cmd".into(),
+                operations: vec![
+                    Operation::Update {
+                        timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                        namespace: "foo.bar".into(),
+                        query: doc! { "_id" : 1 },
+                        update: doc! {},
+                    },
+                    Operation::Delete {
+                        timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                        namespace: "foo.bar".into(),
+                        query: doc! {},
+                    },
+                ],
+                partial: false,
+                prepare: false,
+                previous: None,
+            }
+        );
+    }
+
+    #[test]
+    fn operation_new_projected_still_requires_namespaces() {
+        let ts = bson::Timestamp {
+            time: 1479561394,
+            increment: 0,
+        };
+        let doc = doc! { "ts" : ts, "op" : "i", "o" : { "_id" : 1 } };
+
+        match Operation::new_projected(&doc) {
+            Err(Error::Parse(err)) => assert_eq!(err.path, "ns"),
             _ => panic!("Expected missing field."),
         }
     }
 
+    #[test]
+    fn operation_returns_field_paths_within_apply_ops() {
+        let ts = bson::Timestamp {
+            time: 1483789052,
+            increment: 0,
+        };
+        let doc = doc! {
+            "ts" : ts,
+            "op" : "c",
+            "ns" : "foo.$cmd",
+            "o" : {
+                "applyOps" : [
+                    { "ts" : ts, "op" : "n", "ns" : "" },
+                    { "ts" : ts, "op" : "i", "ns" : 1, "o" : {} }
+                ]
+            }
+        };
+
+        match Operation::new(&doc) {
+            Err(Error::Parse(err)) => {
+                assert_eq!(
+                    err.kind,
+                    ParseErrorKind::InvalidType {
+                        expected: ElementType::String,
+                        found: ElementType::Int32,
+                    }
+                );
+                assert_eq!(err.path, "o.applyOps[1].ns");
+                assert_eq!(err.op, Some("i".into()));
+                assert_eq!(err.timestamp, Some(ts));
+                assert_eq!(
+                    err.to_string(),
+                    "Invalid field o.applyOps[1].ns (expected String, found Int32) \
+                     in \"i\" entry at Timestamp(1483789052, 0)"
+                );
+            }
+            _ => panic!("Expected invalid field."),
+        }
+    }
+
     #[test]
     fn operation_returns_apply_ops() {
         let doc = doc! {
@@ -475,14 +1014,176 @@ mod tests {
         assert_eq!(
             operation,
             Operation::ApplyOps {
-                timestamp: Utc.timestamp(1483789052, 0),
+                timestamp: Utc.timestamp_opt(1483789052, 0).unwrap(),
                 namespace: "foo.$cmd".into(),
                 operations: vec![Operation::Insert {
-                    timestamp: Utc.timestamp(1479561394, 0),
+                    timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
                     namespace: "foo.bar".into(),
                     document: doc! { "_id" : 1, "foo" : "bar" },
                 }],
+                partial: false,
+                prepare: false,
+                previous: None,
+            }
+        );
+    }
+
+    #[test]
+    fn operation_returns_timestamp_and_namespace() {
+        let insert = Operation::Insert {
+            timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+            namespace: "foo.bar".into(),
+            document: doc! { "foo" : "bar" },
+        };
+        let noop = Operation::Noop {
+            timestamp: Utc.timestamp_opt(1479419535, 0).unwrap(),
+            message: None,
+        };
+
+        assert_eq!(
+            insert.timestamp(),
+            Utc.timestamp_opt(1479561394, 0).unwrap()
+        );
+        assert_eq!(insert.namespace(), Some("foo.bar"));
+        assert_eq!(noop.namespace(), None);
+    }
+
+    #[test]
+    fn operation_falls_back_to_unknown_when_lenient() {
+        let doc = doc! {
+            "ts" : Bson::Timestamp(bson::Timestamp {
+                time: 1479561394 ,
+                increment: 0,
+            }),
+            "op" : "u",
+            "ns" : "foo.bar",
+            "o" : {
+                "$set" : { "foo" : "baz" }
+            }
+        };
+        let operation = Operation::new_lenient(&doc).unwrap();
+
+        assert_eq!(
+            operation,
+            Operation::Unknown {
+                timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                raw: doc,
+            }
+        );
+        assert_eq!(operation.namespace(), Some("foo.bar"));
+    }
+
+    #[test]
+    fn operation_lenient_still_requires_timestamps() {
+        let doc = doc! { "op" : "x" };
+
+        match Operation::new_lenient(&doc) {
+            Err(Error::Parse(err)) => {
+                assert_eq!(err.kind, ParseErrorKind::UnknownOperation("x".into()))
+            }
+            _ => panic!("Expected unknown operation."),
+        }
+    }
+
+    #[test]
+    fn operation_converts_back_to_documents() {
+        let doc = doc! {
+            "ts" : Bson::Timestamp(bson::Timestamp {
+                time: 1483789052 ,
+                increment: 3,
+            }),
+            "op" : "c",
+            "ns" : "foo.$cmd",
+            "o" : {
+                "applyOps" : [
+                    {
+                        "ts" : Bson::Timestamp(bson::Timestamp {
+                            time: 1483789052 ,
+                            increment: 3,
+                        }),
+                        "op" : "u",
+                        "ns" : "foo.bar",
+                        "o2" : { "_id" : 1 },
+                        "o" : { "$set" : { "foo" : "baz" } }
+                    }
+                ]
+            }
+        };
+        let operation = Operation::new(&doc).unwrap();
+
+        assert_eq!(operation.to_document(), doc);
+        assert_eq!(
+            operation.optime().timestamp,
+            bson::Timestamp {
+                time: 1483789052,
+                increment: 3,
             }
         );
     }
+
+    #[test]
+    fn operation_keeps_transaction_chains() {
+        let ts = bson::Timestamp {
+            time: 1483789052,
+            increment: 4,
+        };
+        let previous = bson::Timestamp {
+            time: 1483789052,
+            increment: 3,
+        };
+        let doc = doc! {
+            "ts" : ts,
+            "op" : "c",
+            "ns" : "admin.$cmd",
+            "o" : {
+                "applyOps" : [
+                    { "ts" : ts, "op" : "i", "ns" : "foo.bar", "o" : { "_id" : 1 } }
+                ],
+                "prepare" : true
+            },
+            "prevOpTime" : { "ts" : previous, "t" : 2_i64 }
+        };
+        let operation = Operation::new(&doc).unwrap();
+
+        match operation {
+            Operation::ApplyOps {
+                partial,
+                prepare,
+                previous: Some(optime),
+                ..
+            } => {
+                assert!(!partial);
+                assert!(prepare);
+                assert_eq!(optime.timestamp, previous);
+                assert_eq!(optime.term, Some(2));
+            }
+            _ => panic!("Expected a prepared applyOps chained to a previous entry."),
+        }
+        assert_eq!(operation.to_document(), doc);
+    }
+
+    #[test]
+    fn operation_ignores_null_previous_optimes() {
+        let ts = bson::Timestamp {
+            time: 1483789052,
+            increment: 4,
+        };
+        let doc = doc! {
+            "ts" : ts,
+            "op" : "c",
+            "ns" : "admin.$cmd",
+            "o" : { "applyOps" : [], "partialTxn" : true },
+            "prevOpTime" : { "ts" : bson::Timestamp { time: 0, increment: 0 }, "t" : -1_i64 }
+        };
+
+        match Operation::new(&doc).unwrap() {
+            Operation::ApplyOps {
+                partial, previous, ..
+            } => {
+                assert!(partial);
+                assert_eq!(previous, None);
+            }
+            _ => panic!("Expected applyOps."),
+        }
+    }
 }
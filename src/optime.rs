@@ -0,0 +1,68 @@
+//! The optime module identifies individual entries in the oplog so that they can be looked up
+//! again later, e.g. to check they have not been rolled back.
+
+use crate::oper::{datetime_to_timestamp, Entry};
+use crate::Result;
+use bson::{Bson, Document};
+use chrono::{DateTime, Utc};
+use mongodb::bson;
+
+/// The position of an entry in the oplog as recorded by the replica set.
+///
+/// The timestamp alone orders entries but the same timestamp can be reused by a new primary after
+/// a rollback, so the election term (`t`) and, on servers before MongoDB 4.2, the entry hash (`h`)
+/// are kept to tell such entries apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OpTime {
+    /// The timestamp of the entry (`ts`).
+    pub timestamp: bson::Timestamp,
+    /// The election term the entry was written in (`t`), if present.
+    pub term: Option<i64>,
+    /// The unique identifier of the entry (`h`), if present.
+    pub hash: Option<i64>,
+}
+
+impl OpTime {
+    /// Reads the optime of a raw oplog entry.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use oplog::bson::{doc, Timestamp};
+    /// use oplog::OpTime;
+    ///
+    /// let ts = Timestamp { time: 1479561394, increment: 1 };
+    /// let optime = OpTime::from_document(&doc! { "ts": ts, "t": 2_i64, "op": "n" }).unwrap();
+    ///
+    /// assert_eq!(optime.timestamp, ts);
+    /// assert_eq!(optime.term, Some(2));
+    /// assert_eq!(optime.hash, None);
+    /// ```
+    pub fn from_document(document: &Document) -> Result<OpTime> {
+        Ok(OpTime {
+            timestamp: Entry::new(document, String::new()).get_timestamp("ts")?,
+            term: document.get("t").and_then(as_i64),
+            hash: document.get("h").and_then(as_i64),
+        })
+    }
+}
+
+impl From<DateTime<Utc>> for OpTime {
+    /// Returns the optime for the timestamp of an `Operation`, without a term or hash.
+    fn from(datetime: DateTime<Utc>) -> OpTime {
+        OpTime {
+            timestamp: datetime_to_timestamp(datetime),
+            term: None,
+            hash: None,
+        }
+    }
+}
+
+/// Returns any BSON integer as an `i64`.
+pub(crate) fn as_i64(bson: &Bson) -> Option<i64> {
+    match *bson {
+        Bson::Int64(n) => Some(n),
+        Bson::Int32(n) => Some(n.into()),
+        _ => None,
+    }
+}
@@ -0,0 +1,252 @@
+//! The pace module replays operations at the speed they originally happened, or a multiple of
+//! it, for load testing with production traffic.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::{Operation, Result};
+use futures::task::AtomicWaker;
+use futures::{ready, FutureExt, Stream, StreamExt};
+use futures_timer::Delay;
+
+/// A stream adapter delaying operations according to the gaps between their timestamps.
+///
+/// The first operation is yielded straight away and each one after it once the time between its
+/// timestamp and the previous one's has passed, divided by the speed. Gaps are measured from
+/// when each operation was due rather than when it was yielded, so a consumer that falls behind
+/// catches up. As oplog timestamps only have a resolution of one second, operations within the
+/// same second are yielded together.
+///
+/// A `PaceControl` can pause and resume the replay; on resuming, gaps are measured afresh so
+/// time spent paused is not made up for.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use futures::StreamExt;
+/// use mongodb::Client;
+/// use oplog::{Oplog, Paced};
+///
+/// # async fn run() -> Result<(), oplog::Error> {
+/// let client = Client::with_uri_str("mongodb://localhost").await?;
+/// let oplog = Oplog::new(&client).await?;
+///
+/// let mut operations = Paced::new(oplog)
+///     .speed(4.0)
+///     .max_gap(Duration::from_secs(5));
+/// let control = operations.control();
+///
+/// while let Some(res) = operations.next().await {
+///     println!("{:?}", res?);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Paced<S> {
+    stream: S,
+    speed: f64,
+    max_gap: Option<Duration>,
+    control: PaceControl,
+    pending: Option<(Operation, Instant)>,
+    last: Option<(u32, Instant)>,
+    delay: Option<Delay>,
+    paused: bool,
+}
+
+impl<S> Paced<S>
+where
+    S: Stream<Item = Result<Operation>> + Unpin,
+{
+    /// Paces operations at their original speed with no limit on gaps.
+    pub fn new(stream: S) -> Paced<S> {
+        Paced {
+            stream,
+            speed: 1.0,
+            max_gap: None,
+            control: PaceControl::default(),
+            pending: None,
+            last: None,
+            delay: None,
+            paused: false,
+        }
+    }
+
+    /// Sets the multiple of the original speed to replay at, e.g. `2.0` for twice as fast.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the speed is not positive.
+    pub fn speed(mut self, speed: f64) -> Self {
+        assert!(speed > 0.0, "speed must be positive");
+        self.speed = speed;
+        self
+    }
+
+    /// Sets the longest wait between operations, after scaling by the speed.
+    pub fn max_gap(mut self, max_gap: Duration) -> Self {
+        self.max_gap = Some(max_gap);
+        self
+    }
+
+    /// Returns a handle for pausing and resuming the replay.
+    pub fn control(&self) -> PaceControl {
+        self.control.clone()
+    }
+
+    /// Returns when an operation read at the given time with the given timestamp is due.
+    fn due(&self, time: u32, now: Instant) -> Instant {
+        match self.last {
+            Some((last_time, last_due)) => {
+                let seconds = f64::from(time.saturating_sub(last_time)) / self.speed;
+                let mut gap = Duration::from_secs_f64(seconds);
+                if let Some(max_gap) = self.max_gap {
+                    gap = gap.min(max_gap);
+                }
+
+                last_due + gap
+            }
+            None => now,
+        }
+    }
+}
+
+impl<S> Stream for Paced<S>
+where
+    S: Stream<Item = Result<Operation>> + Unpin,
+{
+    type Item = Result<Operation>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.control.inner.paused.load(Ordering::SeqCst) {
+                self.control.inner.waker.register(cx.waker());
+
+                if self.control.inner.paused.load(Ordering::SeqCst) {
+                    self.paused = true;
+                    self.delay = None;
+
+                    return Poll::Pending;
+                }
+            }
+
+            if self.paused {
+                // Measure gaps afresh on resuming.
+                self.paused = false;
+                self.last = None;
+                if let Some(pending) = self.pending.as_mut() {
+                    pending.1 = Instant::now();
+                }
+            }
+
+            let (time, due) = match self.pending {
+                Some((ref operation, due)) => (operation.optime().timestamp.time, due),
+                None => match ready!(self.stream.poll_next_unpin(cx)) {
+                    Some(Ok(operation)) => {
+                        let time = operation.optime().timestamp.time;
+                        let due = self.due(time, Instant::now());
+                        self.pending = Some((operation, due));
+                        continue;
+                    }
+                    other => return Poll::Ready(other),
+                },
+            };
+
+            let now = Instant::now();
+            if due > now {
+                let delay = self.delay.get_or_insert_with(|| Delay::new(due - now));
+                ready!(delay.poll_unpin(cx));
+            }
+
+            self.delay = None;
+            self.last = Some((time, due));
+
+            let (operation, _) = self.pending.take().expect("pending operation");
+
+            return Poll::Ready(Some(Ok(operation)));
+        }
+    }
+}
+
+/// A handle for pausing and resuming a `Paced` replay.
+#[derive(Clone, Debug, Default)]
+pub struct PaceControl {
+    inner: Arc<ControlState>,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    paused: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl PaceControl {
+    /// Stops yielding operations until resumed.
+    pub fn pause(&self) {
+        self.inner.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Carries on yielding operations.
+    pub fn resume(&self) {
+        self.inner.paused.store(false, Ordering::SeqCst);
+        self.inner.waker.wake();
+    }
+
+    /// Returns true if the replay is paused.
+    pub fn is_paused(&self) -> bool {
+        self.inner.paused.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+    use futures::stream;
+    use mongodb::bson;
+
+    fn insert(time: i64) -> Result<Operation> {
+        Ok(Operation::Insert {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: "foo.bar".into(),
+            document: doc! { "_id": time },
+        })
+    }
+
+    #[test]
+    fn paced_delays_operations_by_scaled_gaps() {
+        let operations = stream::iter(vec![insert(0), insert(1), insert(2)]);
+        let paced = Paced::new(operations).speed(50.0);
+        let start = Instant::now();
+
+        assert_eq!(block_on(paced.collect::<Vec<_>>()).len(), 3);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn paced_clamps_gaps() {
+        let operations = stream::iter(vec![insert(0), insert(3600)]);
+        let paced = Paced::new(operations).max_gap(Duration::from_millis(10));
+        let start = Instant::now();
+
+        assert_eq!(block_on(paced.collect::<Vec<_>>()).len(), 2);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn paced_waits_while_paused() {
+        let mut paced = Paced::new(stream::iter(vec![insert(0)]));
+        let control = paced.control();
+
+        control.pause();
+        assert!(paced.next().now_or_never().is_none());
+
+        control.resume();
+        assert!(block_on(paced.next()).is_some());
+    }
+}
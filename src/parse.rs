@@ -0,0 +1,63 @@
+//! The parse module controls what an `Oplog` does with entries that cannot be converted into an
+//! `Operation`.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::Error;
+use bson::Document;
+use mongodb::bson;
+
+/// How an `Oplog` handles oplog entries that cannot be converted into an `Operation`.
+#[derive(Clone, Default)]
+pub enum ParseMode {
+    /// Yield an error for the entry. This is the default.
+    #[default]
+    Strict,
+    /// Yield an `Operation::Unknown` holding the raw entry, as per `Operation::new_lenient`.
+    Lenient,
+    /// Skip the entry, emitting a debug event if the `tracing` feature is enabled.
+    Skip,
+    /// Skip the entry, handing it and the reason it could not be parsed to a sink.
+    DeadLetter(Arc<dyn DeadLetter>),
+}
+
+impl fmt::Debug for ParseMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseMode::Strict => write!(f, "Strict"),
+            ParseMode::Lenient => write!(f, "Lenient"),
+            ParseMode::Skip => write!(f, "Skip"),
+            ParseMode::DeadLetter(_) => write!(f, "DeadLetter"),
+        }
+    }
+}
+
+/// A destination for oplog entries that could not be parsed.
+///
+/// This is implemented for any closure taking the raw entry and the error so that, for instance,
+/// entries can be written to a file or collection for later inspection.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use oplog::ParseMode;
+///
+/// let mode = ParseMode::DeadLetter(Arc::new(|document, error| {
+///     eprintln!("Skipping {}: {}", document, error);
+/// }));
+/// ```
+pub trait DeadLetter: Send + Sync {
+    /// Receives an oplog entry along with the reason it could not be parsed.
+    fn send(&self, document: Document, error: Error);
+}
+
+impl<F> DeadLetter for F
+where
+    F: Fn(Document, Error) + Send + Sync,
+{
+    fn send(&self, document: Document, error: Error) {
+        self(document, error)
+    }
+}
@@ -0,0 +1,334 @@
+//! The partition module applies operations concurrently across workers while keeping operations
+//! on the same document in order, for replicating the oplog into other stores.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use crate::{OpTime, Operation, Result};
+use futures::channel::mpsc;
+use futures::future::{try_join, try_join_all};
+use futures::{SinkExt, Stream, StreamExt};
+
+/// Applies operations with a number of concurrent workers, partitioned by document.
+///
+/// Each operation on a single document is assigned to a worker by a hash of its namespace and
+/// `_id`, so operations on the same document are applied in oplog order while those on different
+/// documents may be applied concurrently. Commands (e.g. DDL such as `drop` or `createIndexes`)
+/// and `applyOps` transactions are barriers: every operation before them is applied first, then
+/// they are applied alone, then work carries on. No-ops are not applied.
+///
+/// The workers are futures polled by `run` rather than tasks of their own, so the apply function
+/// should be asynchronous I/O (e.g. writes to another database) or hand CPU-bound work off to a
+/// thread pool.
+///
+/// As operations complete out of order, `Watermark` tracks the newest position before which
+/// everything has been applied, which is safe to checkpoint.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use mongodb::Client;
+/// use oplog::{Oplog, Operation, PartitionedExecutor};
+///
+/// # async fn run() -> Result<(), oplog::Error> {
+/// let client = Client::with_uri_str("mongodb://localhost").await?;
+/// let oplog = Oplog::new(&client).await?;
+///
+/// let mut executor = PartitionedExecutor::new(8, |operation: Operation| async move {
+///     println!("{:?}", operation);
+///     Ok(())
+/// });
+/// let watermark = executor.watermark();
+///
+/// executor.run(oplog).await?;
+/// println!("Applied up to {:?}", watermark.get());
+/// # Ok(())
+/// # }
+/// ```
+pub struct PartitionedExecutor<F> {
+    apply: F,
+    workers: usize,
+    queue_size: usize,
+    tracker: Arc<Mutex<Tracker>>,
+}
+
+impl<F, Fut> PartitionedExecutor<F>
+where
+    F: Fn(Operation) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    /// Creates an executor applying operations with the given function across a number of
+    /// workers, each queueing up to 100 operations.
+    pub fn new(workers: usize, apply: F) -> PartitionedExecutor<F> {
+        PartitionedExecutor {
+            apply,
+            workers: workers.max(1),
+            queue_size: 100,
+            tracker: Arc::default(),
+        }
+    }
+
+    /// Sets how many operations may be queued for each worker before reading from the stream
+    /// waits for it to catch up.
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size.max(1);
+        self
+    }
+
+    /// Returns a handle to the low watermark of applied operations.
+    pub fn watermark(&self) -> Watermark {
+        Watermark {
+            tracker: Arc::clone(&self.tracker),
+        }
+    }
+
+    /// Applies every operation in the stream until it ends or an error occurs, either reading the
+    /// stream or applying an operation.
+    ///
+    /// On error, operations already queued are abandoned; the watermark still only covers
+    /// operations that were applied.
+    pub async fn run<St>(&mut self, stream: St) -> Result<()>
+    where
+        St: Stream<Item = Result<Operation>>,
+    {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..self.workers)
+            .map(|_| mpsc::channel::<(u64, Operation)>(self.queue_size - 1))
+            .unzip();
+        let (done_sender, done) = mpsc::unbounded();
+
+        let apply = &self.apply;
+        let tracker = &self.tracker;
+
+        let workers = receivers.into_iter().map(|mut receiver| {
+            let done_sender = done_sender.clone();
+
+            async move {
+                while let Some((sequence, operation)) = receiver.next().await {
+                    apply(operation).await?;
+                    tracker.lock().unwrap().complete(sequence);
+                    let _ = done_sender.unbounded_send(());
+                }
+
+                Ok(())
+            }
+        });
+
+        let dispatcher = dispatch(stream, senders, done, apply, tracker);
+
+        try_join(dispatcher, try_join_all(workers)).await?;
+
+        Ok(())
+    }
+}
+
+/// Reads operations from the stream and hands them to the workers, applying barriers itself.
+async fn dispatch<St, F, Fut>(
+    stream: St,
+    mut senders: Vec<mpsc::Sender<(u64, Operation)>>,
+    mut done: mpsc::UnboundedReceiver<()>,
+    apply: &F,
+    tracker: &Mutex<Tracker>,
+) -> Result<()>
+where
+    St: Stream<Item = Result<Operation>>,
+    F: Fn(Operation) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    futures::pin_mut!(stream);
+
+    while let Some(res) = stream.next().await {
+        let operation = res?;
+        let sequence = tracker.lock().unwrap().dispatch(operation.optime());
+
+        // Only completions while waiting on a barrier matter, so discard the rest.
+        while done.try_recv().is_ok() {}
+
+        match partition(&operation, senders.len()) {
+            Partition::Skip => tracker.lock().unwrap().complete(sequence),
+            Partition::Barrier => {
+                while tracker.lock().unwrap().in_flight() > 1 {
+                    done.next().await;
+                }
+
+                apply(operation).await?;
+                tracker.lock().unwrap().complete(sequence);
+            }
+            Partition::Worker(worker) => {
+                // A worker only hangs up if it has failed, which `run` will return.
+                if senders[worker].send((sequence, operation)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// How an operation is applied.
+#[derive(Debug, PartialEq)]
+enum Partition {
+    /// Not at all.
+    Skip,
+    /// Alone, once everything before it has been applied.
+    Barrier,
+    /// By the worker at the given index.
+    Worker(usize),
+}
+
+fn partition(operation: &Operation, workers: usize) -> Partition {
+    match *operation {
+        Operation::Noop { .. } => Partition::Skip,
+        Operation::Command { .. } | Operation::ApplyOps { .. } | Operation::Unknown { .. } => {
+            Partition::Barrier
+        }
+        _ => {
+            let mut hasher = DefaultHasher::new();
+            match operation.document_key() {
+                Some(key) => key.hash(&mut hasher),
+                None => operation.namespace().hash(&mut hasher),
+            }
+
+            Partition::Worker((hasher.finish() % workers as u64) as usize)
+        }
+    }
+}
+
+/// A handle to the newest position before which every operation has been applied by a
+/// `PartitionedExecutor`.
+#[derive(Clone)]
+pub struct Watermark {
+    tracker: Arc<Mutex<Tracker>>,
+}
+
+impl Watermark {
+    /// Returns the position of the newest operation which, along with every operation before it,
+    /// has been applied, if any.
+    pub fn get(&self) -> Option<OpTime> {
+        self.tracker.lock().unwrap().watermark
+    }
+}
+
+/// Tracks operations in flight in the order they were read.
+#[derive(Debug, Default)]
+struct Tracker {
+    next: u64,
+    in_flight: VecDeque<(OpTime, bool)>,
+    watermark: Option<OpTime>,
+}
+
+impl Tracker {
+    /// Records an operation being read, returning its sequence number.
+    fn dispatch(&mut self, optime: OpTime) -> u64 {
+        self.in_flight.push_back((optime, false));
+        self.next += 1;
+
+        self.next - 1
+    }
+
+    /// Records an operation being applied, advancing the watermark past every operation applied
+    /// since the oldest still in flight.
+    fn complete(&mut self, sequence: u64) {
+        let first = self.next - self.in_flight.len() as u64;
+        self.in_flight[(sequence - first) as usize].1 = true;
+
+        while let Some(&(optime, true)) = self.in_flight.front() {
+            self.watermark = Some(optime);
+            self.in_flight.pop_front();
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use bson::doc;
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+    use mongodb::bson;
+    use std::io;
+
+    fn insert(time: i64, id: i32) -> Operation {
+        Operation::Insert {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: "foo.bar".into(),
+            document: doc! { "_id": id },
+        }
+    }
+
+    fn command(time: i64) -> Operation {
+        Operation::Command {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: "foo.$cmd".into(),
+            command: doc! { "drop": "bar" },
+        }
+    }
+
+    fn time(operation: &Operation) -> u32 {
+        operation.optime().timestamp.time
+    }
+
+    #[test]
+    fn partition_keeps_documents_on_one_worker() {
+        assert_eq!(partition(&insert(1, 1), 4), partition(&insert(2, 1), 4));
+        assert_eq!(partition(&command(1), 4), Partition::Barrier);
+    }
+
+    #[test]
+    fn tracker_advances_watermark_past_contiguous_completions() {
+        let mut tracker = Tracker::default();
+        let first = tracker.dispatch(insert(1, 1).optime());
+        let second = tracker.dispatch(insert(2, 2).optime());
+
+        tracker.complete(second);
+        assert_eq!(tracker.watermark, None);
+
+        tracker.complete(first);
+        assert_eq!(tracker.watermark, Some(insert(2, 2).optime()));
+        assert_eq!(tracker.in_flight(), 0);
+    }
+
+    #[test]
+    fn run_applies_barriers_after_earlier_operations() {
+        let applied = Mutex::new(Vec::new());
+        let mut executor = PartitionedExecutor::new(4, |operation: Operation| {
+            applied.lock().unwrap().push(time(&operation));
+            futures::future::ready(Ok(()))
+        });
+        let watermark = executor.watermark();
+        let operations = vec![insert(1, 1), insert(2, 2), command(3), insert(4, 1)];
+
+        block_on(executor.run(futures::stream::iter(operations.into_iter().map(Ok)))).unwrap();
+
+        let applied = applied.into_inner().unwrap();
+        assert_eq!(applied.len(), 4);
+        assert_eq!(applied[2], 3);
+        assert_eq!(watermark.get(), Some(insert(4, 1).optime()));
+    }
+
+    #[test]
+    fn run_stops_on_apply_errors() {
+        let mut executor = PartitionedExecutor::new(2, |operation: Operation| {
+            futures::future::ready(if time(&operation) == 2 {
+                Err(Error::Sink(Box::new(io::Error::other("unavailable"))))
+            } else {
+                Ok(())
+            })
+        });
+        let watermark = executor.watermark();
+        let operations = vec![insert(1, 1), insert(2, 1), insert(3, 1)];
+
+        let result = block_on(executor.run(futures::stream::iter(operations.into_iter().map(Ok))));
+
+        assert!(matches!(result, Err(Error::Sink(_))));
+        assert_eq!(watermark.get(), Some(insert(1, 1).optime()));
+    }
+}
@@ -0,0 +1,553 @@
+//! The postgres module translates operations into parameterised SQL for mirroring collections
+//! into PostgreSQL tables.
+
+use std::collections::HashMap;
+
+use crate::update::Change;
+use crate::{Error, Operation, Result};
+use bson::{Bson, Document};
+use mongodb::bson;
+
+/// A parameterised SQL statement, with `$1`, `$2`, etc. standing for its parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement {
+    /// The SQL of the statement.
+    pub sql: String,
+    /// The values of the parameters, in order.
+    pub params: Vec<SqlValue>,
+}
+
+/// The value of a statement parameter.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    /// `NULL`.
+    Null,
+    /// A `boolean`.
+    Bool(bool),
+    /// A `bigint`, from a BSON 32 or 64-bit integer.
+    Int(i64),
+    /// A `double precision`.
+    Float(f64),
+    /// A `text`, also used for ObjectIds as hex, dates as RFC 3339 and decimals.
+    Text(String),
+    /// A `jsonb`, as relaxed Extended JSON, used for embedded documents, arrays and any other
+    /// BSON type.
+    Json(serde_json::Value),
+    /// A `text[]`, used for JSONB paths.
+    TextArray(Vec<String>),
+}
+
+impl From<&Bson> for SqlValue {
+    fn from(bson: &Bson) -> SqlValue {
+        match *bson {
+            Bson::Null | Bson::Undefined => SqlValue::Null,
+            Bson::Boolean(b) => SqlValue::Bool(b),
+            Bson::Int32(n) => SqlValue::Int(n.into()),
+            Bson::Int64(n) => SqlValue::Int(n),
+            Bson::Double(n) => SqlValue::Float(n),
+            Bson::String(ref s) => SqlValue::Text(s.clone()),
+            Bson::ObjectId(ref id) => SqlValue::Text(id.to_hex()),
+            Bson::Decimal128(ref d) => SqlValue::Text(d.to_string()),
+            Bson::DateTime(ref dt) => match dt.try_to_rfc3339_string() {
+                Ok(s) => SqlValue::Text(s),
+                Err(_) => SqlValue::Json(bson.clone().into_relaxed_extjson()),
+            },
+            _ => SqlValue::Json(bson.clone().into_relaxed_extjson()),
+        }
+    }
+}
+
+/// How the documents of a namespace map onto a table.
+///
+/// Fields, including nested fields given by dotted path (e.g. `address.city`), map onto
+/// columns of their own, and the whole document can be kept in a `jsonb` column. Documents are
+/// keyed by their `_id` in the id column, which must have a unique constraint.
+#[derive(Clone, Debug)]
+pub struct TableMapping {
+    table: String,
+    id_column: String,
+    columns: Vec<(String, String)>,
+    document_column: Option<String>,
+}
+
+impl TableMapping {
+    /// Creates a mapping onto the given table, optionally qualified by schema (e.g.
+    /// `public.orders`), with an id column named `id`.
+    pub fn new(table: &str) -> TableMapping {
+        TableMapping {
+            table: table.to_string(),
+            id_column: "id".to_string(),
+            columns: Vec::new(),
+            document_column: None,
+        }
+    }
+
+    /// Sets the column holding the document `_id`.
+    pub fn id_column(mut self, column: &str) -> Self {
+        self.id_column = column.to_string();
+        self
+    }
+
+    /// Maps a field, given by dotted path, onto a column. Where updates set fields within the
+    /// mapped field, the column must be `jsonb`.
+    pub fn column(mut self, field: &str, column: &str) -> Self {
+        self.columns.push((field.to_string(), column.to_string()));
+        self
+    }
+
+    /// Keeps the whole document in a `jsonb` column.
+    pub fn document_column(mut self, column: &str) -> Self {
+        self.document_column = Some(column.to_string());
+        self
+    }
+}
+
+/// Translates inserts, updates and deletes into SQL statements for PostgreSQL.
+///
+/// Inserts and replacement updates become an `INSERT ... ON CONFLICT ... DO UPDATE` of the mapped
+/// columns. Other updates become an `UPDATE ... SET` of the columns they touch: fields set or
+/// removed (with `$unset`) are set to their new value or `NULL`, and changes within a `jsonb`
+/// column use `jsonb_set` and `#-` with the nested path. Deletes become a `DELETE`. The
+/// operations within an `Operation::ApplyOps` are translated in order, so should be executed in
+/// one transaction. Operations on namespaces without a mapping, commands and no-ops produce no
+/// statements.
+///
+/// As with `jsonb_set`, setting a nested path within a `jsonb` column only creates the last
+/// component of the path if it is missing.
+///
+/// # Example
+///
+/// ```
+/// use oplog::bson::{doc, Timestamp};
+/// use oplog::{Operation, PostgresTranslator, TableMapping};
+///
+/// let translator = PostgresTranslator::new().table(
+///     "shop.orders",
+///     TableMapping::new("orders")
+///         .column("status", "status")
+///         .document_column("doc"),
+/// );
+/// let operation = Operation::new(&doc! {
+///     "ts": Timestamp { time: 1479561394, increment: 0 },
+///     "op": "u",
+///     "ns": "shop.orders",
+///     "o2": { "_id": 1 },
+///     "o": { "$set": { "status": "shipped" } },
+/// }).unwrap();
+///
+/// let statements = translator.translate(&operation).unwrap();
+///
+/// assert_eq!(
+///     statements[0].sql,
+///     r#"UPDATE "orders" SET "status" = $2, "doc" = jsonb_set(COALESCE("doc", '{}'::jsonb), $3::text[], $4::jsonb, true) WHERE "id" = $1"#
+/// );
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PostgresTranslator {
+    tables: HashMap<String, TableMapping>,
+}
+
+impl PostgresTranslator {
+    /// Creates a translator with no mappings.
+    pub fn new() -> PostgresTranslator {
+        PostgresTranslator::default()
+    }
+
+    /// Maps a namespace (e.g. `shop.orders`) onto a table.
+    pub fn table(mut self, namespace: &str, mapping: TableMapping) -> Self {
+        self.tables.insert(namespace.to_string(), mapping);
+        self
+    }
+
+    /// Returns the statements for an operation.
+    ///
+    /// Returns `Error::Unsupported` for updates that cannot be expressed as fields set and
+    /// removed, such as those truncating arrays, and for writes without an `_id` to match rows
+    /// on.
+    pub fn translate(&self, operation: &Operation) -> Result<Vec<Statement>> {
+        let mut statements = Vec::new();
+        self.push_statements(&mut statements, operation)?;
+
+        Ok(statements)
+    }
+
+    fn push_statements(
+        &self,
+        statements: &mut Vec<Statement>,
+        operation: &Operation,
+    ) -> Result<()> {
+        if let Operation::ApplyOps { ref operations, .. } = *operation {
+            for operation in operations {
+                self.push_statements(statements, operation)?;
+            }
+
+            return Ok(());
+        }
+
+        let mapping = match operation.namespace().and_then(|ns| self.tables.get(ns)) {
+            Some(mapping) => mapping,
+            None => return Ok(()),
+        };
+        let id = match (operation.document_id(), operation) {
+            (Some(id), _) => id,
+            (None, Operation::Insert { .. })
+            | (None, Operation::Update { .. })
+            | (None, Operation::Delete { .. }) => {
+                return Err(Error::Unsupported(format!(
+                    "{} of {} without an _id cannot be translated to SQL",
+                    operation.kind(),
+                    operation.namespace().unwrap_or("")
+                )))
+            }
+            (None, _) => return Ok(()),
+        };
+
+        let statement = match *operation {
+            Operation::Insert { ref document, .. } => Some(mapping.upsert(id, document)),
+            Operation::Update { ref update, .. } => match Change::new(update) {
+                Some(Change::Replace(mut document)) => {
+                    if !document.contains_key("_id") {
+                        document.insert("_id", id.clone());
+                    }
+
+                    Some(mapping.upsert(id, &document))
+                }
+                Some(Change::Modify { set, unset }) => mapping.update(id, &set, &unset),
+                None => {
+                    return Err(Error::Unsupported(format!(
+                        "update of {} cannot be translated to SQL",
+                        operation.namespace().unwrap_or("")
+                    )))
+                }
+            },
+            Operation::Delete { .. } => {
+                let mut params = Params::default();
+                let sql = format!(
+                    "DELETE FROM {} WHERE {} = {}",
+                    mapping.quoted_table(),
+                    quote(&mapping.id_column),
+                    params.push(id.into())
+                );
+
+                Some(params.statement(sql))
+            }
+            _ => None,
+        };
+
+        statements.extend(statement);
+
+        Ok(())
+    }
+}
+
+impl TableMapping {
+    fn quoted_table(&self) -> String {
+        self.table
+            .split('.')
+            .map(quote)
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    fn upsert(&self, id: &Bson, document: &Document) -> Statement {
+        let mut params = Params::default();
+        let mut columns = vec![quote(&self.id_column)];
+        let mut values = vec![params.push(id.into())];
+
+        for (field, column) in &self.columns {
+            columns.push(quote(column));
+            values.push(params.push(get_path(document, field).map_or(SqlValue::Null, Into::into)));
+        }
+
+        if let Some(ref column) = self.document_column {
+            columns.push(quote(column));
+            values.push(params.push(SqlValue::Json(
+                Bson::Document(document.clone()).into_relaxed_extjson(),
+            )));
+        }
+
+        let conflict = if columns.len() > 1 {
+            let assignments: Vec<String> = columns[1..]
+                .iter()
+                .map(|column| format!("{} = EXCLUDED.{}", column, column))
+                .collect();
+
+            format!("DO UPDATE SET {}", assignments.join(", "))
+        } else {
+            "DO NOTHING".to_string()
+        };
+
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) {}",
+            self.quoted_table(),
+            columns.join(", "),
+            values.join(", "),
+            columns[0],
+            conflict
+        );
+
+        params.statement(sql)
+    }
+
+    fn update(&self, id: &Bson, set: &[(String, Bson)], unset: &[String]) -> Option<Statement> {
+        let mut params = Params::default();
+        let id = params.push(id.into());
+        let mut assignments = Vec::new();
+
+        for (field, column) in &self.columns {
+            let quoted = quote(column);
+            let mut expression: Option<String> = None;
+
+            for (path, value) in set {
+                if path == field {
+                    expression = Some(params.push(value.into()));
+                } else if let Some(rest) = within(path, field) {
+                    let value = match *value {
+                        Bson::Document(ref document) => get_path(document, rest),
+                        _ => None,
+                    };
+                    expression = Some(params.push(value.map_or(SqlValue::Null, Into::into)));
+                } else if let Some(rest) = within(field, path) {
+                    let target = expression.take().unwrap_or_else(|| quoted.clone());
+                    expression = Some(params.jsonb_set(&target, rest, value));
+                }
+            }
+
+            for path in unset {
+                if path == field || within(path, field).is_some() {
+                    expression = Some("NULL".to_string());
+                } else if let Some(rest) = within(field, path) {
+                    let target = expression.take().unwrap_or_else(|| quoted.clone());
+                    expression = Some(params.jsonb_remove(&target, rest));
+                }
+            }
+
+            if let Some(expression) = expression {
+                assignments.push(format!("{} = {}", quoted, expression));
+            }
+        }
+
+        if let Some(ref column) = self.document_column {
+            let quoted = quote(column);
+            let mut expression = quoted.clone();
+
+            for (path, value) in set {
+                expression = params.jsonb_set(&expression, path, value);
+            }
+
+            for path in unset {
+                expression = params.jsonb_remove(&expression, path);
+            }
+
+            if expression != quoted {
+                assignments.push(format!("{} = {}", quoted, expression));
+            }
+        }
+
+        if assignments.is_empty() {
+            return None;
+        }
+
+        let sql = format!(
+            "UPDATE {} SET {} WHERE {} = {}",
+            self.quoted_table(),
+            assignments.join(", "),
+            quote(&self.id_column),
+            id
+        );
+
+        Some(params.statement(sql))
+    }
+}
+
+/// Collects the parameters of a statement.
+#[derive(Default)]
+struct Params(Vec<SqlValue>);
+
+impl Params {
+    /// Adds a parameter, returning its placeholder.
+    fn push(&mut self, value: SqlValue) -> String {
+        self.0.push(value);
+
+        format!("${}", self.0.len())
+    }
+
+    fn jsonb_set(&mut self, target: &str, path: &str, value: &Bson) -> String {
+        let path = self.push(jsonb_path(path));
+        let value = self.push(SqlValue::Json(value.clone().into_relaxed_extjson()));
+
+        format!(
+            "jsonb_set(COALESCE({}, '{{}}'::jsonb), {}::text[], {}::jsonb, true)",
+            target, path, value
+        )
+    }
+
+    fn jsonb_remove(&mut self, target: &str, path: &str) -> String {
+        format!("{} #- {}::text[]", target, self.push(jsonb_path(path)))
+    }
+
+    fn statement(self, sql: String) -> Statement {
+        Statement {
+            sql,
+            params: self.0,
+        }
+    }
+}
+
+fn jsonb_path(path: &str) -> SqlValue {
+    SqlValue::TextArray(path.split('.').map(String::from).collect())
+}
+
+/// Returns the rest of `path` if it is within `prefix`, e.g. `b.c` for `a.b.c` within `a`.
+fn within<'a>(prefix: &str, path: &'a str) -> Option<&'a str> {
+    path.strip_prefix(prefix)?.strip_prefix('.')
+}
+
+/// Returns the value at a dotted path through embedded documents.
+fn get_path<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    match path.split_once('.') {
+        None => document.get(path),
+        Some((field, rest)) => get_path(document.get_document(field).ok()?, rest),
+    }
+}
+
+/// Quotes an SQL identifier.
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    fn translator() -> PostgresTranslator {
+        PostgresTranslator::new().table(
+            "shop.orders",
+            TableMapping::new("public.orders")
+                .column("status", "status")
+                .column("customer.name", "customer_name")
+                .column("address", "address"),
+        )
+    }
+
+    fn update(update: Document) -> Operation {
+        Operation::Update {
+            timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+            namespace: "shop.orders".into(),
+            query: doc! { "_id": 1 },
+            update,
+        }
+    }
+
+    #[test]
+    fn translate_upserts_inserts() {
+        let operation = Operation::Insert {
+            timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+            namespace: "shop.orders".into(),
+            document: doc! { "_id": 1, "status": "new", "customer": { "name": "Alice" } },
+        };
+        let statements = translator().translate(&operation).unwrap();
+
+        assert_eq!(
+            statements,
+            vec![Statement {
+                sql: r#"INSERT INTO "public"."orders" ("id", "status", "customer_name", "address") VALUES ($1, $2, $3, $4) ON CONFLICT ("id") DO UPDATE SET "status" = EXCLUDED."status", "customer_name" = EXCLUDED."customer_name", "address" = EXCLUDED."address""#.to_string(),
+                params: vec![
+                    SqlValue::Int(1),
+                    SqlValue::Text("new".into()),
+                    SqlValue::Text("Alice".into()),
+                    SqlValue::Null,
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn translate_sets_and_unsets_nested_paths() {
+        let statements = translator()
+            .translate(&update(doc! {
+                "$set": { "customer": { "name": "Bob" }, "address.city": "Leeds" },
+                "$unset": { "status": true },
+            }))
+            .unwrap();
+
+        assert_eq!(
+            statements[0].sql,
+            r#"UPDATE "public"."orders" SET "status" = NULL, "customer_name" = $2, "address" = jsonb_set(COALESCE("address", '{}'::jsonb), $3::text[], $4::jsonb, true) WHERE "id" = $1"#
+        );
+        assert_eq!(
+            statements[0].params[1..],
+            [
+                SqlValue::Text("Bob".into()),
+                SqlValue::TextArray(vec!["city".into()]),
+                SqlValue::Json(json!("Leeds")),
+            ]
+        );
+    }
+
+    #[test]
+    fn translate_removes_paths_from_document_columns() {
+        let translator = PostgresTranslator::new().table(
+            "shop.orders",
+            TableMapping::new("orders").document_column("doc"),
+        );
+        let statements = translator
+            .translate(&update(doc! { "$unset": { "a.b": 1 } }))
+            .unwrap();
+
+        assert_eq!(
+            statements[0].sql,
+            r#"UPDATE "orders" SET "doc" = "doc" #- $2::text[] WHERE "id" = $1"#
+        );
+    }
+
+    #[test]
+    fn translate_deletes_and_ignores_unmapped_namespaces() {
+        let delete = Operation::Delete {
+            timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+            namespace: "shop.orders".into(),
+            query: doc! { "_id": 1 },
+        };
+        let statements = translator().translate(&delete).unwrap();
+
+        assert_eq!(
+            statements[0].sql,
+            r#"DELETE FROM "public"."orders" WHERE "id" = $1"#
+        );
+
+        let unmapped = Operation::Delete {
+            timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+            namespace: "shop.users".into(),
+            query: doc! { "_id": 1 },
+        };
+        assert!(translator().translate(&unmapped).unwrap().is_empty());
+    }
+
+    #[test]
+    fn translate_rejects_updates_without_an_id() {
+        let operation = Operation::Update {
+            timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+            namespace: "shop.orders".into(),
+            query: doc! { "status": "new" },
+            update: doc! { "$set": { "status": "paid" } },
+        };
+
+        assert!(matches!(
+            translator().translate(&operation),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn translate_rejects_array_truncation() {
+        let operation = update(doc! { "$v": 2, "diff": { "stags": { "a": true, "l": 1 } } });
+
+        assert!(matches!(
+            translator().translate(&operation),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
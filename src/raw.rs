@@ -0,0 +1,719 @@
+//! The raw module converts oplog entries into operations that borrow from the raw BSON returned
+//! by the server instead of copying every subdocument into an owned `Document`.
+//!
+//! This avoids most of the cost of parsing for consumers that only look at a few fields of each
+//! operation (e.g. its namespace and `_id`) and lets the rest be converted into an owned
+//! `Operation` only when needed.
+
+use std::convert::TryFrom;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::error::{ParseError, ParseErrorKind};
+use crate::oper::{previous_optime, timestamp_to_datetime};
+use crate::shutdown::StoppableCursor;
+use crate::{instrument, Error, OpTime, Operation, OplogBuilder, Result, ShutdownHandle};
+use bson::raw::{RawArray, RawBsonRef, RawDocument, RawDocumentBuf};
+use bson::spec::ElementType;
+use bson::Document;
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use mongodb::{bson, Collection, Cursor};
+
+/// A MongoDB oplog operation borrowed from a raw BSON oplog entry.
+///
+/// This mirrors `Operation` but holds references into the entry rather than owned copies, so
+/// creating one does not allocate. Use `RawOperation::to_operation` to convert it into an owned
+/// `Operation`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RawOperation<'a> {
+    /// A no-op as inserted periodically by MongoDB or used to initiate new replica sets.
+    Noop {
+        /// The time of the operation.
+        timestamp: DateTime<Utc>,
+        /// The raw oplog entry.
+        raw: &'a RawDocument,
+        /// The message associated with this operation.
+        message: Option<&'a str>,
+    },
+    /// An insert of a document into a specific database and collection.
+    Insert {
+        /// The time of the operation.
+        timestamp: DateTime<Utc>,
+        /// The raw oplog entry.
+        raw: &'a RawDocument,
+        /// The full namespace of the operation including its database and collection.
+        namespace: &'a str,
+        /// The BSON document inserted into the namespace.
+        document: &'a RawDocument,
+    },
+    /// An update of a document in a specific database and collection matching a given query.
+    Update {
+        /// The time of the operation.
+        timestamp: DateTime<Utc>,
+        /// The raw oplog entry.
+        raw: &'a RawDocument,
+        /// The full namespace of the operation including its database and collection.
+        namespace: &'a str,
+        /// The BSON selection criteria for the update.
+        query: &'a RawDocument,
+        /// The BSON update applied in this operation.
+        update: &'a RawDocument,
+    },
+    /// The deletion of a document in a specific database and collection matching a given query.
+    Delete {
+        /// The time of the operation.
+        timestamp: DateTime<Utc>,
+        /// The raw oplog entry.
+        raw: &'a RawDocument,
+        /// The full namespace of the operation including its database and collection.
+        namespace: &'a str,
+        /// The BSON selection criteria for the delete.
+        query: &'a RawDocument,
+    },
+    /// A command such as the creation or deletion of a collection.
+    Command {
+        /// The time of the operation.
+        timestamp: DateTime<Utc>,
+        /// The raw oplog entry.
+        raw: &'a RawDocument,
+        /// The full namespace of the operation including its database and collection.
+        namespace: &'a str,
+        /// The BSON command.
+        command: &'a RawDocument,
+    },
+    /// A command to apply multiple oplog operations at once.
+    ///
+    /// The operations are only parsed when calling `RawOperation::operations`.
+    ApplyOps {
+        /// The time of the operation.
+        timestamp: DateTime<Utc>,
+        /// The raw oplog entry.
+        raw: &'a RawDocument,
+        /// The full namespace of the operation including its database and collection.
+        namespace: &'a str,
+        /// The raw oplog entries to apply.
+        operations: &'a RawArray,
+    },
+}
+
+impl<'a> RawOperation<'a> {
+    /// Try to create a new operation borrowing from a raw BSON document.
+    ///
+    /// Entries are validated as strictly as by `Operation::new` except for the operations within
+    /// an `applyOps` command, which are only parsed by `RawOperation::operations`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use oplog::bson::{doc, rawdoc, Timestamp};
+    /// use oplog::{Operation, RawOperation};
+    ///
+    /// let entry = rawdoc! {
+    ///     "ts": Timestamp { time: 1479561394, increment: 0 },
+    ///     "op": "i",
+    ///     "ns": "foo.bar",
+    ///     "o": { "_id": 1, "foo": "bar" },
+    /// };
+    /// let operation = RawOperation::new(&entry).unwrap();
+    ///
+    /// assert_eq!(operation.namespace(), Some("foo.bar"));
+    /// assert_eq!(
+    ///     operation.to_operation().unwrap(),
+    ///     Operation::new(&doc! {
+    ///         "ts": Timestamp { time: 1479561394, increment: 0 },
+    ///         "op": "i",
+    ///         "ns": "foo.bar",
+    ///         "o": { "_id": 1, "foo": "bar" },
+    ///     })
+    ///     .unwrap()
+    /// );
+    /// ```
+    pub fn new(document: &'a RawDocument) -> Result<RawOperation<'a>> {
+        RawOperation::from_entry(&RawEntry::new(document, String::new()))
+    }
+
+    /// Returns an operation for a given entry.
+    fn from_entry(entry: &RawEntry<'a>) -> Result<RawOperation<'a>> {
+        let op = entry.get_str("op")?;
+        let timestamp = || entry.get_timestamp("ts").map(timestamp_to_datetime);
+        let raw = entry.document;
+
+        match op {
+            "n" => {
+                let timestamp = timestamp()?;
+                // We don't always get a document in "o"
+                let message = match entry.document.get("o") {
+                    Ok(Some(RawBsonRef::Document(o))) => match o.get("msg") {
+                        Ok(Some(RawBsonRef::String(message))) => Some(message),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+
+                Ok(RawOperation::Noop {
+                    timestamp,
+                    raw,
+                    message,
+                })
+            }
+            "i" => Ok(RawOperation::Insert {
+                timestamp: timestamp()?,
+                raw,
+                namespace: entry.get_str("ns")?,
+                document: entry.get_document("o")?,
+            }),
+            "u" => Ok(RawOperation::Update {
+                timestamp: timestamp()?,
+                raw,
+                namespace: entry.get_str("ns")?,
+                update: entry.get_document("o")?,
+                query: entry.get_document("o2")?,
+            }),
+            "d" => Ok(RawOperation::Delete {
+                timestamp: timestamp()?,
+                raw,
+                namespace: entry.get_str("ns")?,
+                query: entry.get_document("o")?,
+            }),
+            "c" => {
+                let timestamp = timestamp()?;
+                let namespace = entry.get_str("ns")?;
+                let command = entry.get_document("o")?;
+
+                match command.get("applyOps") {
+                    Ok(Some(RawBsonRef::Array(operations))) => Ok(RawOperation::ApplyOps {
+                        timestamp,
+                        raw,
+                        namespace,
+                        operations,
+                    }),
+                    Ok(_) => Ok(RawOperation::Command {
+                        timestamp,
+                        raw,
+                        namespace,
+                        command,
+                    }),
+                    Err(e) => {
+                        Err(entry.error("o.applyOps", ParseErrorKind::Malformed(e.to_string())))
+                    }
+                }
+            }
+            op => Err(entry.error("op", ParseErrorKind::UnknownOperation(op.into()))),
+        }
+    }
+
+    /// Returns the time of the operation.
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match *self {
+            RawOperation::Noop { timestamp, .. }
+            | RawOperation::Insert { timestamp, .. }
+            | RawOperation::Update { timestamp, .. }
+            | RawOperation::Delete { timestamp, .. }
+            | RawOperation::Command { timestamp, .. }
+            | RawOperation::ApplyOps { timestamp, .. } => timestamp,
+        }
+    }
+
+    /// Returns the raw oplog entry of the operation.
+    pub fn raw(&self) -> &'a RawDocument {
+        match *self {
+            RawOperation::Noop { raw, .. }
+            | RawOperation::Insert { raw, .. }
+            | RawOperation::Update { raw, .. }
+            | RawOperation::Delete { raw, .. }
+            | RawOperation::Command { raw, .. }
+            | RawOperation::ApplyOps { raw, .. } => raw,
+        }
+    }
+
+    /// Returns the position of the operation in the oplog.
+    ///
+    /// Unlike `Operation::optime`, this includes the term (`t`) and hash (`h`) of the entry when
+    /// present, as they are read from the raw entry.
+    pub fn optime(&self) -> OpTime {
+        let raw = self.raw();
+        let get_i64 = |key| match raw.get(key) {
+            Ok(Some(RawBsonRef::Int64(n))) => Some(n),
+            Ok(Some(RawBsonRef::Int32(n))) => Some(n.into()),
+            _ => None,
+        };
+
+        OpTime {
+            term: get_i64("t"),
+            hash: get_i64("h"),
+            ..self.timestamp().into()
+        }
+    }
+
+    /// Returns the full namespace of the operation, if it has one.
+    pub fn namespace(&self) -> Option<&'a str> {
+        match *self {
+            RawOperation::Noop { .. } => None,
+            RawOperation::Insert { namespace, .. }
+            | RawOperation::Update { namespace, .. }
+            | RawOperation::Delete { namespace, .. }
+            | RawOperation::Command { namespace, .. }
+            | RawOperation::ApplyOps { namespace, .. } => Some(namespace),
+        }
+    }
+
+    /// Returns the `_id` of the document inserted, updated or deleted, if known.
+    pub fn document_id(&self) -> Option<RawBsonRef<'a>> {
+        match *self {
+            RawOperation::Insert { document, .. } => document.get("_id").ok().flatten(),
+            RawOperation::Update { query, .. } | RawOperation::Delete { query, .. } => {
+                query.get("_id").ok().flatten()
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns a short name for the type of the operation, as per `Operation::kind`.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            RawOperation::Noop { .. } => "noop",
+            RawOperation::Insert { .. } => "insert",
+            RawOperation::Update { .. } => "update",
+            RawOperation::Delete { .. } => "delete",
+            RawOperation::Command { .. } => "command",
+            RawOperation::ApplyOps { .. } => "applyOps",
+        }
+    }
+
+    /// Parses the operations applied by an `applyOps` command, returning an empty vector for any
+    /// other operation.
+    ///
+    /// The paths of any parse errors are relative to this operation, e.g. `o.applyOps[3].ns`.
+    pub fn operations(&self) -> Result<Vec<RawOperation<'a>>> {
+        let operations = match *self {
+            RawOperation::ApplyOps { operations, .. } => operations,
+            _ => return Ok(Vec::new()),
+        };
+
+        operations
+            .into_iter()
+            .enumerate()
+            .map(|(i, res)| {
+                let path = format!("o.applyOps[{}]", i);
+                let malformed = |e: bson::raw::Error| {
+                    Error::Parse(ParseError {
+                        kind: ParseErrorKind::Malformed(e.to_string()),
+                        path: path.clone(),
+                        op: Some("c".into()),
+                        timestamp: Some(self.optime().timestamp),
+                        raw: None,
+                    })
+                };
+
+                match res.map_err(malformed)? {
+                    RawBsonRef::Document(document) => {
+                        RawOperation::from_entry(&RawEntry::new(document, path))
+                    }
+                    bson => Err(Error::Parse(ParseError {
+                        kind: ParseErrorKind::InvalidType {
+                            expected: ElementType::EmbeddedDocument,
+                            found: bson.element_type(),
+                        },
+                        path,
+                        op: Some("c".into()),
+                        timestamp: Some(self.optime().timestamp),
+                        raw: None,
+                    })),
+                }
+            })
+            .collect()
+    }
+
+    /// Converts the operation into an owned `Operation`, copying its documents.
+    ///
+    /// This returns an error if the documents or any operations within an `applyOps` command are
+    /// not valid BSON.
+    pub fn to_operation(&self) -> Result<Operation> {
+        Ok(match *self {
+            RawOperation::Noop {
+                timestamp, message, ..
+            } => Operation::Noop {
+                timestamp,
+                message: message.map(String::from),
+            },
+            RawOperation::Insert {
+                timestamp,
+                namespace,
+                document,
+                ..
+            } => Operation::Insert {
+                timestamp,
+                namespace: namespace.into(),
+                document: to_document(document, "o")?,
+            },
+            RawOperation::Update {
+                timestamp,
+                namespace,
+                query,
+                update,
+                ..
+            } => Operation::Update {
+                timestamp,
+                namespace: namespace.into(),
+                query: to_document(query, "o2")?,
+                update: to_document(update, "o")?,
+            },
+            RawOperation::Delete {
+                timestamp,
+                namespace,
+                query,
+                ..
+            } => Operation::Delete {
+                timestamp,
+                namespace: namespace.into(),
+                query: to_document(query, "o")?,
+            },
+            RawOperation::Command {
+                timestamp,
+                namespace,
+                command,
+                ..
+            } => Operation::Command {
+                timestamp,
+                namespace: namespace.into(),
+                command: to_document(command, "o")?,
+            },
+            RawOperation::ApplyOps {
+                timestamp,
+                raw,
+                namespace,
+                ..
+            } => {
+                let flag = |key| {
+                    raw.get_document("o")
+                        .and_then(|o| o.get_bool(key))
+                        .unwrap_or(false)
+                };
+                let previous = match raw.get_document("prevOpTime") {
+                    Ok(optime) => previous_optime(&to_document(optime, "prevOpTime")?),
+                    Err(_) => None,
+                };
+
+                Operation::ApplyOps {
+                    timestamp,
+                    namespace: namespace.into(),
+                    operations: self
+                        .operations()?
+                        .iter()
+                        .map(RawOperation::to_operation)
+                        .collect::<Result<Vec<Operation>>>()?,
+                    partial: flag("partialTxn"),
+                    prepare: flag("prepare"),
+                    previous,
+                }
+            }
+        })
+    }
+}
+
+/// Copies a raw document into an owned `Document`.
+fn to_document(document: &RawDocument, path: &str) -> Result<Document> {
+    Document::try_from(document).map_err(|e| {
+        Error::Parse(ParseError {
+            kind: ParseErrorKind::Malformed(e.to_string()),
+            path: path.into(),
+            op: None,
+            timestamp: None,
+            raw: None,
+        })
+    })
+}
+
+/// A raw oplog entry being converted, along with the context needed to report errors.
+struct RawEntry<'a> {
+    /// The raw BSON document of the entry.
+    document: &'a RawDocument,
+    /// The path to this entry from the top-level entry (e.g. `o.applyOps[3]`), empty for the
+    /// top-level entry itself.
+    prefix: String,
+}
+
+impl<'a> RawEntry<'a> {
+    fn new(document: &'a RawDocument, prefix: String) -> RawEntry<'a> {
+        RawEntry { document, prefix }
+    }
+
+    /// Returns an error for a field of this entry.
+    fn error(&self, key: &str, kind: ParseErrorKind) -> Error {
+        let path = if self.prefix.is_empty() {
+            key.into()
+        } else {
+            format!("{}.{}", self.prefix, key)
+        };
+
+        Error::Parse(ParseError {
+            kind,
+            path,
+            op: self.document.get_str("op").ok().map(|s| s.to_string()),
+            timestamp: self.document.get_timestamp("ts").ok(),
+            raw: None,
+        })
+    }
+
+    /// Returns a field of this entry converted with `f`, or an error if it is missing, malformed
+    /// or `f` returns `None` as it has the wrong type.
+    fn get<T, F>(&self, key: &str, expected: ElementType, f: F) -> Result<T>
+    where
+        F: FnOnce(RawBsonRef<'a>) -> Option<T>,
+    {
+        match self.document.get(key) {
+            Err(e) => Err(self.error(key, ParseErrorKind::Malformed(e.to_string()))),
+            Ok(None) => Err(self.error(key, ParseErrorKind::MissingField { expected })),
+            Ok(Some(bson)) => f(bson).ok_or_else(|| {
+                let found = bson.element_type();
+
+                self.error(key, ParseErrorKind::InvalidType { expected, found })
+            }),
+        }
+    }
+
+    fn get_str(&self, key: &str) -> Result<&'a str> {
+        self.get(key, ElementType::String, RawBsonRef::as_str)
+    }
+
+    fn get_timestamp(&self, key: &str) -> Result<bson::Timestamp> {
+        self.get(key, ElementType::Timestamp, RawBsonRef::as_timestamp)
+    }
+
+    fn get_document(&self, key: &str) -> Result<&'a RawDocument> {
+        self.get(key, ElementType::EmbeddedDocument, RawBsonRef::as_document)
+    }
+}
+
+/// An oplog that yields raw BSON entries, to be parsed with `RawOperation::new`.
+///
+/// As a `Stream` cannot yield items borrowing from itself, this yields each entry as an owned
+/// `RawDocumentBuf` (which the driver reads without any further parsing) and leaves conversion
+/// into a `RawOperation` to the caller. Entries are therefore never rejected by the stream itself
+/// and the builder's `parse_mode` and `detect_rollbacks` options do not apply.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use futures::TryStreamExt;
+/// use mongodb::Client;
+/// use oplog::{Oplog, RawOperation};
+///
+/// # async fn run() -> Result<(), oplog::Error> {
+/// let client = Client::with_uri_str("mongodb://localhost").await?;
+/// let mut oplog = Oplog::builder().build_raw(&client).await?;
+///
+/// while let Some(entry) = oplog.try_next().await? {
+///     let operation = RawOperation::new(&entry)?;
+///
+///     if let (Some(namespace), Some(id)) = (operation.namespace(), operation.document_id()) {
+///         println!("Invalidate {} {:?}", namespace, id);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct RawOplog {
+    /// The internal MongoDB cursor for the current position in the oplog, closed on shutdown.
+    cursor: StoppableCursor<Cursor<RawDocumentBuf>>,
+    /// The oplog collection, kept so the cursor can be reopened on resume.
+    coll: Collection<RawDocumentBuf>,
+    /// The options the cursor was built with.
+    builder: OplogBuilder,
+    /// The timestamp of the last entry read from the cursor.
+    last_timestamp: Option<bson::Timestamp>,
+}
+
+impl RawOplog {
+    pub(crate) fn new(
+        cursor: Cursor<RawDocumentBuf>,
+        coll: Collection<RawDocumentBuf>,
+        builder: OplogBuilder,
+    ) -> RawOplog {
+        RawOplog {
+            cursor: StoppableCursor::new(cursor),
+            coll,
+            builder,
+            last_timestamp: None,
+        }
+    }
+
+    /// Returns the timestamp of the last entry read from the oplog, if any.
+    ///
+    /// See `Oplog::last_timestamp`.
+    pub fn last_timestamp(&self) -> Option<bson::Timestamp> {
+        self.last_timestamp
+    }
+
+    /// Reopens the underlying cursor directly after the last entry read.
+    ///
+    /// See `Oplog::resume`.
+    pub async fn resume(&mut self) -> Result<()> {
+        let start = self
+            .last_timestamp
+            .map(crate::Start::After)
+            .or(self.builder.start);
+
+        self.cursor
+            .reopen(self.builder.open(&self.coll, start).await?);
+        instrument::reconnect(self.last_timestamp);
+
+        Ok(())
+    }
+
+    /// Returns a handle to shut the oplog down gracefully from another task.
+    ///
+    /// See `ShutdownHandle`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.cursor.handle()
+    }
+}
+
+impl Stream for RawOplog {
+    type Item = Result<RawDocumentBuf>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        match this.cursor.poll_next(cx) {
+            Poll::Ready(Some(Ok(entry))) => {
+                if let Ok(ts) = entry.get_timestamp("ts") {
+                    this.last_timestamp = Some(ts);
+                }
+
+                Poll::Ready(Some(Ok(entry)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => {
+                this.cursor.finish(this.last_timestamp);
+
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for RawOplog {
+    fn drop(&mut self) {
+        self.cursor.finish(self.last_timestamp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::{doc, rawdoc, Timestamp};
+
+    fn ts() -> Timestamp {
+        Timestamp {
+            time: 1479561394,
+            increment: 0,
+        }
+    }
+
+    #[test]
+    fn raw_operation_borrows_updates() {
+        let entry = rawdoc! {
+            "ts": ts(),
+            "op": "u",
+            "ns": "foo.bar",
+            "o2": { "_id": 1 },
+            "o": { "$set": { "foo": "baz" } },
+        };
+        let operation = RawOperation::new(&entry).unwrap();
+
+        assert_eq!(operation.kind(), "update");
+        assert_eq!(operation.namespace(), Some("foo.bar"));
+        assert_eq!(operation.document_id(), Some(RawBsonRef::Int32(1)));
+        assert_eq!(operation.optime().timestamp, ts());
+    }
+
+    #[test]
+    fn raw_operation_keeps_the_term_and_hash_of_entries() {
+        let entry = rawdoc! {
+            "ts": ts(),
+            "t": 3_i64,
+            "h": 42_i64,
+            "op": "i",
+            "ns": "foo.bar",
+            "o": { "_id": 1 },
+        };
+        let optime = RawOperation::new(&entry).unwrap().optime();
+
+        assert_eq!(
+            optime,
+            OpTime {
+                timestamp: ts(),
+                term: Some(3),
+                hash: Some(42),
+            }
+        );
+    }
+
+    #[test]
+    fn raw_operation_converts_to_the_same_operation() {
+        let entries = vec![
+            doc! { "ts": ts(), "op": "n", "ns": "", "o": { "msg": "initiating set" } },
+            doc! { "ts": ts(), "op": "i", "ns": "foo.bar", "o": { "_id": 1 } },
+            doc! { "ts": ts(), "op": "d", "ns": "foo.bar", "o": { "_id": 1 } },
+            doc! { "ts": ts(), "op": "c", "ns": "foo.$cmd", "o": { "drop": "bar" } },
+            doc! {
+                "ts": ts(),
+                "op": "c",
+                "ns": "admin.$cmd",
+                "o": {
+                    "applyOps": [
+                        { "ts": ts(), "op": "i", "ns": "foo.bar", "o": { "_id": 1 } },
+                        { "ts": ts(), "op": "u", "ns": "foo.bar", "o2": { "_id": 1 }, "o": { "a": 1 } },
+                    ],
+                },
+            },
+            doc! {
+                "ts": ts(),
+                "op": "c",
+                "ns": "admin.$cmd",
+                "o": { "applyOps": [], "partialTxn": true },
+                "prevOpTime": { "ts": ts(), "t": 1_i64 },
+            },
+        ];
+
+        for entry in entries {
+            let raw = RawDocumentBuf::from_document(&entry).unwrap();
+
+            assert_eq!(
+                RawOperation::new(&raw).unwrap().to_operation().unwrap(),
+                Operation::new(&entry).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn raw_operation_reports_errors_like_operation() {
+        let entry = doc! { "ts": ts(), "op": "u", "ns": "foo.bar", "o": { "a": 1 } };
+        let raw = RawDocumentBuf::from_document(&entry).unwrap();
+
+        assert_eq!(
+            RawOperation::new(&raw).unwrap_err().to_string(),
+            Operation::new(&entry).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn raw_operation_parses_apply_ops_lazily() {
+        let entry = rawdoc! {
+            "ts": ts(),
+            "op": "c",
+            "ns": "admin.$cmd",
+            "o": { "applyOps": [{ "ts": ts(), "op": "x" }] },
+        };
+        let operation = RawOperation::new(&entry).unwrap();
+
+        assert_eq!(operation.kind(), "applyOps");
+        assert_eq!(
+            operation.operations().unwrap_err().to_string(),
+            "Unknown operation type found at o.applyOps[0].op: x in \"x\" entry at Timestamp(1479561394, 0)"
+        );
+    }
+}
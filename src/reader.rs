@@ -0,0 +1,145 @@
+//! The reader module holds the state shared by the async and sync oplog iterators: it converts
+//! each entry read from a cursor into an `Operation` and keeps track of where we are.
+
+use crate::rollback::RollbackTracker;
+use crate::{instrument, Error, OpTime, Operation, ParseMode, Result, Start};
+use bson::Document;
+use mongodb::bson;
+
+/// Converts raw oplog entries into `Operation`s, remembering the position reached.
+pub(crate) struct Reader {
+    /// How entries that cannot be parsed are handled.
+    parse_mode: ParseMode,
+    /// The position the cursor was originally opened from.
+    start: Option<Start>,
+    /// The timestamp of the last entry read from the cursor.
+    last_timestamp: Option<bson::Timestamp>,
+    /// Recently read entries, if rollback detection is enabled.
+    tracker: Option<RollbackTracker>,
+    /// Whether entries are read with a projection and parsed with `Operation::new_projected`.
+    projected: bool,
+}
+
+impl Reader {
+    pub(crate) fn new(
+        parse_mode: ParseMode,
+        start: Option<Start>,
+        rollback_window: Option<usize>,
+        projected: bool,
+    ) -> Reader {
+        Reader {
+            parse_mode,
+            start,
+            last_timestamp: None,
+            tracker: rollback_window.map(RollbackTracker::new),
+            projected,
+        }
+    }
+
+    /// Returns the timestamp of the last entry read, if any.
+    pub(crate) fn last_timestamp(&self) -> Option<bson::Timestamp> {
+        self.last_timestamp
+    }
+
+    /// Returns the position to reopen the cursor from, i.e. after the last entry read.
+    pub(crate) fn resume_from(&self) -> Option<Start> {
+        match self.last_timestamp {
+            Some(ts) => Some(Start::After(ts)),
+            None => self.start,
+        }
+    }
+
+    /// Returns the recently read entries to check for a rollback, if rollback detection is
+    /// enabled.
+    pub(crate) fn recent(&self) -> Option<Vec<OpTime>> {
+        self.tracker.as_ref().map(RollbackTracker::recent)
+    }
+
+    /// Returns the recently read entries to check for a rollback if the given entry was written in
+    /// a new term.
+    pub(crate) fn term_changed(&self, document: &Document) -> Option<Vec<OpTime>> {
+        let tracker = self.tracker.as_ref()?;
+        let optime = OpTime::from_document(document).ok()?;
+
+        if tracker.term_changed(&optime) {
+            Some(tracker.recent())
+        } else {
+            None
+        }
+    }
+
+    /// Stops tracking rolled back entries, returning an error listing them if there are any.
+    ///
+    /// If `rewind` is set, the position to resume from is moved back to the newest entry that
+    /// survived.
+    pub(crate) fn rolled_back(&mut self, lost: Vec<OpTime>, rewind: bool) -> Result<()> {
+        if lost.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(ref mut tracker) = self.tracker {
+            tracker.forget(&lost);
+
+            if rewind {
+                self.last_timestamp = tracker.last().map(|o| o.timestamp).or(self.last_timestamp);
+            }
+        }
+
+        instrument::rollback_detected(&lost);
+
+        Err(Error::RollbackDetected { operations: lost })
+    }
+
+    /// Records an entry as read and converts it into an `Operation`.
+    ///
+    /// Returns `None` if the entry could not be parsed and should be skipped.
+    pub(crate) fn read(&mut self, document: Document) -> Option<Result<Operation>> {
+        instrument::entry_read(&document);
+
+        let optime = OpTime::from_document(&document).ok();
+
+        if let Some(optime) = optime {
+            self.last_timestamp = Some(optime.timestamp);
+
+            if let Some(ref mut tracker) = self.tracker {
+                tracker.record(optime);
+            }
+        }
+
+        let parsed = if self.projected {
+            Operation::new_projected(&document)
+        } else {
+            Operation::new(&document)
+        };
+
+        let error = match parsed {
+            Ok(operation) => {
+                instrument::operation_read(&operation, optime);
+
+                return Some(Ok(operation));
+            }
+            Err(e) => e,
+        };
+
+        instrument::parse_error(&document, &error);
+
+        match self.parse_mode {
+            ParseMode::Strict => Some(Err(error.with_raw(document))),
+            ParseMode::Lenient => match Operation::unknown(&document) {
+                Some(operation) => Some(Ok(operation)),
+                None => Some(Err(error.with_raw(document))),
+            },
+            ParseMode::Skip => {
+                instrument::entry_skipped(&document);
+
+                None
+            }
+            ParseMode::DeadLetter(ref sink) => {
+                instrument::entry_skipped(&document);
+                sink.send(document, error);
+
+                None
+            }
+        }
+    }
+}
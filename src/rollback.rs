@@ -0,0 +1,145 @@
+//! The rollback module keeps track of recently read oplog entries so that, following an election,
+//! we can check they are still present in the new primary's oplog.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::{OpTime, Result};
+use bson::{doc, Document};
+use futures::TryStreamExt;
+use mongodb::bson;
+use mongodb::options::FindOptions;
+use mongodb::Collection;
+
+/// A bounded window of the most recently read optimes.
+#[derive(Clone, Debug)]
+pub(crate) struct RollbackTracker {
+    window: usize,
+    recent: VecDeque<OpTime>,
+}
+
+impl RollbackTracker {
+    pub(crate) fn new(window: usize) -> RollbackTracker {
+        RollbackTracker {
+            window,
+            recent: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// Records an entry as read, evicting the oldest one if the window is full.
+    pub(crate) fn record(&mut self, optime: OpTime) {
+        if self.window == 0 {
+            return;
+        }
+
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+
+        self.recent.push_back(optime);
+    }
+
+    /// Returns true if the given entry was written in a different term to the last one read.
+    pub(crate) fn term_changed(&self, optime: &OpTime) -> bool {
+        match (self.recent.back().and_then(|o| o.term), optime.term) {
+            (Some(last), Some(next)) => last != next,
+            _ => false,
+        }
+    }
+
+    /// Returns the optimes currently being tracked, oldest first.
+    pub(crate) fn recent(&self) -> Vec<OpTime> {
+        self.recent.iter().copied().collect()
+    }
+
+    /// Returns the newest tracked optime.
+    pub(crate) fn last(&self) -> Option<OpTime> {
+        self.recent.back().copied()
+    }
+
+    /// Stops tracking entries that have been rolled back.
+    pub(crate) fn forget(&mut self, lost: &[OpTime]) {
+        self.recent.retain(|o| !lost.contains(o));
+    }
+}
+
+/// Looks up the given optimes in the oplog and returns those that no longer exist.
+///
+/// An entry counts as missing if there is no entry with its timestamp or if the entry found was
+/// written in a different term or has a different hash.
+pub(crate) async fn find_lost(
+    coll: Collection<Document>,
+    recent: Vec<OpTime>,
+) -> Result<Vec<OpTime>> {
+    if recent.is_empty() {
+        return Ok(recent);
+    }
+
+    let (filter, opts) = lookup(&recent);
+    let found = coll.find(filter, opts).await?.try_collect().await?;
+
+    lost_in(recent, found)
+}
+
+/// Returns the query and options to look up the given optimes in the oplog.
+pub(crate) fn lookup(recent: &[OpTime]) -> (Document, FindOptions) {
+    let timestamps: Vec<_> = recent.iter().map(|o| o.timestamp).collect();
+    let opts = FindOptions::builder()
+        .projection(doc! { "ts": 1, "t": 1, "h": 1 })
+        .build();
+
+    (doc! { "ts": { "$in": timestamps } }, opts)
+}
+
+/// Returns the optimes that are not among the entries found by a lookup.
+pub(crate) fn lost_in(recent: Vec<OpTime>, found: Vec<Document>) -> Result<Vec<OpTime>> {
+    let found = found
+        .iter()
+        .map(|d| OpTime::from_document(d).map(|o| (o.timestamp, o)))
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    Ok(lost_from(recent, &found))
+}
+
+/// Returns the optimes not present in `found`.
+fn lost_from(recent: Vec<OpTime>, found: &HashMap<bson::Timestamp, OpTime>) -> Vec<OpTime> {
+    recent
+        .into_iter()
+        .filter(|o| found.get(&o.timestamp) != Some(o))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optime(time: u32, term: i64) -> OpTime {
+        OpTime {
+            timestamp: bson::Timestamp { time, increment: 0 },
+            term: Some(term),
+            hash: None,
+        }
+    }
+
+    #[test]
+    fn tracker_keeps_a_bounded_window() {
+        let mut tracker = RollbackTracker::new(2);
+        tracker.record(optime(1, 1));
+        tracker.record(optime(2, 1));
+        tracker.record(optime(3, 1));
+
+        assert_eq!(tracker.recent(), vec![optime(2, 1), optime(3, 1)]);
+        assert!(tracker.term_changed(&optime(4, 2)));
+        assert!(!tracker.term_changed(&optime(4, 1)));
+    }
+
+    #[test]
+    fn lost_from_returns_missing_and_rewritten_entries() {
+        let found: HashMap<_, _> = vec![optime(1, 1), optime(3, 2)]
+            .into_iter()
+            .map(|o| (o.timestamp, o))
+            .collect();
+        let lost = lost_from(vec![optime(1, 1), optime(2, 1), optime(3, 1)], &found);
+
+        assert_eq!(lost, vec![optime(2, 1), optime(3, 1)]);
+    }
+}
@@ -0,0 +1,692 @@
+//! The server module serves the oplog over HTTP as Server-Sent Events and newline-delimited JSON,
+//! fanning out a single cursor to every client.
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::future::ready;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{instrument, shared};
+use crate::{Error, Operation, Oplog, OplogBuilder, Result, SharedOplog, SubscribeOptions};
+use bson::{Bson, Timestamp};
+use bytes::Bytes;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use mongodb::{bson, Client};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+
+type Body = UnsyncBoxBody<Bytes, Infallible>;
+
+/// An HTTP server streaming operations to clients as they are read from the oplog.
+///
+/// The server reads from a single `Oplog` and serves two endpoints:
+///
+/// * `GET /sse`: operations as Server-Sent Events, with the kind of operation (see
+///   `Operation::kind`) as the event name
+/// * `GET /ndjson`: operations as newline-delimited JSON
+///
+/// Each operation is sent as a relaxed Extended JSON oplog entry (see `Operation::to_document`).
+/// Clients can restrict the operations they receive with repeated `ns` (e.g. `ns=foo.bar`) and
+/// `op` (e.g. `op=insert`) query parameters; an `Operation::ApplyOps` is sent if any operation
+/// it applies matches.
+///
+/// Every event has the id `<seconds>.<increment>` of its oplog timestamp. Clients resume after an
+/// event by sending its id in a `Last-Event-ID` header, as browsers do when reconnecting, or an
+/// `after` query parameter. Recent operations are kept in memory to replay to resuming clients;
+/// clients resuming from further back get a cursor of their own until they catch up with them or,
+/// if none are kept, with the newest operation sent to every client. A client that falls more
+/// than the buffer capacity behind is disconnected and must resume.
+///
+/// Errors are sent to every client as `{"error": "..."}`, with the event name `error` and no id.
+/// Retryable errors are recovered from with `Oplog::resume`, as by `SharedOplog`, and reading
+/// carries on after a rollback or an entry that cannot be parsed; after any other error, the
+/// connections are closed.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use mongodb::Client;
+/// use oplog::{Oplog, Server};
+/// use tokio::net::TcpListener;
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let client = Client::with_uri_str("mongodb://localhost").await?;
+/// let listener = TcpListener::bind("127.0.0.1:8080").await?;
+///
+/// Server::new(&client, Oplog::builder())
+///     .history(10_000)
+///     .serve(listener)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Server {
+    client: Client,
+    builder: OplogBuilder,
+    capacity: usize,
+    history: usize,
+}
+
+impl Server {
+    /// Creates a server reading the oplog as configured by the given builder.
+    pub fn new(client: &Client, builder: OplogBuilder) -> Server {
+        Server {
+            client: client.clone(),
+            builder,
+            capacity: 1024,
+            history: 1024,
+        }
+    }
+
+    /// Sets how many operations each client may fall behind before it is disconnected. Defaults
+    /// to 1024.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Sets how many recent operations are kept in memory for resuming clients. Defaults to 1024.
+    pub fn history(mut self, history: usize) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Serves clients from the given listener until the oplog returns an error that cannot be
+    /// recovered from with `Oplog::resume`.
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        let oplog = self.builder.clone().build(&self.client).await?;
+        let hub = Arc::new(Hub {
+            client: self.client,
+            builder: self.builder,
+            broadcast: Arc::new(Broadcast::new(self.capacity, self.history)),
+        });
+
+        let accept = {
+            let hub = Arc::clone(&hub);
+
+            async move {
+                loop {
+                    let stream = match listener.accept().await {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            // Such as running out of file descriptors, which may take a while.
+                            instrument::accept_failed(&e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    };
+                    let hub = Arc::clone(&hub);
+                    let service = service_fn(move |request| {
+                        let hub = Arc::clone(&hub);
+                        async move { Ok::<_, Infallible>(hub.handle(request).await) }
+                    });
+
+                    tokio::spawn(async move {
+                        let _ = http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    });
+                }
+            }
+        };
+
+        tokio::select! {
+            result = hub.run(oplog) => result,
+            _ = accept => Ok(()),
+        }
+    }
+}
+
+/// The state shared by all connections.
+struct Hub {
+    client: Client,
+    builder: OplogBuilder,
+    broadcast: Arc<Broadcast>,
+}
+
+impl Hub {
+    /// Reads the oplog through a `SharedOplog`, publishing every operation and error, and closes
+    /// the broadcast on a fatal error.
+    async fn run(&self, oplog: Oplog) -> Result<()> {
+        let shared = SharedOplog::new(oplog);
+        let publish = shared.subscribe(SubscribeOptions::new()).for_each(|res| {
+            match res {
+                Ok(operation) => self.broadcast.publish(operation),
+                Err(e) => self.broadcast.report(&e),
+            }
+
+            ready(())
+        });
+
+        let (result, ()) = futures::join!(shared.run(), publish);
+        self.broadcast.close();
+
+        result
+    }
+
+    async fn handle(&self, request: Request<Incoming>) -> Response<Body> {
+        let format = match (request.method(), request.uri().path()) {
+            (&Method::GET, "/sse") => Format::Sse,
+            (&Method::GET, "/ndjson") => Format::Ndjson,
+            _ => return text(StatusCode::NOT_FOUND, "Not found"),
+        };
+
+        let query = match Query::parse(request.uri().query().unwrap_or("")) {
+            Ok(query) => query,
+            Err(message) => return text(StatusCode::BAD_REQUEST, &message),
+        };
+
+        let last_event_id = request
+            .headers()
+            .get("last-event-id")
+            .and_then(|value| value.to_str().ok());
+        let after = match last_event_id.map(parse_event_id) {
+            Some(Some(timestamp)) => Some(timestamp),
+            Some(None) => return text(StatusCode::BAD_REQUEST, "Invalid Last-Event-ID"),
+            None => query.after,
+        };
+
+        let events = match self.broadcast.subscribe(after) {
+            Some(Subscription::Live(events)) => events,
+            Some(Subscription::Behind(after)) => match self.catch_up(after).await {
+                Ok(events) => events,
+                Err(e @ Error::PositionLost { .. }) => {
+                    return text(StatusCode::GONE, &e.to_string())
+                }
+                Err(e) => return text(StatusCode::BAD_GATEWAY, &e.to_string()),
+            },
+            None => return text(StatusCode::SERVICE_UNAVAILABLE, "Oplog closed"),
+        };
+
+        let frames = events
+            .filter(move |event| {
+                ready(match *event {
+                    Event::Operation(ref operation) => query.matches(operation),
+                    Event::Error(_) => true,
+                })
+            })
+            .map(move |event| Ok(Frame::data(format.encode(&event))));
+
+        Response::builder()
+            .header(CONTENT_TYPE, format.content_type())
+            .header(CACHE_CONTROL, "no-cache")
+            .body(StreamBody::new(frames).boxed_unsync())
+            .expect("valid response")
+    }
+
+    /// Returns the events after a timestamp read by a cursor of their own until they reach the
+    /// operations kept in memory, then switches to the broadcast.
+    async fn catch_up(&self, after: Timestamp) -> Result<BoxStream<'static, Event>> {
+        let oplog = self
+            .builder
+            .clone()
+            .start_after(after)
+            .build(&self.client)
+            .await?;
+        let broadcast = Arc::clone(&self.broadcast);
+
+        let events = stream::unfold(Some((oplog, after, 0)), move |state| {
+            let broadcast = Arc::clone(&broadcast);
+
+            async move {
+                let (mut oplog, last, mut attempts) = state?;
+
+                match broadcast.subscribe(Some(last))? {
+                    Subscription::Live(events) => return Some((events, None)),
+                    Subscription::Behind(_) => {}
+                }
+
+                let res = loop {
+                    match oplog.next().await? {
+                        Err(ref e) if e.is_retryable() => {
+                            if let Err(e) = shared::resume(&mut oplog, &mut attempts).await {
+                                break Err(e);
+                            }
+                        }
+                        res => break res,
+                    }
+                };
+
+                let (event, state) = match res {
+                    Ok(operation) => {
+                        let last = operation.optime().timestamp;
+
+                        (
+                            Event::Operation(Arc::new(operation)),
+                            Some((oplog, last, 0)),
+                        )
+                    }
+                    Err(e) if e.carries_on() => (Event::from(&e), Some((oplog, last, attempts))),
+                    Err(e) => (Event::from(&e), None),
+                };
+
+                Some((stream::once(ready(event)).boxed(), state))
+            }
+        });
+
+        Ok(events.flatten().boxed())
+    }
+}
+
+/// What is sent to clients.
+#[derive(Clone, Debug)]
+enum Event {
+    Operation(Arc<Operation>),
+    Error(Arc<str>),
+}
+
+impl From<&Error> for Event {
+    fn from(error: &Error) -> Event {
+        Event::Error(error.to_string().into())
+    }
+}
+
+/// A channel of operations to every client with a buffer of recent operations for resuming.
+struct Broadcast {
+    state: Mutex<State>,
+    history: usize,
+}
+
+struct State {
+    sender: Option<broadcast::Sender<Event>>,
+    recent: VecDeque<Arc<Operation>>,
+    /// The timestamp of the newest operation published, whether or not it is kept.
+    newest: Option<Timestamp>,
+}
+
+/// Where a client should read operations from.
+enum Subscription {
+    /// From the broadcast, after any buffered operations to replay.
+    Live(BoxStream<'static, Event>),
+    /// From a cursor of its own as it resumes from before the buffered operations.
+    Behind(Timestamp),
+}
+
+impl Broadcast {
+    fn new(capacity: usize, history: usize) -> Broadcast {
+        let (sender, _) = broadcast::channel(capacity);
+
+        Broadcast {
+            state: Mutex::new(State {
+                sender: Some(sender),
+                recent: VecDeque::with_capacity(history),
+                newest: None,
+            }),
+            history,
+        }
+    }
+
+    fn publish(&self, operation: Operation) {
+        let operation = Arc::new(operation);
+        let mut state = self.state.lock().unwrap();
+        state.newest = Some(operation.optime().timestamp);
+
+        if self.history > 0 {
+            if state.recent.len() == self.history {
+                state.recent.pop_front();
+            }
+            state.recent.push_back(Arc::clone(&operation));
+        }
+
+        if let Some(ref sender) = state.sender {
+            // There being no clients is not an error.
+            let _ = sender.send(Event::Operation(operation));
+        }
+    }
+
+    /// Sends an error to every client without keeping it for resuming clients.
+    fn report(&self, error: &Error) {
+        if let Some(ref sender) = self.state.lock().unwrap().sender {
+            let _ = sender.send(Event::from(error));
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().sender = None;
+    }
+
+    /// Subscribes a client to operations after the given timestamp or, if none, from now on.
+    /// Returns `None` once the broadcast is closed.
+    fn subscribe(&self, after: Option<Timestamp>) -> Option<Subscription> {
+        let state = self.state.lock().unwrap();
+        let sender = state.sender.as_ref()?;
+
+        let replay: Vec<_> = match after {
+            Some(after) => match state.recent.front() {
+                Some(oldest) if oldest.optime().timestamp <= after => state
+                    .recent
+                    .iter()
+                    .filter(|operation| operation.optime().timestamp > after)
+                    .cloned()
+                    .map(Event::Operation)
+                    .collect(),
+                // Nothing is kept but the client has read every operation published so far.
+                _ if state.newest.is_some_and(|newest| newest <= after) => Vec::new(),
+                _ => return Some(Subscription::Behind(after)),
+            },
+            None => Vec::new(),
+        };
+
+        // A lagging client is disconnected so that it resumes instead of missing operations.
+        let live = stream::unfold(sender.subscribe(), |mut receiver| async move {
+            receiver.recv().await.ok().map(|event| (event, receiver))
+        });
+
+        // A client catching up with its own cursor may have read past the broadcast.
+        let live = live.filter(move |event| {
+            ready(match (event, after) {
+                (Event::Operation(operation), Some(after)) => operation.optime().timestamp > after,
+                _ => true,
+            })
+        });
+
+        Some(Subscription::Live(stream::iter(replay).chain(live).boxed()))
+    }
+}
+
+/// The filters and position of a request.
+#[derive(Debug, Default, PartialEq)]
+struct Query {
+    namespaces: Vec<String>,
+    ops: Vec<String>,
+    after: Option<Timestamp>,
+}
+
+impl Query {
+    fn parse(query: &str) -> std::result::Result<Query, String> {
+        let mut parsed = Query::default();
+
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "ns" => parsed.namespaces.push(value.into_owned()),
+                "op" => parsed.ops.push(value.into_owned()),
+                "after" => {
+                    let after = parse_event_id(&value)
+                        .ok_or_else(|| format!("Invalid after: {}", value))?;
+                    parsed.after = Some(after);
+                }
+                _ => return Err(format!("Unknown parameter: {}", key)),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn matches(&self, operation: &Operation) -> bool {
+        let namespace = self.namespaces.is_empty()
+            || operation
+                .namespace()
+                .is_some_and(|ns| self.namespaces.iter().any(|n| n == ns));
+        let op = self.ops.is_empty() || self.ops.iter().any(|op| op == operation.kind());
+
+        match *operation {
+            _ if namespace && op => true,
+            Operation::ApplyOps { ref operations, .. } => {
+                operations.iter().any(|operation| self.matches(operation))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Returns the event id of an operation.
+fn event_id(operation: &Operation) -> String {
+    let timestamp = operation.optime().timestamp;
+
+    format!("{}.{}", timestamp.time, timestamp.increment)
+}
+
+/// Parses an event id of the form `<seconds>.<increment>`.
+fn parse_event_id(id: &str) -> Option<Timestamp> {
+    let (time, increment) = id.split_once('.')?;
+
+    Some(Timestamp {
+        time: time.parse().ok()?,
+        increment: increment.parse().ok()?,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Sse,
+    Ndjson,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Sse => "text/event-stream",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn encode(self, event: &Event) -> Bytes {
+        let operation = match *event {
+            Event::Operation(ref operation) => operation,
+            Event::Error(ref message) => {
+                let json = serde_json::json!({ "error": &**message });
+
+                return match self {
+                    Format::Sse => format!("event: error\ndata: {}\n\n", json),
+                    Format::Ndjson => format!("{}\n", json),
+                }
+                .into();
+            }
+        };
+        let json = Bson::Document(operation.to_document()).into_relaxed_extjson();
+
+        match self {
+            Format::Sse => format!(
+                "id: {}\nevent: {}\ndata: {}\n\n",
+                event_id(operation),
+                operation.kind(),
+                json
+            ),
+            Format::Ndjson => format!("{}\n", json),
+        }
+        .into()
+    }
+}
+
+fn text(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Full::new(Bytes::from(format!("{}\n", message))).boxed_unsync())
+        .expect("valid response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+
+    fn insert(time: i64, namespace: &str) -> Operation {
+        Operation::Insert {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: namespace.into(),
+            document: doc! { "_id": time },
+        }
+    }
+
+    fn times(events: Vec<Event>) -> Vec<u32> {
+        events
+            .iter()
+            .map(|event| match *event {
+                Event::Operation(ref operation) => operation.optime().timestamp.time,
+                Event::Error(_) => panic!("expected an operation"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn subscribe_replays_recent_operations() {
+        let broadcast = Broadcast::new(16, 2);
+        broadcast.publish(insert(1, "foo.bar"));
+        broadcast.publish(insert(2, "foo.bar"));
+        broadcast.publish(insert(3, "foo.bar"));
+
+        let after = Timestamp {
+            time: 2,
+            increment: 0,
+        };
+        let operations = match broadcast.subscribe(Some(after)) {
+            Some(Subscription::Live(operations)) => operations,
+            _ => panic!("expected a live subscription"),
+        };
+        broadcast.publish(insert(4, "foo.bar"));
+        broadcast.close();
+
+        assert_eq!(times(block_on(operations.collect())), vec![3, 4]);
+    }
+
+    #[test]
+    fn subscribe_skips_live_operations_already_read() {
+        let broadcast = Broadcast::new(16, 2);
+        broadcast.publish(insert(1, "foo.bar"));
+
+        let after = Timestamp {
+            time: 2,
+            increment: 0,
+        };
+        let operations = match broadcast.subscribe(Some(after)) {
+            Some(Subscription::Live(operations)) => operations,
+            _ => panic!("expected a live subscription"),
+        };
+        broadcast.publish(insert(2, "foo.bar"));
+        broadcast.publish(insert(3, "foo.bar"));
+        broadcast.close();
+
+        assert_eq!(times(block_on(operations.collect())), vec![3]);
+    }
+
+    #[test]
+    fn subscribe_from_before_recent_operations_is_behind() {
+        let broadcast = Broadcast::new(16, 2);
+        broadcast.publish(insert(2, "foo.bar"));
+        broadcast.publish(insert(3, "foo.bar"));
+
+        let after = Timestamp {
+            time: 1,
+            increment: 0,
+        };
+
+        assert!(matches!(
+            broadcast.subscribe(Some(after)),
+            Some(Subscription::Behind(timestamp)) if timestamp == after
+        ));
+    }
+
+    #[test]
+    fn subscribe_without_history_is_live_once_caught_up() {
+        let broadcast = Broadcast::new(16, 0);
+        let after = Timestamp {
+            time: 2,
+            increment: 0,
+        };
+
+        assert!(matches!(
+            broadcast.subscribe(Some(after)),
+            Some(Subscription::Behind(_))
+        ));
+
+        broadcast.publish(insert(3, "foo.bar"));
+        assert!(matches!(
+            broadcast.subscribe(Some(after)),
+            Some(Subscription::Behind(_))
+        ));
+
+        let after = Timestamp {
+            time: 3,
+            increment: 0,
+        };
+        let operations = match broadcast.subscribe(Some(after)) {
+            Some(Subscription::Live(operations)) => operations,
+            _ => panic!("expected a live subscription"),
+        };
+        broadcast.publish(insert(4, "foo.bar"));
+        broadcast.close();
+
+        assert_eq!(times(block_on(operations.collect())), vec![4]);
+    }
+
+    #[test]
+    fn subscribe_after_close_returns_none() {
+        let broadcast = Broadcast::new(16, 2);
+        broadcast.close();
+
+        assert!(broadcast.subscribe(None).is_none());
+    }
+
+    #[test]
+    fn query_parses_filters_and_position() {
+        let query = Query::parse("ns=foo.bar&ns=foo.%24cmd&op=insert&after=12.3").unwrap();
+
+        assert_eq!(
+            query,
+            Query {
+                namespaces: vec!["foo.bar".into(), "foo.$cmd".into()],
+                ops: vec!["insert".into()],
+                after: Some(Timestamp {
+                    time: 12,
+                    increment: 3
+                }),
+            }
+        );
+        assert!(Query::parse("after=nope").is_err());
+        assert!(Query::parse("limit=1").is_err());
+    }
+
+    #[test]
+    fn query_matches_operations_within_apply_ops() {
+        let query = Query::parse("ns=foo.baz").unwrap();
+        let operation = Operation::ApplyOps {
+            timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+            namespace: "admin.$cmd".into(),
+            operations: vec![insert(1, "foo.bar"), insert(1, "foo.baz")],
+            partial: false,
+            prepare: false,
+            previous: None,
+        };
+
+        assert!(query.matches(&operation));
+        assert!(!query.matches(&insert(1, "foo.bar")));
+    }
+
+    #[test]
+    fn format_encodes_server_sent_events() {
+        let event = Format::Sse.encode(&Event::Operation(Arc::new(insert(12, "foo.bar"))));
+
+        assert!(event.starts_with(b"id: 12.0\nevent: insert\ndata: {"));
+        assert!(event.ends_with(b"}\n\n"));
+    }
+
+    #[test]
+    fn format_encodes_errors_without_an_id() {
+        let error = Error::RollbackDetected {
+            operations: Vec::new(),
+        };
+        let event = Event::from(&error);
+
+        assert_eq!(
+            Format::Sse.encode(&event),
+            "event: error\ndata: {\"error\":\"Rollback detected, 0 operations no longer exist\"}\n\n"
+        );
+        assert_eq!(
+            Format::Ndjson.encode(&event),
+            "{\"error\":\"Rollback detected, 0 operations no longer exist\"}\n"
+        );
+    }
+}
@@ -0,0 +1,488 @@
+//! The shared module reads one `Oplog` on behalf of many in-process consumers, each with its own
+//! filter and bounded buffer.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::{Error, Operation, Oplog, Result};
+use futures::channel::mpsc;
+use futures::{ready, SinkExt, Stream, StreamExt};
+use futures_timer::Delay;
+
+/// The wait before the first attempt to resume after a retryable error.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The longest wait between attempts to resume after retryable errors.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Resumes an oplog after a retryable error, retrying until it succeeds or fails with an error
+/// that is not retryable.
+///
+/// The wait before each attempt doubles from `INITIAL_BACKOFF` up to `MAX_BACKOFF` with every
+/// attempt counted in `attempts`, which callers reset once an operation is read.
+pub(crate) async fn resume(oplog: &mut Oplog, attempts: &mut u32) -> Result<()> {
+    loop {
+        Delay::new(backoff(*attempts)).await;
+        *attempts = attempts.saturating_add(1);
+
+        match oplog.resume().await {
+            Err(ref e) if e.is_retryable() => continue,
+            res => return res,
+        }
+    }
+}
+
+/// Returns the wait before an attempt to resume after `attempts` earlier ones.
+fn backoff(attempts: u32) -> Duration {
+    INITIAL_BACKOFF
+        .checked_mul(1 << attempts.min(16))
+        .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF))
+}
+
+type Filter = Arc<dyn Fn(&Operation) -> bool + Send + Sync>;
+
+/// What to do when a subscriber's buffer is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Wait for the subscriber to make room, holding up every other subscriber and the cursor.
+    #[default]
+    Block,
+    /// Discard operations until the subscriber makes room, counting them in
+    /// `Subscriber::dropped`.
+    Drop,
+    /// Disconnect the subscriber, which receives `Error::Lagged` after the operations already
+    /// buffered.
+    Disconnect,
+}
+
+/// Options for a subscriber to a `SharedOplog`.
+///
+/// By default, a subscriber receives every operation, buffers up to 1024 of them and blocks when
+/// its buffer is full.
+#[derive(Clone)]
+pub struct SubscribeOptions {
+    filter: Option<Filter>,
+    capacity: usize,
+    lag_policy: LagPolicy,
+}
+
+impl Default for SubscribeOptions {
+    fn default() -> SubscribeOptions {
+        SubscribeOptions {
+            filter: None,
+            capacity: 1024,
+            lag_policy: LagPolicy::default(),
+        }
+    }
+}
+
+impl SubscribeOptions {
+    /// Creates the default options.
+    pub fn new() -> SubscribeOptions {
+        SubscribeOptions::default()
+    }
+
+    /// Only receive operations for which the given function returns true.
+    pub fn filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Operation) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Sets how many operations may be buffered for the subscriber.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Sets what to do when the subscriber's buffer is full.
+    pub fn lag_policy(mut self, lag_policy: LagPolicy) -> Self {
+        self.lag_policy = lag_policy;
+        self
+    }
+}
+
+/// One `Oplog` read on behalf of many subscribers.
+///
+/// Each subscriber is an independent stream of the operations read after it subscribed. Nothing
+/// is read until `run` is polled, typically in a task of its own; subscribers can be added at any
+/// time through a `SharedOplogHandle`. Retryable errors are recovered from with `Oplog::resume`,
+/// waiting longer between each consecutive attempt, from 100 milliseconds up to 10 seconds.
+/// Rollbacks and entries that cannot be parsed are sent to every subscriber and reading carries
+/// on; any other error is sent to every subscriber, whose streams then end, and returned from
+/// `run`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use futures::StreamExt;
+/// use mongodb::Client;
+/// use oplog::{LagPolicy, Oplog, Operation, SharedOplog, SubscribeOptions};
+///
+/// # async fn run() -> Result<(), oplog::Error> {
+/// let client = Client::with_uri_str("mongodb://localhost").await?;
+/// let shared = SharedOplog::new(Oplog::new(&client).await?);
+///
+/// let mut inserts = shared.subscribe(
+///     SubscribeOptions::new()
+///         .filter(|operation| matches!(operation, Operation::Insert { .. }))
+///         .capacity(100)
+///         .lag_policy(LagPolicy::Disconnect),
+/// );
+///
+/// tokio::spawn(shared.run());
+///
+/// while let Some(res) = inserts.next().await {
+///     println!("{:?}", res?);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct SharedOplog {
+    oplog: Oplog,
+    handle: SharedOplogHandle,
+}
+
+impl SharedOplog {
+    /// Shares an `Oplog` between subscribers.
+    pub fn new(oplog: Oplog) -> SharedOplog {
+        SharedOplog {
+            oplog,
+            handle: SharedOplogHandle::default(),
+        }
+    }
+
+    /// Returns a handle for adding subscribers once `run` has taken ownership.
+    pub fn handle(&self) -> SharedOplogHandle {
+        self.handle.clone()
+    }
+
+    /// Adds a subscriber.
+    pub fn subscribe(&self, options: SubscribeOptions) -> Subscriber {
+        self.handle.subscribe(options)
+    }
+
+    /// Reads the oplog, delivering every operation and error to the subscribers, until it returns
+    /// a fatal error.
+    pub async fn run(mut self) -> Result<()> {
+        let mut attempts = 0;
+        let result = loop {
+            let res = match self.oplog.next().await {
+                Some(Ok(operation)) => {
+                    attempts = 0;
+                    Ok(operation)
+                }
+                Some(Err(ref e)) if e.is_retryable() => {
+                    match resume(&mut self.oplog, &mut attempts).await {
+                        Ok(()) => continue,
+                        Err(e) => Err(e),
+                    }
+                }
+                Some(Err(e)) => Err(e),
+                None => break Ok(()),
+            };
+
+            self.handle.publish(&res).await;
+
+            if let Err(e) = res {
+                if !e.carries_on() {
+                    break Err(e);
+                }
+            }
+        };
+
+        self.handle.close();
+
+        result
+    }
+}
+
+/// A handle for adding subscribers to a `SharedOplog`.
+#[derive(Clone, Default)]
+pub struct SharedOplogHandle {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    closed: bool,
+    subscriptions: Vec<Subscription>,
+}
+
+impl SharedOplogHandle {
+    /// Adds a subscriber. If the `SharedOplog` has already stopped, the subscriber's stream is
+    /// empty.
+    pub fn subscribe(&self, options: SubscribeOptions) -> Subscriber {
+        // The channel holds one more than its buffer for its single sender.
+        let (sender, receiver) = mpsc::channel(options.capacity - 1);
+        let status = Arc::new(Status {
+            capacity: options.capacity,
+            ..Status::default()
+        });
+        let mut state = self.state.lock().unwrap();
+
+        if !state.closed {
+            state.subscriptions.push(Subscription {
+                sender,
+                filter: options.filter,
+                lag_policy: options.lag_policy,
+                status: Arc::clone(&status),
+            });
+        }
+
+        Subscriber {
+            receiver,
+            status,
+            reported: false,
+        }
+    }
+
+    /// Delivers an operation or error to every subscriber, removing any that have gone.
+    async fn publish(&self, res: &Result<Operation>) {
+        let subscriptions = std::mem::take(&mut self.state.lock().unwrap().subscriptions);
+        let mut retained = Vec::with_capacity(subscriptions.len());
+
+        for mut subscription in subscriptions {
+            if subscription.deliver(res).await {
+                retained.push(subscription);
+            }
+        }
+
+        // Keep any subscribers added while delivering.
+        let mut state = self.state.lock().unwrap();
+        retained.append(&mut state.subscriptions);
+        state.subscriptions = retained;
+    }
+
+    /// Ends every subscriber stream.
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.subscriptions.clear();
+    }
+}
+
+/// The sending half of a subscriber.
+struct Subscription {
+    sender: mpsc::Sender<Result<Operation>>,
+    filter: Option<Filter>,
+    lag_policy: LagPolicy,
+    status: Arc<Status>,
+}
+
+#[derive(Default)]
+struct Status {
+    capacity: usize,
+    dropped: AtomicU64,
+    lagged: AtomicBool,
+}
+
+impl Subscription {
+    /// Delivers an operation or error as per the lag policy, returning false if the subscriber is
+    /// gone. Errors are delivered whatever the filter.
+    async fn deliver(&mut self, res: &Result<Operation>) -> bool {
+        if let (Some(ref filter), Ok(operation)) = (&self.filter, res) {
+            if !filter(operation) {
+                return true;
+            }
+        }
+
+        let res = match *res {
+            Ok(ref operation) => Ok(operation.clone()),
+            Err(ref e) => Err(copy_error(e)),
+        };
+
+        if self.lag_policy == LagPolicy::Block {
+            return self.sender.send(res).await.is_ok();
+        }
+
+        match self.sender.try_send(res) {
+            Ok(()) => true,
+            Err(e) if e.is_full() && self.lag_policy == LagPolicy::Drop => {
+                self.status.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(e) if e.is_full() => {
+                self.status.lagged.store(true, Ordering::Relaxed);
+                false
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Copies an error for delivery to each subscriber as `Error` cannot be cloned.
+///
+/// The variants an `Oplog` never returns keep only their message.
+fn copy_error(error: &Error) -> Error {
+    match *error {
+        Error::Database(ref e) => Error::Database(e.clone()),
+        Error::Parse(ref e) => Error::Parse(e.clone()),
+        Error::PositionLost { requested, oldest } => Error::PositionLost { requested, oldest },
+        Error::Sink(ref e) => Error::Sink(e.to_string().into()),
+        Error::Checkpoint(ref e) => Error::Checkpoint(std::io::Error::new(e.kind(), e.to_string())),
+        Error::RollbackDetected { ref operations } => Error::RollbackDetected {
+            operations: operations.clone(),
+        },
+        Error::Lagged { capacity } => Error::Lagged { capacity },
+        Error::Unsupported(ref message) => Error::Unsupported(message.clone()),
+        Error::Export(ref e) => Error::Export(e.to_string().into()),
+    }
+}
+
+/// A stream of the operations read by a `SharedOplog` since subscribing.
+pub struct Subscriber {
+    receiver: mpsc::Receiver<Result<Operation>>,
+    status: Arc<Status>,
+    reported: bool,
+}
+
+impl Subscriber {
+    /// Returns how many operations were discarded as the buffer was full, with
+    /// `LagPolicy::Drop`.
+    pub fn dropped(&self) -> u64 {
+        self.status.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for Subscriber {
+    type Item = Result<Operation>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match ready!(self.receiver.poll_next_unpin(cx)) {
+            Some(item) => Poll::Ready(Some(item)),
+            None if !self.reported && self.status.lagged.load(Ordering::Relaxed) => {
+                self.reported = true;
+
+                Poll::Ready(Some(Err(Error::Lagged {
+                    capacity: self.status.capacity,
+                })))
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::{doc, Timestamp};
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+    use mongodb::bson;
+
+    fn insert(time: i64, namespace: &str) -> Operation {
+        Operation::Insert {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: namespace.into(),
+            document: doc! { "_id": time },
+        }
+    }
+
+    fn publish_all(handle: &SharedOplogHandle, operations: Vec<Operation>) {
+        for operation in operations {
+            block_on(handle.publish(&Ok(operation)));
+        }
+        handle.close();
+    }
+
+    #[test]
+    fn subscribers_receive_filtered_operations() {
+        let handle = SharedOplogHandle::default();
+        let all = handle.subscribe(SubscribeOptions::new());
+        let bars = handle.subscribe(
+            SubscribeOptions::new().filter(|operation| operation.namespace() == Some("foo.bar")),
+        );
+
+        publish_all(&handle, vec![insert(1, "foo.bar"), insert(2, "foo.baz")]);
+
+        assert_eq!(block_on(all.collect::<Vec<_>>()).len(), 2);
+        assert_eq!(block_on(bars.collect::<Vec<_>>()).len(), 1);
+    }
+
+    #[test]
+    fn drop_policy_discards_operations_when_full() {
+        let handle = SharedOplogHandle::default();
+        let subscriber = handle.subscribe(
+            SubscribeOptions::new()
+                .capacity(2)
+                .lag_policy(LagPolicy::Drop),
+        );
+
+        publish_all(
+            &handle,
+            vec![
+                insert(1, "foo.bar"),
+                insert(2, "foo.bar"),
+                insert(3, "foo.bar"),
+            ],
+        );
+
+        assert_eq!(subscriber.dropped(), 1);
+        assert_eq!(block_on(subscriber.collect::<Vec<_>>()).len(), 2);
+    }
+
+    #[test]
+    fn disconnect_policy_reports_lag_after_buffered_operations() {
+        let handle = SharedOplogHandle::default();
+        let subscriber = handle.subscribe(
+            SubscribeOptions::new()
+                .capacity(1)
+                .lag_policy(LagPolicy::Disconnect),
+        );
+
+        publish_all(&handle, vec![insert(1, "foo.bar"), insert(2, "foo.bar")]);
+        let results = block_on(subscriber.collect::<Vec<_>>());
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(Error::Lagged { capacity: 1 })));
+    }
+
+    #[test]
+    fn errors_reach_every_subscriber_whatever_the_filter() {
+        let handle = SharedOplogHandle::default();
+        let subscriber = handle
+            .subscribe(SubscribeOptions::new().filter(|operation| operation.kind() == "insert"));
+        let position = Timestamp {
+            time: 1,
+            increment: 0,
+        };
+
+        block_on(handle.publish(&Err(Error::PositionLost {
+            requested: position,
+            oldest: position,
+        })));
+        handle.close();
+        let results = block_on(subscriber.collect::<Vec<_>>());
+
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0], Err(Error::PositionLost { .. })));
+    }
+
+    #[test]
+    fn resuming_backs_off_exponentially_up_to_a_limit() {
+        let waits: Vec<_> = (0..10).map(backoff).collect();
+
+        assert_eq!(waits[0], Duration::from_millis(100));
+        assert_eq!(waits[1], Duration::from_millis(200));
+        assert_eq!(waits[6], Duration::from_millis(6400));
+        assert_eq!(waits[7], Duration::from_secs(10));
+        assert_eq!(backoff(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn subscribing_after_close_is_empty() {
+        let handle = SharedOplogHandle::default();
+        handle.close();
+
+        let subscriber = handle.subscribe(SubscribeOptions::new());
+
+        assert!(block_on(subscriber.collect::<Vec<_>>()).is_empty());
+    }
+}
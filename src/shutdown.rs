@@ -0,0 +1,338 @@
+//! The shutdown module lets an `Oplog` or `RawOplog` be stopped cleanly from another task, so that
+//! the last position checkpointed matches the last operation processed.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use bson::Timestamp;
+use futures::task::AtomicWaker;
+use futures::Stream;
+use mongodb::bson;
+
+/// A handle to stop an `Oplog` gracefully, as returned by `Oplog::shutdown_handle` and
+/// `RawOplog::shutdown_handle`.
+///
+/// Once shutdown is requested, the `Oplog` carries on yielding the entries already fetched in the
+/// current batch. When the batch is drained, by which point the driver has already sent a
+/// `getMore` for the next one, it abandons that request, closes its cursor (which makes the
+/// driver send `killCursors` to the server), ends the stream and reports the timestamp of the
+/// last entry read to every handle. Entries in the abandoned batch are never yielded, so that
+/// timestamp is suitable for `OplogBuilder::start_after` when starting up again.
+///
+/// The `Oplog` must keep being polled for shutdown to complete, so request it from a different
+/// task to the one consuming the stream. A `SyncOplog` has no handle (see `SyncOplog`).
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use futures::StreamExt;
+/// use mongodb::Client;
+/// use oplog::Oplog;
+///
+/// # async fn wait_for_signal() {}
+/// # async fn run() -> Result<(), oplog::Error> {
+/// let client = Client::with_uri_str("mongodb://localhost").await?;
+/// let mut oplog = Oplog::builder().build(&client).await?;
+/// let handle = oplog.shutdown_handle();
+///
+/// let consume = async {
+///     while let Some(res) = oplog.next().await {
+///         println!("{}", res?);
+///     }
+///
+///     Ok::<_, oplog::Error>(())
+/// };
+/// let stop = async {
+///     wait_for_signal().await;
+///     handle.shutdown().await
+/// };
+///
+/// let (res, last) = futures::join!(consume, stop);
+/// res?;
+/// eprintln!("Stopped after {:?}", last);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    signal: Arc<ShutdownSignal>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(signal: Arc<ShutdownSignal>) -> ShutdownHandle {
+        ShutdownHandle { signal }
+    }
+
+    /// Requests shutdown without waiting for it to complete.
+    pub fn cancel(&self) {
+        if !self.signal.requested.swap(true, Ordering::SeqCst) {
+            self.signal.waker.wake();
+        }
+    }
+
+    /// Returns true if shutdown has been requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.signal.is_requested()
+    }
+
+    /// Waits for the `Oplog` to finish, whether following a request to shut down, because its
+    /// cursor ended or because it was dropped, returning the timestamp of the last entry read.
+    pub fn finished(&self) -> Finished {
+        Finished {
+            signal: Arc::clone(&self.signal),
+        }
+    }
+
+    /// Requests shutdown and waits for it to complete, returning the timestamp of the last entry
+    /// read.
+    pub fn shutdown(&self) -> Finished {
+        self.cancel();
+        self.finished()
+    }
+}
+
+/// A future resolving to the final position of an `Oplog` once it has finished, as returned by
+/// `ShutdownHandle::finished` and `ShutdownHandle::shutdown`.
+pub struct Finished {
+    signal: Arc<ShutdownSignal>,
+}
+
+impl Future for Finished {
+    type Output = Option<Timestamp>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.signal.state.lock().expect("shutdown lock poisoned");
+
+        match state.finished {
+            Some(last) => Poll::Ready(last),
+            None => {
+                if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                    state.waiters.push(cx.waker().clone());
+                }
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// The state shared between an `Oplog` and its `ShutdownHandle`s.
+#[derive(Default)]
+pub(crate) struct ShutdownSignal {
+    /// Whether shutdown has been requested.
+    requested: AtomicBool,
+    /// The task polling the `Oplog`, woken when shutdown is requested.
+    waker: AtomicWaker,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The final position, once the `Oplog` has finished.
+    finished: Option<Option<Timestamp>>,
+    /// Tasks waiting for the `Oplog` to finish.
+    waiters: Vec<Waker>,
+}
+
+impl ShutdownSignal {
+    /// Registers the task polling the `Oplog` to be woken when shutdown is requested.
+    pub(crate) fn register(&self, waker: &Waker) {
+        self.waker.register(waker);
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Records that the `Oplog` has finished at the given position, waking any waiting handles.
+    ///
+    /// Only the first call has any effect.
+    pub(crate) fn finish(&self, last: Option<Timestamp>) {
+        let mut state = self.state.lock().expect("shutdown lock poisoned");
+
+        if state.finished.is_none() {
+            state.finished = Some(last);
+
+            for waker in state.waiters.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// A cursor that stops once its current batch is drained after shutdown has been requested.
+pub(crate) struct StoppableCursor<C> {
+    /// The cursor, until it is closed.
+    cursor: Option<C>,
+    /// Shared with any `ShutdownHandle`s to request and report shutdown.
+    signal: Arc<ShutdownSignal>,
+}
+
+impl<C: Stream + Unpin> StoppableCursor<C> {
+    pub(crate) fn new(cursor: C) -> StoppableCursor<C> {
+        StoppableCursor {
+            cursor: Some(cursor),
+            signal: Arc::default(),
+        }
+    }
+
+    pub(crate) fn handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(Arc::clone(&self.signal))
+    }
+
+    /// Replaces the cursor, e.g. after it has died.
+    pub(crate) fn reopen(&mut self, cursor: C) {
+        self.cursor = Some(cursor);
+    }
+
+    /// Closes the cursor and reports the final position to any handles.
+    ///
+    /// Only the first call reports a position.
+    pub(crate) fn finish(&mut self, last: Option<Timestamp>) {
+        self.cursor = None;
+        self.signal.finish(last);
+    }
+
+    /// Polls the cursor for its next entry.
+    ///
+    /// Returns `Poll::Ready(None)` once the cursor is over or closed, including as soon as it has
+    /// to wait on the server after shutdown has been requested. The caller should then `finish`.
+    pub(crate) fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<C::Item>> {
+        self.signal.register(cx.waker());
+
+        let cursor = match self.cursor {
+            Some(ref mut cursor) => cursor,
+            None => return Poll::Ready(None),
+        };
+
+        match Pin::new(cursor).poll_next(cx) {
+            Poll::Pending if self.signal.is_requested() => {
+                self.cursor = None;
+
+                Poll::Ready(None)
+            }
+            poll => poll,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OplogBuilder;
+    use bson::doc;
+    use futures::executor::block_on;
+    use futures::task::{waker, ArcWake};
+    use futures::{poll, stream, FutureExt, StreamExt};
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl ArcWake for CountingWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn ts() -> Timestamp {
+        Timestamp {
+            time: 1479561394,
+            increment: 1,
+        }
+    }
+
+    #[test]
+    fn shutdown_resolves_with_the_final_position() {
+        let signal = Arc::new(ShutdownSignal::default());
+        let handle = ShutdownHandle::new(Arc::clone(&signal));
+
+        block_on(async {
+            let mut shutdown = handle.shutdown().boxed();
+            assert!(poll!(&mut shutdown).is_pending());
+            assert!(signal.is_requested());
+
+            signal.finish(Some(ts()));
+            signal.finish(None);
+
+            assert_eq!(shutdown.await, Some(ts()));
+            assert_eq!(handle.finished().await, Some(ts()));
+        });
+    }
+
+    #[test]
+    fn finished_does_not_request_shutdown() {
+        let signal = Arc::new(ShutdownSignal::default());
+        let handle = ShutdownHandle::new(Arc::clone(&signal));
+
+        block_on(async {
+            assert!(poll!(handle.finished()).is_pending());
+        });
+
+        assert!(!handle.is_cancelled());
+    }
+
+    #[test]
+    fn stoppable_cursor_drains_the_current_batch_before_stopping() {
+        let entries = (1..=3).map(|time| {
+            doc! {
+                "ts": Timestamp { time, increment: 0 },
+                "op": "i",
+                "ns": "foo.bar",
+                "o": { "_id": time },
+            }
+        });
+        let batch = stream::iter(entries).chain(stream::pending());
+        let mut cursor = StoppableCursor::new(batch);
+        let mut reader = OplogBuilder::new().reader();
+        let handle = cursor.handle();
+        let wakes = Arc::new(CountingWaker::default());
+        let waker = waker(Arc::clone(&wakes));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut read = |cursor: &mut StoppableCursor<_>| match cursor.poll_next(&mut cx) {
+            Poll::Ready(Some(entry)) => Poll::Ready(Some(reader.read(entry).unwrap().unwrap())),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        };
+
+        assert!(read(&mut cursor).is_ready());
+        handle.cancel();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+
+        let drained = [read(&mut cursor), read(&mut cursor)];
+        assert!(drained
+            .iter()
+            .all(|poll| matches!(poll, Poll::Ready(Some(_)))));
+        assert!(matches!(read(&mut cursor), Poll::Ready(None)));
+        assert!(cursor.cursor.is_none());
+
+        cursor.finish(reader.last_timestamp());
+
+        assert_eq!(
+            block_on(handle.finished()),
+            Some(Timestamp {
+                time: 3,
+                increment: 0
+            })
+        );
+    }
+
+    #[test]
+    fn stoppable_cursor_waits_on_the_server_unless_shutdown_is_requested() {
+        let mut cursor = StoppableCursor::new(stream::pending::<()>());
+        let handle = cursor.handle();
+
+        block_on(async {
+            assert!(poll!(futures::future::poll_fn(|cx| cursor.poll_next(cx))).is_pending());
+            handle.cancel();
+            assert_eq!(
+                futures::future::poll_fn(|cx| cursor.poll_next(cx)).await,
+                None
+            );
+        });
+    }
+}
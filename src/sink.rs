@@ -0,0 +1,457 @@
+//! The sink module forwards operations to a message broker as keyed records, committing a
+//! checkpoint only once the broker has acknowledged them so that nothing is lost on restart.
+
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::{Error, OpTime, Operation, Result};
+use bson::Bson;
+use futures::{Stream, StreamExt};
+use mongodb::bson;
+
+#[cfg(feature = "kafka")]
+pub use kafka::KafkaSink;
+
+/// A keyed record produced from an `Operation`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// The key of the record: the namespace and, if the operation is on a single document, its
+    /// `_id` as relaxed Extended JSON (e.g. `foo.bar/{"$oid":"..."}`). Operations with the same
+    /// key must be delivered in order.
+    pub key: String,
+    /// The operation as a relaxed Extended JSON oplog entry (see `Operation::to_document`).
+    pub value: Vec<u8>,
+    /// The position of the operation in the oplog.
+    pub optime: OpTime,
+}
+
+impl Record {
+    /// Returns the records for an operation.
+    ///
+    /// An `Operation::ApplyOps` becomes one record per operation it applies so that each is keyed
+    /// by its own document; they all share the optime of the `applyOps` entry. No-ops produce no
+    /// records.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use oplog::bson::{doc, Timestamp};
+    /// use oplog::{Operation, Record};
+    ///
+    /// let operation = Operation::new(&doc! {
+    ///     "ts": Timestamp { time: 1479561394, increment: 0 },
+    ///     "op": "i",
+    ///     "ns": "foo.bar",
+    ///     "o": { "_id": 1, "foo": "bar" },
+    /// }).unwrap();
+    /// let records = Record::from_operation(&operation);
+    ///
+    /// assert_eq!(records[0].key, "foo.bar/1");
+    /// ```
+    pub fn from_operation(operation: &Operation) -> Vec<Record> {
+        let mut records = Vec::new();
+        push_records(&mut records, operation, operation.optime());
+
+        records
+    }
+}
+
+fn push_records(records: &mut Vec<Record>, operation: &Operation, optime: OpTime) {
+    match *operation {
+        Operation::Noop { .. } => {}
+        Operation::ApplyOps { ref operations, .. } => {
+            for operation in operations {
+                push_records(records, operation, optime);
+            }
+        }
+        _ => {
+            let key = operation
+                .document_key()
+                .unwrap_or_else(|| operation.namespace().unwrap_or("").to_string());
+            let value = Bson::Document(operation.to_document())
+                .into_relaxed_extjson()
+                .to_string()
+                .into_bytes();
+
+            records.push(Record { key, value, optime });
+        }
+    }
+}
+
+/// A destination for records, such as a message broker topic.
+pub trait Sink {
+    /// Delivers a batch of records in order, resolving once all of them have been acknowledged.
+    ///
+    /// Records with the same key must be delivered in the order given.
+    fn send(&mut self, records: Vec<Record>) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// A store for the position of the last operation delivered.
+pub trait Checkpoint {
+    /// Returns the last position committed, if any.
+    fn load(&mut self) -> Result<Option<OpTime>>;
+
+    /// Records that every operation up to and including `optime` has been delivered.
+    fn commit(&mut self, optime: OpTime) -> Result<()>;
+}
+
+/// Reads operations from a stream and forwards them to a `Sink`, committing a `Checkpoint` after
+/// each batch is acknowledged.
+///
+/// Batches hold as many records as are immediately available from the stream, up to the batch
+/// size. If delivery fails, the error is returned without committing so that, once restarted with
+/// `OplogBuilder::start_after` from the last checkpoint, undelivered operations are read again.
+/// Delivery is therefore at least once.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use mongodb::Client;
+/// use oplog::{Checkpoint, FileCheckpoint, Forwarder, MemorySink, Oplog};
+///
+/// # async fn run() -> Result<(), oplog::Error> {
+/// let client = Client::with_uri_str("mongodb://localhost").await?;
+/// let mut checkpoint = FileCheckpoint::new("oplog.checkpoint");
+///
+/// let mut builder = Oplog::builder();
+/// if let Some(optime) = checkpoint.load()? {
+///     builder = builder.start_after(optime.timestamp);
+/// }
+/// let oplog = builder.build(&client).await?;
+///
+/// Forwarder::new(MemorySink::new(), checkpoint)
+///     .batch_size(500)
+///     .run(oplog)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Forwarder<S, C> {
+    sink: S,
+    checkpoint: C,
+    batch_size: usize,
+}
+
+impl<S: Sink, C: Checkpoint> Forwarder<S, C> {
+    /// Creates a forwarder delivering batches of up to 100 operations.
+    pub fn new(sink: S, checkpoint: C) -> Forwarder<S, C> {
+        Forwarder {
+            sink,
+            checkpoint,
+            batch_size: 100,
+        }
+    }
+
+    /// Sets the maximum number of operations in a batch.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Forwards every operation in the stream until it ends or an error occurs.
+    pub async fn run<St>(&mut self, stream: St) -> Result<()>
+    where
+        St: Stream<Item = Result<Operation>>,
+    {
+        let batches = stream.ready_chunks(self.batch_size);
+        futures::pin_mut!(batches);
+
+        while let Some(batch) = batches.next().await {
+            let mut records = Vec::new();
+            let mut last = None;
+
+            for res in batch {
+                let operation = res?;
+                push_records(&mut records, &operation, operation.optime());
+                last = Some(operation.optime());
+            }
+
+            if !records.is_empty() {
+                self.sink.send(records).await?;
+            }
+
+            if let Some(optime) = last {
+                self.checkpoint.commit(optime)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the sink and checkpoint.
+    pub fn into_inner(self) -> (S, C) {
+        (self.sink, self.checkpoint)
+    }
+}
+
+/// A `Sink` keeping records in memory, for use in tests.
+///
+/// Clones share the same records.
+#[derive(Clone, Debug, Default)]
+pub struct MemorySink {
+    records: Arc<Mutex<Vec<Record>>>,
+}
+
+impl MemorySink {
+    /// Creates an empty sink.
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+
+    /// Returns the records delivered so far.
+    pub fn records(&self) -> Vec<Record> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+impl Sink for MemorySink {
+    fn send(&mut self, records: Vec<Record>) -> impl Future<Output = Result<()>> + Send {
+        self.records.lock().unwrap().extend(records);
+
+        futures::future::ready(Ok(()))
+    }
+}
+
+/// A `Checkpoint` kept in memory, for use in tests.
+///
+/// Clones share the same position.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryCheckpoint {
+    optime: Arc<Mutex<Option<OpTime>>>,
+}
+
+impl MemoryCheckpoint {
+    /// Creates an empty checkpoint.
+    pub fn new() -> MemoryCheckpoint {
+        MemoryCheckpoint::default()
+    }
+}
+
+impl Checkpoint for MemoryCheckpoint {
+    fn load(&mut self) -> Result<Option<OpTime>> {
+        Ok(*self.optime.lock().unwrap())
+    }
+
+    fn commit(&mut self, optime: OpTime) -> Result<()> {
+        *self.optime.lock().unwrap() = Some(optime);
+
+        Ok(())
+    }
+}
+
+/// A `Checkpoint` saved to a file as the relaxed Extended JSON of the oplog timestamp.
+///
+/// The file is replaced atomically on each commit.
+#[derive(Clone, Debug)]
+pub struct FileCheckpoint {
+    path: PathBuf,
+}
+
+impl FileCheckpoint {
+    /// Creates a checkpoint saved at `path`, which need not exist yet.
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileCheckpoint {
+        FileCheckpoint { path: path.into() }
+    }
+}
+
+impl Checkpoint for FileCheckpoint {
+    fn load(&mut self) -> Result<Option<OpTime>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::Checkpoint(e)),
+        };
+        let invalid = || {
+            let message = format!("invalid checkpoint in {}", self.path.display());
+            Error::Checkpoint(io::Error::new(io::ErrorKind::InvalidData, message))
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&contents).map_err(|_| invalid())?;
+
+        match std::convert::TryFrom::try_from(json) {
+            Ok(Bson::Timestamp(timestamp)) => Ok(Some(OpTime {
+                timestamp,
+                term: None,
+                hash: None,
+            })),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn commit(&mut self, optime: OpTime) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let json = Bson::Timestamp(optime.timestamp).into_relaxed_extjson();
+
+        fs::write(&tmp, json.to_string())
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(Error::Checkpoint)
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use std::future::Future;
+    use std::time::{Duration, Instant};
+
+    use super::{Record, Sink};
+    use crate::{Error, Result};
+    use rdkafka::config::ClientConfig;
+    use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+    use rdkafka::util::{AsyncRuntime, TokioRuntime};
+
+    /// A `Sink` producing records to a Kafka topic, available with the `kafka` feature.
+    ///
+    /// The producer is created with idempotence enabled so that retries cannot reorder or
+    /// duplicate records within a partition. As Kafka assigns partitions by key, this preserves
+    /// the order of operations on each document.
+    pub struct KafkaSink {
+        producer: FutureProducer,
+        topic: String,
+        timeout: Duration,
+    }
+
+    impl KafkaSink {
+        /// Creates a sink producing to `topic` on the given comma-separated bootstrap servers.
+        pub fn new(brokers: &str, topic: &str) -> Result<KafkaSink> {
+            let mut config = ClientConfig::new();
+            config
+                .set("bootstrap.servers", brokers)
+                .set("enable.idempotence", "true");
+
+            KafkaSink::from_config(&config, topic)
+        }
+
+        /// Creates a sink producing to `topic` with a custom producer configuration.
+        pub fn from_config(config: &ClientConfig, topic: &str) -> Result<KafkaSink> {
+            let producer = config.create().map_err(|e| Error::Sink(Box::new(e)))?;
+
+            Ok(KafkaSink {
+                producer,
+                topic: topic.to_string(),
+                timeout: Duration::from_secs(30),
+            })
+        }
+
+        /// Sets how long to wait for space in the producer queue before failing a batch.
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+    }
+
+    impl Sink for KafkaSink {
+        fn send(&mut self, records: Vec<Record>) -> impl Future<Output = Result<()>> + Send {
+            let producer = self.producer.clone();
+            let topic = self.topic.clone();
+            let timeout = self.timeout;
+
+            async move {
+                let mut deliveries = Vec::with_capacity(records.len());
+
+                // Enqueue one record at a time, waiting for space in the queue if necessary, so
+                // that records are produced in order.
+                for record in &records {
+                    let started = Instant::now();
+                    let mut message = FutureRecord::to(&topic)
+                        .key(&record.key)
+                        .payload(&record.value);
+
+                    loop {
+                        match producer.send_result(message) {
+                            Ok(delivery) => break deliveries.push(delivery),
+                            Err((
+                                KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull),
+                                m,
+                            )) if started.elapsed() < timeout => {
+                                message = m;
+                                TokioRuntime::delay_for(Duration::from_millis(100)).await;
+                            }
+                            Err((e, _)) => return Err(Error::Sink(Box::new(e))),
+                        }
+                    }
+                }
+
+                for delivery in deliveries {
+                    match delivery.await {
+                        Ok(Ok(_)) => {}
+                        Ok(Err((e, _))) => return Err(Error::Sink(Box::new(e))),
+                        Err(e) => return Err(Error::Sink(Box::new(e))),
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+
+    fn insert(time: i64, id: i32) -> Operation {
+        Operation::Insert {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: "foo.bar".into(),
+            document: doc! { "_id": id },
+        }
+    }
+
+    struct FailingSink;
+
+    impl Sink for FailingSink {
+        fn send(&mut self, _records: Vec<Record>) -> impl Future<Output = Result<()>> + Send {
+            let err = io::Error::other("unavailable");
+
+            futures::future::ready(Err(Error::Sink(Box::new(err))))
+        }
+    }
+
+    #[test]
+    fn forwarder_commits_after_delivery() {
+        let sink = MemorySink::new();
+        let mut checkpoint = MemoryCheckpoint::new();
+        let operations = futures::stream::iter(vec![Ok(insert(1, 1)), Ok(insert(2, 2))]);
+
+        block_on(Forwarder::new(sink.clone(), checkpoint.clone()).run(operations)).unwrap();
+
+        let keys: Vec<_> = sink.records().into_iter().map(|r| r.key).collect();
+        assert_eq!(keys, vec!["foo.bar/1", "foo.bar/2"]);
+        assert_eq!(checkpoint.load().unwrap(), Some(insert(2, 2).optime()));
+    }
+
+    #[test]
+    fn forwarder_does_not_commit_failed_deliveries() {
+        let mut checkpoint = MemoryCheckpoint::new();
+        let operations = futures::stream::iter(vec![Ok(insert(1, 1))]);
+
+        let res = block_on(Forwarder::new(FailingSink, checkpoint.clone()).run(operations));
+
+        assert!(matches!(res, Err(Error::Sink(_))));
+        assert_eq!(checkpoint.load().unwrap(), None);
+    }
+
+    #[test]
+    fn record_expands_apply_ops() {
+        let operation = Operation::ApplyOps {
+            timestamp: Utc.timestamp_opt(3, 0).unwrap(),
+            namespace: "admin.$cmd".into(),
+            operations: vec![insert(3, 1), insert(3, 2)],
+            partial: false,
+            prepare: false,
+            previous: None,
+        };
+        let records = Record::from_operation(&operation);
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].key, "foo.bar/2");
+        assert_eq!(records[1].optime, operation.optime());
+    }
+}
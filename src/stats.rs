@@ -0,0 +1,476 @@
+//! The stats module summarises a range of the oplog to find where its churn comes from, e.g.
+//! before resizing it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::oper::document_size;
+use crate::{Operation, Result};
+use bson::Bson;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use mongodb::bson;
+use serde_json::{json, Value};
+
+/// The number and encoded size of a set of operations.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    /// The number of operations.
+    pub operations: u64,
+    /// The total size of the operations' oplog entries in bytes.
+    pub bytes: u64,
+}
+
+impl Counts {
+    fn add(&mut self, bytes: u64) {
+        self.operations += 1;
+        self.bytes += bytes;
+    }
+
+    fn to_json(self) -> Value {
+        json!({ "operations": self.operations, "bytes": self.bytes })
+    }
+}
+
+/// A document updated frequently within the analysed range.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HotDocument {
+    /// The namespace of the document.
+    pub namespace: String,
+    /// The `_id` of the document.
+    pub id: Bson,
+    /// The number of updates to the document.
+    pub updates: u64,
+}
+
+/// A bucket of a histogram of operations per second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateBucket {
+    /// The lowest rate in operations per second falling in this bucket.
+    pub min: u64,
+    /// The highest rate in operations per second falling in this bucket.
+    pub max: u64,
+    /// The number of seconds whose rate fell in this bucket.
+    pub seconds: u64,
+}
+
+/// A summary of the operations in a range of the oplog, as produced by an `Analyzer`.
+///
+/// Sizes are those of each operation's oplog entry as returned by `Operation::to_document`, which
+/// omits fields not kept in an `Operation` (e.g. `lsid`), so they slightly underestimate the space
+/// used in the oplog itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OplogStats {
+    /// The time of the first operation.
+    pub first: Option<DateTime<Utc>>,
+    /// The time of the last operation.
+    pub last: Option<DateTime<Utc>>,
+    /// All entries read.
+    pub total: Counts,
+    /// Operations by namespace.
+    pub by_namespace: BTreeMap<String, Counts>,
+    /// Operations by type, as returned by `Operation::kind`.
+    pub by_kind: BTreeMap<String, Counts>,
+    /// Commands by name (the first key of the command, e.g. `create` or `applyOps`).
+    pub by_command: BTreeMap<String, Counts>,
+    /// The most frequently updated documents, most updated first.
+    pub hot_documents: Vec<HotDocument>,
+    /// A histogram of the number of operations per second with power-of-two bucket boundaries,
+    /// counting only seconds with at least one operation.
+    pub rates: Vec<RateBucket>,
+    /// The highest number of operations in a single second.
+    pub peak_rate: u64,
+}
+
+impl OplogStats {
+    /// Returns the statistics rendered as plain text tables.
+    pub fn to_table(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "{} operations, {} bytes, from {} to {}",
+            self.total.operations,
+            self.total.bytes,
+            self.first.map(|t| t.to_rfc3339()).unwrap_or_default(),
+            self.last.map(|t| t.to_rfc3339()).unwrap_or_default(),
+        );
+
+        for (title, counts) in &[
+            ("NAMESPACE", &self.by_namespace),
+            ("OPERATION", &self.by_kind),
+            ("COMMAND", &self.by_command),
+        ] {
+            let mut rows: Vec<_> = counts.iter().collect();
+            rows.sort_by_key(|(_, counts)| std::cmp::Reverse(counts.bytes));
+
+            let _ = writeln!(out, "\n{:<50} {:>12} {:>14}", title, "OPERATIONS", "BYTES");
+            for (name, counts) in rows {
+                let _ = writeln!(
+                    out,
+                    "{:<50} {:>12} {:>14}",
+                    name, counts.operations, counts.bytes
+                );
+            }
+        }
+
+        let _ = writeln!(out, "\n{:<50} {:<30} {:>7}", "NAMESPACE", "_ID", "UPDATES");
+        for hot in &self.hot_documents {
+            let _ = writeln!(
+                out,
+                "{:<50} {:<30} {:>7}",
+                hot.namespace, hot.id, hot.updates
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "\n{:<20} {:>12}  (peak {}/s)",
+            "OPS/SECOND", "SECONDS", self.peak_rate
+        );
+        for bucket in &self.rates {
+            let range = format!("{}-{}", bucket.min, bucket.max);
+            let _ = writeln!(out, "{:<20} {:>12}", range, bucket.seconds);
+        }
+
+        out
+    }
+
+    /// Returns the statistics as JSON.
+    pub fn to_json(&self) -> Value {
+        let counts = |map: &BTreeMap<String, Counts>| -> Value {
+            map.iter()
+                .map(|(k, v)| (k.clone(), v.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into()
+        };
+
+        json!({
+            "first": self.first.map(|t| t.to_rfc3339()),
+            "last": self.last.map(|t| t.to_rfc3339()),
+            "total": self.total.to_json(),
+            "byNamespace": counts(&self.by_namespace),
+            "byKind": counts(&self.by_kind),
+            "byCommand": counts(&self.by_command),
+            "hotDocuments": self.hot_documents.iter().map(|hot| json!({
+                "namespace": hot.namespace,
+                "id": hot.id.clone().into_relaxed_extjson(),
+                "updates": hot.updates,
+            })).collect::<Vec<_>>(),
+            "rates": self.rates.iter().map(|bucket| json!({
+                "min": bucket.min,
+                "max": bucket.max,
+                "seconds": bucket.seconds,
+            })).collect::<Vec<_>>(),
+            "peakRate": self.peak_rate,
+        })
+    }
+}
+
+/// Analyzes a stream of operations, reporting counts and volume by namespace, operation type and
+/// command along with the most updated documents and a histogram of operations per second.
+///
+/// The operations within an `Operation::ApplyOps` are also counted by namespace, type and
+/// document so that writes made in transactions are attributed to their collections, but only the
+/// `applyOps` entry itself counts towards the total.
+///
+/// Memory use grows with the number of distinct updated documents and seconds in the range.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use futures::StreamExt;
+/// use mongodb::Client;
+/// use oplog::bson::Timestamp;
+/// use oplog::{Analyzer, Oplog};
+///
+/// # async fn run() -> Result<(), oplog::Error> {
+/// let client = Client::with_uri_str("mongodb://localhost").await?;
+///
+/// let oplog = Oplog::builder()
+///     .start_at(Timestamp { time: 1479561394, increment: 0 })
+///     .build(&client)
+///     .await?;
+///
+/// let stats = Analyzer::new().consume(oplog.take(100_000)).await?;
+/// println!("{}", stats.to_table());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Analyzer {
+    stats: OplogStats,
+    top: usize,
+    /// Update counts by namespace and `_id`, keyed by `Operation::document_key`.
+    updates: HashMap<String, (String, Bson, u64)>,
+    seconds: BTreeMap<i64, u64>,
+}
+
+impl Default for Analyzer {
+    fn default() -> Analyzer {
+        Analyzer::new()
+    }
+}
+
+impl Analyzer {
+    /// Creates an analyzer reporting the 10 most updated documents.
+    pub fn new() -> Analyzer {
+        Analyzer {
+            stats: OplogStats::default(),
+            top: 10,
+            updates: HashMap::new(),
+            seconds: BTreeMap::new(),
+        }
+    }
+
+    /// Sets the number of most updated documents to report.
+    pub fn top(mut self, top: usize) -> Self {
+        self.top = top;
+        self
+    }
+
+    /// Records a single operation.
+    pub fn record(&mut self, operation: &Operation) {
+        let timestamp = operation.timestamp();
+        let bytes = document_size(&operation.to_document());
+
+        self.stats.first = Some(self.stats.first.map_or(timestamp, |t| t.min(timestamp)));
+        self.stats.last = Some(self.stats.last.map_or(timestamp, |t| t.max(timestamp)));
+        self.stats.total.add(bytes);
+        *self.seconds.entry(timestamp.timestamp()).or_insert(0) += 1;
+
+        self.record_breakdown(operation, bytes);
+    }
+
+    /// Records an operation by namespace, type, command and document.
+    fn record_breakdown(&mut self, operation: &Operation, bytes: u64) {
+        if let Some(namespace) = operation.namespace() {
+            self.stats
+                .by_namespace
+                .entry(namespace.to_string())
+                .or_default()
+                .add(bytes);
+        }
+
+        self.stats
+            .by_kind
+            .entry(operation.kind().to_string())
+            .or_default()
+            .add(bytes);
+
+        match *operation {
+            Operation::Command { ref command, .. } => {
+                if let Some(name) = command.keys().next() {
+                    self.stats
+                        .by_command
+                        .entry(name.clone())
+                        .or_default()
+                        .add(bytes);
+                }
+            }
+            Operation::ApplyOps { ref operations, .. } => {
+                self.stats
+                    .by_command
+                    .entry("applyOps".to_string())
+                    .or_default()
+                    .add(bytes);
+
+                for operation in operations {
+                    let bytes = document_size(&operation.to_document());
+                    self.record_breakdown(operation, bytes);
+                }
+            }
+            Operation::Update {
+                ref namespace,
+                ref query,
+                ..
+            } => {
+                if let (Some(key), Some(id)) = (operation.document_key(), query.get("_id")) {
+                    self.updates
+                        .entry(key)
+                        .or_insert_with(|| (namespace.clone(), id.clone(), 0))
+                        .2 += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Records every operation in a stream until it ends, returning the statistics.
+    ///
+    /// As an `Oplog` never ends by itself, bound it first (e.g. with `StreamExt::take_while` on
+    /// the operation timestamps).
+    pub async fn consume<S>(mut self, stream: S) -> Result<OplogStats>
+    where
+        S: Stream<Item = Result<Operation>>,
+    {
+        futures::pin_mut!(stream);
+
+        while let Some(res) = stream.next().await {
+            self.record(&res?);
+        }
+
+        Ok(self.finish())
+    }
+
+    /// Returns the statistics for the operations recorded so far.
+    pub fn finish(self) -> OplogStats {
+        let mut stats = self.stats;
+
+        let mut hot: Vec<_> = self
+            .updates
+            .into_iter()
+            .map(|(_, (namespace, id, updates))| HotDocument {
+                namespace,
+                id,
+                updates,
+            })
+            .collect();
+        hot.sort_by(|a, b| {
+            b.updates
+                .cmp(&a.updates)
+                .then_with(|| a.namespace.cmp(&b.namespace))
+        });
+        hot.truncate(self.top);
+        stats.hot_documents = hot;
+
+        stats.peak_rate = self.seconds.values().copied().max().unwrap_or(0);
+        stats.rates = rate_histogram(self.seconds.values().copied());
+
+        stats
+    }
+}
+
+/// Returns a histogram of per-second rates with power-of-two bucket boundaries (1, 2-3, 4-7...).
+fn rate_histogram<I: Iterator<Item = u64>>(rates: I) -> Vec<RateBucket> {
+    let mut buckets: BTreeMap<u32, u64> = BTreeMap::new();
+
+    for rate in rates.filter(|&r| r > 0) {
+        *buckets.entry(63 - rate.leading_zeros()).or_insert(0) += 1;
+    }
+
+    buckets
+        .into_iter()
+        .map(|(exp, seconds)| RateBucket {
+            min: 1 << exp,
+            max: (1 << exp) * 2 - 1,
+            seconds,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use chrono::TimeZone;
+
+    fn update(time: i64, namespace: &str, id: i32) -> Operation {
+        Operation::Update {
+            timestamp: Utc.timestamp_opt(time, 0).unwrap(),
+            namespace: namespace.into(),
+            query: doc! { "_id": id },
+            update: doc! { "$inc": { "n": 1 } },
+        }
+    }
+
+    #[test]
+    fn analyzer_keeps_ids_of_different_types_apart() {
+        let mut analyzer = Analyzer::new();
+        analyzer.record(&update(1, "foo.bar", 1));
+        analyzer.record(&Operation::Update {
+            timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+            namespace: "foo.bar".into(),
+            query: doc! { "_id": "1" },
+            update: doc! { "$inc": { "n": 1 } },
+        });
+        let stats = analyzer.finish();
+
+        assert_eq!(stats.hot_documents.len(), 2);
+        assert!(stats.hot_documents.iter().all(|hot| hot.updates == 1));
+    }
+
+    #[test]
+    fn analyzer_counts_numerically_equal_ids_together() {
+        let mut analyzer = Analyzer::new();
+        for id in [Bson::Int32(1), Bson::Int64(1)] {
+            analyzer.record(&Operation::Update {
+                timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+                namespace: "foo.bar".into(),
+                query: doc! { "_id": id },
+                update: doc! { "$inc": { "n": 1 } },
+            });
+        }
+        let stats = analyzer.finish();
+
+        assert_eq!(stats.hot_documents.len(), 1);
+        assert_eq!(stats.hot_documents[0].updates, 2);
+    }
+
+    #[test]
+    fn analyzer_counts_operations_and_hot_documents() {
+        let mut analyzer = Analyzer::new().top(1);
+        analyzer.record(&update(1, "foo.bar", 1));
+        analyzer.record(&update(1, "foo.bar", 1));
+        analyzer.record(&update(2, "foo.baz", 2));
+        analyzer.record(&Operation::Command {
+            timestamp: Utc.timestamp_opt(2, 0).unwrap(),
+            namespace: "foo.$cmd".into(),
+            command: doc! { "create": "qux" },
+        });
+        let stats = analyzer.finish();
+
+        assert_eq!(stats.total.operations, 4);
+        assert_eq!(stats.by_namespace["foo.bar"].operations, 2);
+        assert_eq!(stats.by_kind["update"].operations, 3);
+        assert_eq!(stats.by_command["create"].operations, 1);
+        assert_eq!(
+            stats.hot_documents,
+            vec![HotDocument {
+                namespace: "foo.bar".into(),
+                id: Bson::Int32(1),
+                updates: 2,
+            }]
+        );
+        assert_eq!(stats.peak_rate, 2);
+        assert_eq!(
+            stats.rates,
+            vec![RateBucket {
+                min: 2,
+                max: 3,
+                seconds: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn rate_histogram_uses_power_of_two_buckets() {
+        let rates = rate_histogram(vec![1, 3, 4, 7, 8].into_iter());
+
+        assert_eq!(
+            rates,
+            vec![
+                RateBucket {
+                    min: 1,
+                    max: 1,
+                    seconds: 1,
+                },
+                RateBucket {
+                    min: 2,
+                    max: 3,
+                    seconds: 1,
+                },
+                RateBucket {
+                    min: 4,
+                    max: 7,
+                    seconds: 2,
+                },
+                RateBucket {
+                    min: 8,
+                    max: 15,
+                    seconds: 1,
+                },
+            ]
+        );
+    }
+}
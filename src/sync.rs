@@ -0,0 +1,174 @@
+//! The sync module provides a blocking `Iterator` over the oplog for programs that do not
+//! otherwise use an async runtime, built on the MongoDB driver's sync API.
+
+use crate::reader::Reader;
+use crate::rollback;
+use crate::{check_oldest, instrument, oldest_options};
+use crate::{Operation, OplogBuilder, Result, Start};
+use bson::Document;
+use mongodb::bson;
+use mongodb::sync::{Client, Collection, Cursor};
+
+/// SyncOplog represents a MongoDB replica set oplog read with blocking I/O.
+///
+/// It implements the `Iterator` trait, yielding successive `Operation`s as they are read from the
+/// server and blocking while it awaits new operations. It is configured with the same
+/// `OplogBuilder` as an `Oplog`, using `OplogBuilder::build_sync`.
+///
+/// There is no `ShutdownHandle` as a request could not interrupt a call blocked on the server. To
+/// stop, drop it between calls to `next`, which closes the cursor, and checkpoint
+/// `last_timestamp`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use oplog::bson::doc;
+/// use oplog::mongodb::sync::Client;
+/// use oplog::Oplog;
+///
+/// # fn run() -> Result<(), oplog::Error> {
+/// let client = Client::with_uri_str("mongodb://localhost")?;
+///
+/// let oplog = Oplog::builder()
+///     .filter(doc! { "op": "i" })
+///     .build_sync(&client)?;
+///
+/// for res in oplog {
+///     let oper = res?;
+///     println!("{}", oper);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct SyncOplog {
+    /// The internal MongoDB cursor for the current position in the oplog.
+    cursor: Cursor<Document>,
+    /// The oplog collection, kept so the cursor can be reopened on resume.
+    coll: Collection<Document>,
+    /// The options the cursor was built with.
+    builder: OplogBuilder,
+    /// Converts entries into operations and tracks the position reached.
+    reader: Reader,
+    /// An entry read from a new term, held back until the rollback error has been yielded.
+    held: Option<Document>,
+}
+
+impl SyncOplog {
+    /// Creates an instance with default options.
+    pub fn new(client: &Client) -> Result<SyncOplog> {
+        OplogBuilder::new().build_sync(client)
+    }
+
+    /// Returns the timestamp of the last entry read from the oplog, if any.
+    ///
+    /// See `Oplog::last_timestamp`.
+    pub fn last_timestamp(&self) -> Option<bson::Timestamp> {
+        self.reader.last_timestamp()
+    }
+
+    /// Reopens the underlying cursor directly after the last entry read.
+    ///
+    /// See `Oplog::resume`.
+    pub fn resume(&mut self) -> Result<()> {
+        let lost = match self.reader.recent() {
+            Some(recent) => find_lost(&self.coll, recent)?,
+            None => Vec::new(),
+        };
+        let rolled_back = self.reader.rolled_back(lost, true);
+
+        self.cursor = self
+            .builder
+            .open_sync(&self.coll, self.reader.resume_from())?;
+        instrument::reconnect(self.reader.last_timestamp());
+        self.held = None;
+
+        rolled_back
+    }
+}
+
+impl Iterator for SyncOplog {
+    type Item = Result<Operation>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(held) = self.held.take() {
+            if let Some(res) = self.reader.read(held) {
+                return Some(res);
+            }
+        }
+
+        loop {
+            // A `None` here probably indicates that the oplog.rs collection is empty. See
+            // https://jira.mongodb.org/browse/SERVER-13955
+            let document = match self.cursor.next()? {
+                Ok(document) => document,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            if let Some(recent) = self.reader.term_changed(&document) {
+                let res = find_lost(&self.coll, recent)
+                    .and_then(|lost| self.reader.rolled_back(lost, false));
+
+                if let Err(e) = res {
+                    self.held = Some(document);
+
+                    return Some(Err(e));
+                }
+            }
+
+            if let Some(res) = self.reader.read(document) {
+                return Some(res);
+            }
+        }
+    }
+}
+
+impl OplogBuilder {
+    /// Executes the query and builds a blocking `SyncOplog` over the sync client provided.
+    ///
+    /// This requires the `sync` feature.
+    pub fn build_sync(self, client: &Client) -> Result<SyncOplog> {
+        let coll = client
+            .database(&self.database)
+            .collection_with_options(&self.collection, self.collection_options());
+        let cursor = self.open_sync(&coll, self.start)?;
+
+        Ok(SyncOplog {
+            cursor,
+            coll,
+            reader: self.reader(),
+            builder: self,
+            held: None,
+        })
+    }
+
+    /// Opens a tailable cursor on the oplog from the given position.
+    fn open_sync(
+        &self,
+        coll: &Collection<Document>,
+        start: Option<Start>,
+    ) -> Result<Cursor<Document>> {
+        if let Some(start) = start {
+            let first = coll.find_one(None, oldest_options())?;
+            check_oldest(first, start.timestamp())?;
+        }
+
+        Ok(coll.find(self.query(start), self.find_options())?)
+    }
+}
+
+/// Looks up the given optimes in the oplog and returns those that no longer exist.
+fn find_lost(
+    coll: &Collection<Document>,
+    recent: Vec<crate::OpTime>,
+) -> Result<Vec<crate::OpTime>> {
+    if recent.is_empty() {
+        return Ok(recent);
+    }
+
+    let (filter, opts) = rollback::lookup(&recent);
+    let found = coll
+        .find(filter, opts)?
+        .collect::<mongodb::error::Result<_>>()?;
+
+    rollback::lost_in(recent, found)
+}
@@ -0,0 +1,235 @@
+//! The update module interprets the update documents of oplog entries as the fields they set and
+//! remove, whichever format the server wrote them in.
+
+use crate::optime::as_i64;
+use bson::{Bson, Document};
+use mongodb::bson;
+
+/// An update as either a replacement document or fields set and removed by dotted path.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Change {
+    Replace(Document),
+    Modify {
+        set: Vec<(String, Bson)>,
+        unset: Vec<String>,
+    },
+}
+
+impl Change {
+    /// Interprets an update, returning `None` if it uses anything other than `$set` and `$unset`
+    /// or a `$v: 2` diff that does not truncate arrays.
+    pub(crate) fn new(update: &Document) -> Option<Change> {
+        match Change::with_truncations(update)? {
+            (change, truncated) if truncated.is_empty() => Some(change),
+            _ => None,
+        }
+    }
+
+    /// Interprets an update like `new` but also accepting `$v: 2` diffs that truncate arrays,
+    /// returning the path and new length of each array truncated alongside the change.
+    pub(crate) fn with_truncations(update: &Document) -> Option<(Change, Vec<(String, i64)>)> {
+        if !update.keys().any(|key| key.starts_with('$')) {
+            return Some((Change::Replace(update.clone()), Vec::new()));
+        }
+
+        let mut set = Vec::new();
+        let mut unset = Vec::new();
+        let mut truncated = Vec::new();
+
+        for (key, value) in update {
+            match (key.as_str(), value) {
+                ("$v", _) => {}
+                ("$set", Bson::Document(fields)) => {
+                    set.extend(fields.iter().map(|(k, v)| (k.clone(), v.clone())))
+                }
+                ("$unset", Bson::Document(fields)) => unset.extend(fields.keys().cloned()),
+                ("diff", Bson::Document(diff)) => {
+                    flatten_diff(diff, "", &mut set, &mut unset, &mut truncated)?
+                }
+                _ => return None,
+            }
+        }
+
+        Some((Change::Modify { set, unset }, truncated))
+    }
+
+    /// Returns the document with the change applied, or `None` if a path runs through anything
+    /// but embedded documents.
+    pub(crate) fn apply(self, document: &Document) -> Option<Document> {
+        match self {
+            Change::Replace(mut replacement) => {
+                if let Some(id) = document.get("_id") {
+                    if !replacement.contains_key("_id") {
+                        replacement.insert("_id", id.clone());
+                    }
+                }
+
+                Some(replacement)
+            }
+            Change::Modify { set, unset } => {
+                let mut document = document.clone();
+
+                for (path, value) in set {
+                    set_path(&mut document, &path, value)?;
+                }
+
+                for path in unset {
+                    unset_path(&mut document, &path)?;
+                }
+
+                Some(document)
+            }
+        }
+    }
+
+    /// Returns a change with the same effect as this followed by another, or `None` if paths
+    /// touched by both overlap without being equal.
+    pub(crate) fn merge(self, later: Change) -> Option<Change> {
+        match (self, later) {
+            (Change::Replace(document), later) => later.apply(&document).map(Change::Replace),
+            (_, later @ Change::Replace(_)) => Some(later),
+            (
+                Change::Modify { mut set, mut unset },
+                Change::Modify {
+                    set: later_set,
+                    unset: later_unset,
+                },
+            ) => {
+                let later_paths: Vec<&str> = later_set
+                    .iter()
+                    .map(|(path, _)| path.as_str())
+                    .chain(later_unset.iter().map(String::as_str))
+                    .collect();
+                let paths = set
+                    .iter()
+                    .map(|(path, _)| path.as_str())
+                    .chain(unset.iter().map(String::as_str));
+
+                for path in paths {
+                    if later_paths.iter().any(|later| overlaps(path, later)) {
+                        return None;
+                    }
+                }
+
+                set.retain(|(path, _)| !later_paths.contains(&path.as_str()));
+                unset.retain(|path| !later_paths.contains(&path.as_str()));
+                set.extend(later_set);
+                unset.extend(later_unset);
+
+                Some(Change::Modify { set, unset })
+            }
+        }
+    }
+
+    pub(crate) fn into_update(self) -> Document {
+        match self {
+            Change::Replace(document) => document,
+            Change::Modify { set, unset } => {
+                let mut update = Document::new();
+
+                if !set.is_empty() {
+                    update.insert("$set", set.into_iter().collect::<Document>());
+                }
+
+                if !unset.is_empty() {
+                    let unset: Document = unset
+                        .into_iter()
+                        .map(|path| (path, Bson::Int32(1)))
+                        .collect();
+                    update.insert("$unset", unset);
+                }
+
+                update
+            }
+        }
+    }
+}
+
+/// Returns true if one path is within the other but they are not the same, e.g. `a` and `a.b`.
+pub(crate) fn overlaps(path: &str, other: &str) -> bool {
+    let (shorter, longer) = if path.len() < other.len() {
+        (path, other)
+    } else {
+        (other, path)
+    };
+
+    longer.len() > shorter.len()
+        && longer.starts_with(shorter)
+        && longer.as_bytes()[shorter.len()] == b'.'
+}
+
+/// Flattens a `$v: 2` update diff into dotted paths (with array indexes as path components) and
+/// the arrays it truncates, returning `None` if it cannot be interpreted.
+fn flatten_diff(
+    diff: &Document,
+    prefix: &str,
+    set: &mut Vec<(String, Bson)>,
+    unset: &mut Vec<String>,
+    truncated: &mut Vec<(String, i64)>,
+) -> Option<()> {
+    let is_array = diff.get_bool("a").unwrap_or(false);
+
+    for (key, value) in diff {
+        match (key.as_str(), value) {
+            ("a", _) if is_array => {}
+            ("u", Bson::Document(fields)) | ("i", Bson::Document(fields)) if !is_array => {
+                set.extend(
+                    fields
+                        .iter()
+                        .map(|(field, value)| (format!("{}{}", prefix, field), value.clone())),
+                );
+            }
+            ("d", Bson::Document(fields)) if !is_array => {
+                unset.extend(fields.keys().map(|field| format!("{}{}", prefix, field)));
+            }
+            ("l", size) if is_array => {
+                truncated.push((prefix.trim_end_matches('.').to_string(), as_i64(size)?));
+            }
+            (key, value) if is_array && key.starts_with('u') => {
+                set.push((format!("{}{}", prefix, &key[1..]), value.clone()));
+            }
+            (key, Bson::Document(subdiff)) if key.starts_with('s') => {
+                let prefix = format!("{}{}.", prefix, &key[1..]);
+                flatten_diff(subdiff, &prefix, set, unset, truncated)?;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(())
+}
+
+/// Sets a field by dotted path, creating embedded documents as needed.
+fn set_path(document: &mut Document, path: &str, value: Bson) -> Option<()> {
+    match path.split_once('.') {
+        None => {
+            document.insert(path, value);
+            Some(())
+        }
+        Some((field, rest)) => {
+            if !document.contains_key(field) {
+                document.insert(field, Document::new());
+            }
+
+            match document.get_mut(field) {
+                Some(Bson::Document(embedded)) => set_path(embedded, rest, value),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Removes a field by dotted path.
+fn unset_path(document: &mut Document, path: &str) -> Option<()> {
+    match path.split_once('.') {
+        None => {
+            document.remove(path);
+            Some(())
+        }
+        Some((field, rest)) => match document.get_mut(field) {
+            Some(Bson::Document(embedded)) => unset_path(embedded, rest),
+            None | Some(Bson::Null) => Some(()),
+            _ => None,
+        },
+    }
+}